@@ -0,0 +1,22 @@
+-- Add up migration script here
+CREATE TABLE
+    "audit_events" (
+        id BIGSERIAL PRIMARY KEY,
+        occurred_at TIMESTAMP
+        WITH
+            TIME ZONE NOT NULL DEFAULT NOW(),
+            event_type VARCHAR(64) NOT NULL,
+            outcome VARCHAR(16) NOT NULL,
+            actor_id BIGINT,
+            subject_id BIGINT,
+            email VARCHAR(255),
+            ip VARCHAR(64),
+            user_agent TEXT,
+            detail TEXT
+    );
+
+CREATE INDEX audit_events_occurred_at_idx ON audit_events (occurred_at);
+
+CREATE INDEX audit_events_actor_id_idx ON audit_events (actor_id);
+
+CREATE INDEX audit_events_subject_id_idx ON audit_events (subject_id);
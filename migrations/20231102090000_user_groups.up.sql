@@ -0,0 +1,7 @@
+-- Add up migration script here
+CREATE TABLE
+    "user_groups" (
+        user_id INT NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+        group_name VARCHAR(64) NOT NULL,
+        PRIMARY KEY (user_id, group_name)
+    );
@@ -0,0 +1,10 @@
+-- Add up migration script here
+CREATE TABLE
+    "mfa_credentials" (
+        user_id BIGINT PRIMARY KEY,
+        secret TEXT NOT NULL,
+        enabled BOOLEAN NOT NULL DEFAULT FALSE,
+        last_step BIGINT NOT NULL DEFAULT 0,
+        recovery_codes TEXT[] NOT NULL DEFAULT '{}',
+        created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+    );
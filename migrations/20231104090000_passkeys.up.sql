@@ -0,0 +1,11 @@
+-- Add up migration script here
+CREATE TABLE
+    "passkeys" (
+        credential_id TEXT PRIMARY KEY,
+        user_id BIGINT NOT NULL,
+        credential TEXT NOT NULL,
+        sign_count BIGINT NOT NULL DEFAULT 0,
+        created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+    );
+
+CREATE INDEX passkeys_user_id_idx ON passkeys (user_id);
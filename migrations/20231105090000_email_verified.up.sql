@@ -0,0 +1,3 @@
+-- Add up migration script here
+-- accounts from before verification existed count as verified
+ALTER TABLE "users" ADD COLUMN "verified" BOOLEAN NOT NULL DEFAULT TRUE;
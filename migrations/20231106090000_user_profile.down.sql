@@ -0,0 +1,7 @@
+-- Add down migration script here
+ALTER TABLE "users"
+    DROP COLUMN "cn",
+    DROP COLUMN "sn",
+    DROP COLUMN "display_name",
+    DROP COLUMN "given_name",
+    DROP COLUMN "telephone_number";
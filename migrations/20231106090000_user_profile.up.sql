@@ -0,0 +1,8 @@
+-- Add up migration script here
+-- the attributes PATCH /api/user/{id} changes besides the address
+ALTER TABLE "users"
+    ADD COLUMN "cn" VARCHAR(255),
+    ADD COLUMN "sn" VARCHAR(255),
+    ADD COLUMN "display_name" VARCHAR(255),
+    ADD COLUMN "given_name" VARCHAR(255),
+    ADD COLUMN "telephone_number" VARCHAR(64);
@@ -0,0 +1,3 @@
+-- Add up migration script here
+-- why an admin disabled the account, next to disabled_at
+ALTER TABLE "users" ADD COLUMN "disabled_reason" TEXT;
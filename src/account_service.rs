@@ -0,0 +1,40 @@
+use chrono::Utc;
+use redis::AsyncCommands;
+
+use crate::config::Config;
+
+// Whether an account is disabled is kept by the identity store. Redis only
+// holds the cutoff below, which revokes the tokens the account held until then.
+
+// Tokens of the user issued up to this timestamp are refused. It outlives
+// re-enabling, so tokens from before the account was disabled stay dead.
+fn revoked_before_key(uid: u64) -> String {
+    format!("revoked_before:{}", uid)
+}
+
+pub async fn revoke_tokens(redis_client: &redis::Client, config: &Config, uid: u64) -> redis::RedisResult<()> {
+    let mut conn = redis_client.get_async_connection().await?;
+    // no token outlives a refresh token, so neither does the cutoff
+    let _: () = conn
+        .set_ex(
+            revoked_before_key(uid),
+            Utc::now().timestamp(),
+            (config.refresh_token_max_age * 60) as usize,
+        )
+        .await?;
+    Ok(())
+}
+
+// Whether a token of `uid` issued at `issued_at` was signed before its tokens were revoked.
+pub async fn token_revoked(redis_client: &redis::Client, uid: u64, issued_at: i64) -> redis::RedisResult<bool> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let revoked_before: Option<i64> = conn.get(revoked_before_key(uid)).await?;
+    Ok(revoked_before.map_or(false, |revoked_before| issued_at <= revoked_before))
+}
+
+// Drops the revocation cutoff of a user whose account is deleted.
+pub async fn forget_user(redis_client: &redis::Client, uid: u64) -> redis::RedisResult<()> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let _: () = conn.del(revoked_before_key(uid)).await?;
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use crate::{
+    audit_service::{AuditEventType, AuditFilter},
+    authz::{Admin, RequireRole},
+    user_model::AuditQuery,
+    AppState,
+};
+use actix_web::{get, web, HttpResponse, Responder};
+
+const DEFAULT_PAGE_SIZE: i64 = 100;
+const MAX_PAGE_SIZE: i64 = 1000;
+
+#[get("")]
+async fn list_audit_events_handler(
+    query: web::Query<AuditQuery>,
+    data: web::Data<AppState>,
+    _admin: RequireRole<Admin>,
+) -> impl Responder {
+    if !data.audit.is_persistent() {
+        return HttpResponse::ServiceUnavailable().json(
+            serde_json::json!({"status": "error","message": "The audit log needs DATABASE_URL"}),
+        );
+    }
+    let event_type = match query.event_type.as_deref() {
+        Some(value) => match AuditEventType::parse(value) {
+            Some(event_type) => Some(event_type),
+            None => {
+                return HttpResponse::BadRequest().json(
+                    serde_json::json!({"status": "fail","message": format!("Unknown event type {}", value)}),
+                );
+            }
+        },
+        None => None,
+    };
+    let filter = AuditFilter {
+        user_id: query.user,
+        event_type,
+        from: query.from,
+        to: query.to,
+        before: query.before,
+        limit: query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE),
+    };
+
+    match data.audit.query(&filter).await {
+        Ok(events) => {
+            // the next page starts below the oldest event of a full page
+            let next = if events.len() as i64 == filter.limit {
+                events.last().map(|event| event.id)
+            } else {
+                None
+            };
+            HttpResponse::Ok().json(serde_json::json!({"status": "success","data": serde_json::json!({
+                "events": events,
+                "next_before": next
+            })}))
+        }
+        Err(message) => HttpResponse::InternalServerError()
+            .json(serde_json::json!({"status": "error","message": message})),
+    }
+}
+
+pub fn config(conf: &mut web::ServiceConfig) {
+    let scope = web::scope("/api/audit").service(list_audit_events_handler);
+    conf.service(scope);
+}
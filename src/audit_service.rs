@@ -0,0 +1,320 @@
+use actix_web::{web, HttpRequest};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{FromRow, Pool, Postgres};
+use std::net::IpAddr;
+
+use crate::AppState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEventType {
+    LoginSuccess,
+    LoginFailure,
+    Refresh,
+    RefreshReuse,
+    Register,
+    Delete,
+    Lockout,
+    MfaEnabled,
+    MfaDisabled,
+    RecoveryCodeUsed,
+    RecoveryCodesRegenerated,
+    TokenCreated,
+    TokenRevoked,
+    InviteCreated,
+    RegistrationApproved,
+    RegistrationRejected,
+    AccountDisabled,
+    AccountEnabled,
+    GroupCreated,
+    GroupDeleted,
+    GroupMemberAdded,
+    GroupMemberRemoved,
+}
+
+impl AuditEventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditEventType::LoginSuccess => "login_success",
+            AuditEventType::LoginFailure => "login_failure",
+            AuditEventType::Refresh => "refresh",
+            AuditEventType::RefreshReuse => "refresh_reuse",
+            AuditEventType::Register => "register",
+            AuditEventType::Delete => "delete",
+            AuditEventType::Lockout => "lockout",
+            AuditEventType::MfaEnabled => "mfa_enabled",
+            AuditEventType::MfaDisabled => "mfa_disabled",
+            AuditEventType::RecoveryCodeUsed => "recovery_code_used",
+            AuditEventType::RecoveryCodesRegenerated => "recovery_codes_regenerated",
+            AuditEventType::TokenCreated => "token_created",
+            AuditEventType::TokenRevoked => "token_revoked",
+            AuditEventType::InviteCreated => "invite_created",
+            AuditEventType::RegistrationApproved => "registration_approved",
+            AuditEventType::RegistrationRejected => "registration_rejected",
+            AuditEventType::AccountDisabled => "account_disabled",
+            AuditEventType::AccountEnabled => "account_enabled",
+            AuditEventType::GroupCreated => "group_created",
+            AuditEventType::GroupDeleted => "group_deleted",
+            AuditEventType::GroupMemberAdded => "group_member_added",
+            AuditEventType::GroupMemberRemoved => "group_member_removed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<AuditEventType> {
+        let all = [
+            AuditEventType::LoginSuccess,
+            AuditEventType::LoginFailure,
+            AuditEventType::Refresh,
+            AuditEventType::RefreshReuse,
+            AuditEventType::Register,
+            AuditEventType::Delete,
+            AuditEventType::Lockout,
+            AuditEventType::MfaEnabled,
+            AuditEventType::MfaDisabled,
+            AuditEventType::RecoveryCodeUsed,
+            AuditEventType::RecoveryCodesRegenerated,
+            AuditEventType::TokenCreated,
+            AuditEventType::TokenRevoked,
+            AuditEventType::InviteCreated,
+            AuditEventType::RegistrationApproved,
+            AuditEventType::RegistrationRejected,
+            AuditEventType::AccountDisabled,
+            AuditEventType::AccountEnabled,
+            AuditEventType::GroupCreated,
+            AuditEventType::GroupDeleted,
+            AuditEventType::GroupMemberAdded,
+            AuditEventType::GroupMemberRemoved,
+        ];
+        all.into_iter().find(|event_type| event_type.as_str() == value)
+    }
+}
+
+// The address of the client. X-Forwarded-For is anyone's to write, so it is
+// only read when the connection comes from a proxy in TRUSTED_PROXIES, and then
+// from the right, skipping the hops the trusted proxies added.
+fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    let trusted: &[IpAddr] = match req.app_data::<web::Data<AppState>>() {
+        Some(data) => &data.env.trusted_proxies,
+        None => &[],
+    };
+    let forwarded = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    Some(forwarded_client(peer, &forwarded, trusted))
+}
+
+fn forwarded_client(peer: IpAddr, forwarded: &str, trusted: &[IpAddr]) -> IpAddr {
+    let mut client = peer;
+    for hop in forwarded.rsplit(',') {
+        if !trusted.contains(&client) {
+            break;
+        }
+        match hop.trim().parse::<IpAddr>() {
+            Ok(hop) => client = hop,
+            // an entry that is not an address cannot be followed any further
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    pub event_type: AuditEventType,
+    pub success: bool,
+    // who did it, when known
+    pub actor_id: Option<u64>,
+    // whose account it concerns
+    pub subject_id: Option<u64>,
+    pub email: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub detail: Option<String>,
+}
+
+impl AuditEvent {
+    // Starts an event with the client address and user agent of `req`, see `client_ip`.
+    pub fn new(event_type: AuditEventType, success: bool, req: &HttpRequest) -> Self {
+        let ip = client_ip(req).map(|ip| ip.to_string());
+        let user_agent = req
+            .headers()
+            .get(actix_web::http::header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string());
+        AuditEvent {
+            event_type,
+            success,
+            actor_id: None,
+            subject_id: None,
+            email: None,
+            ip,
+            user_agent,
+            detail: None,
+        }
+    }
+
+    pub fn actor(mut self, uid: u64) -> Self {
+        self.actor_id = Some(uid);
+        self
+    }
+
+    pub fn subject(mut self, uid: u64) -> Self {
+        self.subject_id = Some(uid);
+        self
+    }
+
+    pub fn email(mut self, email: &str) -> Self {
+        self.email = Some(email.to_lowercase());
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    fn outcome(&self) -> &'static str {
+        if self.success {
+            "success"
+        } else {
+            "failure"
+        }
+    }
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct AuditRecord {
+    pub id: i64,
+    pub occurred_at: DateTime<Utc>,
+    pub event_type: String,
+    pub outcome: String,
+    pub actor_id: Option<i64>,
+    pub subject_id: Option<i64>,
+    pub email: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub detail: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct AuditFilter {
+    // matches events the user did or that concern them
+    pub user_id: Option<u64>,
+    pub event_type: Option<AuditEventType>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    // only events older than this id, for paging backwards
+    pub before: Option<i64>,
+    pub limit: i64,
+}
+
+// Writes audit events to the `audit_events` table. Without DATABASE_URL the
+// events are only printed, so development setups still see them.
+pub struct AuditLog {
+    pool: Option<Pool<Postgres>>,
+}
+
+impl AuditLog {
+    pub fn new(pool: Option<Pool<Postgres>>) -> Self {
+        AuditLog { pool }
+    }
+
+    pub fn is_persistent(&self) -> bool {
+        self.pool.is_some()
+    }
+
+    // Records in the background so a slow database never holds up a login.
+    pub fn record(&self, event: AuditEvent) {
+        println!(
+            "📝Audit {} {} actor={:?} subject={:?} email={:?} ip={:?}",
+            event.event_type.as_str(),
+            event.outcome(),
+            event.actor_id,
+            event.subject_id,
+            event.email,
+            event.ip
+        );
+        let pool = match &self.pool {
+            Some(pool) => pool.clone(),
+            None => return,
+        };
+        actix_web::rt::spawn(async move {
+            if let Err(err) = insert(&pool, &event).await {
+                println!("❌Could not write audit event {}: {}", event.event_type.as_str(), err);
+            }
+        });
+    }
+
+    pub async fn query(&self, filter: &AuditFilter) -> Result<Vec<AuditRecord>, String> {
+        let pool = match &self.pool {
+            Some(pool) => pool,
+            None => return Err("The audit log needs DATABASE_URL".to_string()),
+        };
+        sqlx::query_as::<_, AuditRecord>(
+            "SELECT * FROM audit_events
+             WHERE ($1::BIGINT IS NULL OR actor_id = $1 OR subject_id = $1)
+               AND ($2::VARCHAR IS NULL OR event_type = $2)
+               AND ($3::TIMESTAMPTZ IS NULL OR occurred_at >= $3)
+               AND ($4::TIMESTAMPTZ IS NULL OR occurred_at < $4)
+               AND ($5::BIGINT IS NULL OR id < $5)
+             ORDER BY id DESC
+             LIMIT $6",
+        )
+        .bind(filter.user_id.map(|uid| uid as i64))
+        .bind(filter.event_type.map(|event_type| event_type.as_str()))
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(filter.before)
+        .bind(filter.limit)
+        .fetch_all(pool)
+        .await
+        .map_err(|err| err.to_string())
+    }
+}
+
+async fn insert(pool: &Pool<Postgres>, event: &AuditEvent) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO audit_events (event_type, outcome, actor_id, subject_id, email, ip, user_agent, detail)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+    )
+    .bind(event.event_type.as_str())
+    .bind(event.outcome())
+    .bind(event.actor_id.map(|uid| uid as i64))
+    .bind(event.subject_id.map(|uid| uid as i64))
+    .bind(&event.email)
+    .bind(&event.ip)
+    .bind(&event.user_agent)
+    .bind(&event.detail)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn ignores_forwarding_headers_from_untrusted_peers() {
+        assert_eq!(forwarded_client(ip("203.0.113.7"), "198.51.100.1", &[]), ip("203.0.113.7"));
+        assert_eq!(forwarded_client(ip("203.0.113.7"), "198.51.100.1", &[ip("10.0.0.1")]), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn follows_trusted_proxies_only() {
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        assert_eq!(forwarded_client(ip("10.0.0.1"), "198.51.100.1", &trusted), ip("198.51.100.1"));
+        // a client-written entry left of the real client is not believed
+        assert_eq!(forwarded_client(ip("10.0.0.1"), "192.0.2.9, 198.51.100.1, 10.0.0.2", &trusted), ip("198.51.100.1"));
+        assert_eq!(forwarded_client(ip("10.0.0.1"), "", &trusted), ip("10.0.0.1"));
+        assert_eq!(forwarded_client(ip("10.0.0.1"), "unknown", &trusted), ip("10.0.0.1"));
+    }
+}
@@ -1,151 +1,715 @@
 use crate::{
     jwt_auth,
-    user_model::{LoginUserSchema,  User, RefreshSchema},
-    user_service::{filter_user_record,fetch_user_by_id_query},
-    token_service, AppState,
-    ldap_service::get_admin_ldap
+    user_model::{LoginUserSchema, RefreshSchema, MfaCodeSchema, MfaDisableSchema, MfaVerifySchema, PasskeyLoginStartSchema, PasskeyLoginFinishSchema, MagicLinkRequestSchema, MagicLinkRedeemSchema},
+    token_service, token_model::TokenDetails, user_cache::{self, CachedUser}, AppState,
+    identity_store::IdentityError,
+    mfa_service::{self, MfaError},
+    webauthn_service::{self, PasskeyError},
+    verification_service::EmailVerificationMode,
+    magic_link_service::{self, MagicLinkError},
+    audit_service::{AuditEvent, AuditEventType},
+    scope_service,
+    registration_service,
+    dpop_service::{self, DpopError},
+    cookie_service,
+    account_service
 };
 use actix_web::{
-    get, post, web, HttpMessage, HttpRequest, HttpResponse, Responder,
+    get, post, routes, web, HttpMessage, HttpRequest, HttpResponse, Responder,
 };
-use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHash, PasswordVerifier},
-    Argon2,
-};
-use redis::AsyncCommands;
 use serde_json::json;
-use uuid::Uuid;
-use ldap3::{LdapConn, Scope, SearchEntry};
-use tokio::runtime::Runtime;
-use std::thread;
+use webauthn_rs::prelude::RegisterPublicKeyCredential;
+use webauthn_rs::Webauthn;
+
 #[post("/login")]
 async fn login_user_handler(
+    req: HttpRequest,
     body: web::Json<LoginUserSchema>,
     data: web::Data<AppState>,
 ) -> impl Responder {
-    let base_dn = "ou=dia,dc=diditalready,dc=com";
     let email = body.email.as_str();
-    let filter = format!("(&(objectClass=inetOrgPerson)(mail={}))",email);
-    let ldap = get_admin_ldap(&data.ldap_pool, &data.env.ldap_admin_dn,&data.env.ldap_admin_password).await;
-    let mut ldap = match ldap {
-        Ok(ldap) => ldap,
+    let password = body.password.as_str(); // The password to check
+
+    let identity = match data.identity.authenticate(email, password).await {
+        Ok(identity) => identity,
+        Err(IdentityError::NotFound) => {
+            data.audit.record(AuditEvent::new(AuditEventType::LoginFailure, false, &req).email(email).detail("unknown account"));
+            return HttpResponse::Conflict().json(
+                serde_json::json!({"status": "fail","message": "Invalid email or password"}),
+            );
+        }
+        Err(IdentityError::InvalidCredentials) => {
+            data.audit.record(AuditEvent::new(AuditEventType::LoginFailure, false, &req).email(email).detail("wrong password"));
+            return HttpResponse::BadRequest()
+                .json(serde_json::json!({"status": "error","message": "Invalid email or password"}));
+        }
         Err(err) => {
             return HttpResponse::InternalServerError()
                 .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
     };
-    let search_result = ldap
-        .search(
-            &base_dn,
-            Scope::Subtree,
-            &filter, 
-            vec!["uid"],
-        )
-        .await;
-    let search_result = match search_result {
-        Ok(search_result) => search_result,
+    finish_login(&data, &req, CachedUser::from(identity), "password", body.scope.as_deref()).await
+}
+
+// Completes a first-factor login: applies the email verification policy, then
+// either hands out a 2FA challenge or the tokens themselves. `method` names
+// the first factor in the audit log. With 2FA on, `scope` is asked for again
+// at /mfa/verify.
+async fn finish_login(
+    data: &AppState,
+    req: &HttpRequest,
+    user: CachedUser,
+    method: &str,
+    scope: Option<&str>,
+) -> HttpResponse {
+    let user_id = user.user_id;
+    let groups = match account_gate(data, user).await {
+        Ok(groups) => groups,
+        Err(response) => {
+            data.audit.record(AuditEvent::new(AuditEventType::LoginFailure, false, req).subject(user_id).detail("account not active"));
+            return response;
+        }
+    };
+
+    // with 2FA on, the first factor only earns a challenge to complete at /mfa/verify
+    match mfa_service::is_enabled(data.db_pool.as_ref(), user_id).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return match mfa_service::create_challenge(&data.redis_client, &data.env, user_id).await {
+                Ok(challenge) => HttpResponse::Ok().json(json!({
+                    "status": "mfa_required",
+                    "challenge": challenge,
+                    "expires_in": data.env.mfa_challenge_max_age_secs
+                })),
+                Err(err) => mfa_error_response(err),
+            };
+        }
+        Err(err) => return mfa_error_response(err),
+    }
+
+    match issue_tokens(data, req, user_id, groups, scope).await {
+        Ok(tokens) => {
+            data.audit.record(AuditEvent::new(AuditEventType::LoginSuccess, true, req).actor(user_id).subject(user_id).detail(method));
+            deliver_tokens(data, tokens, cookie_service::wants_cookies(&data.env, req))
+        }
+        Err(response) => response,
+    }
+}
+
+// Refuses logins by disabled accounts and those still waiting for registration
+// approval, then applies EMAIL_VERIFICATION to unverified accounts: they are
+// refused when verification is required, and lose their group claims when limited.
+async fn account_gate(data: &AppState, user: CachedUser) -> Result<Vec<String>, HttpResponse> {
+    let CachedUser { user_id, groups, disabled, verified, .. } = user;
+    // registrations waiting for approval are disabled too, tell them apart
+    match registration_service::is_pending(&data.redis_client, user_id).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return Err(HttpResponse::Forbidden()
+                .json(serde_json::json!({"status": "fail","message": "The account is waiting for approval"})));
+        }
+        Err(err) => {
+            return Err(HttpResponse::InternalServerError()
+                .json(serde_json::json!({"status": "error","message": format!("{:?}", err)})));
+        }
+    }
+    if disabled {
+        return Err(HttpResponse::Forbidden()
+            .json(serde_json::json!({"status": "fail","message": "The account is disabled"})));
+    }
+    if data.env.email_verification == EmailVerificationMode::Off {
+        return Ok(groups);
+    }
+    if verified {
+        Ok(groups)
+    } else if data.env.email_verification == EmailVerificationMode::Limited {
+        Ok(Vec::new())
+    } else {
+        Err(HttpResponse::Forbidden()
+            .json(serde_json::json!({"status": "fail","message": "Email address is not verified"})))
+    }
+}
+
+fn invalid_scope(message: String) -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({"status": "fail","error": "invalid_scope","message": message}))
+}
+
+fn dpop_error_response(err: DpopError) -> HttpResponse {
+    match err {
+        DpopError::Invalid(message) => HttpResponse::BadRequest()
+            .json(serde_json::json!({"status": "fail","error": "invalid_dpop_proof","message": message})),
+        DpopError::Replayed => HttpResponse::BadRequest()
+            .json(serde_json::json!({"status": "fail","error": "invalid_dpop_proof","message": "The DPoP proof was already used"})),
+        err => HttpResponse::InternalServerError()
+            .json(serde_json::json!({"status": "error","message": format!("{:?}", err)})),
+    }
+}
+
+fn token_type(jkt: &Option<String>) -> &'static str {
+    if jkt.is_some() {
+        "DPoP"
+    } else {
+        "Bearer"
+    }
+}
+
+// Signs the access and refresh tokens handed out once a login is complete,
+// granting the requested scopes the user's groups allow. A DPoP proof on the
+// request binds both tokens to the client's key.
+async fn issue_tokens(
+    data: &AppState,
+    req: &HttpRequest,
+    user_id: u64,
+    groups: Vec<String>,
+    scope: Option<&str>,
+) -> Result<serde_json::Value, HttpResponse> {
+    let scopes = match scope_service::grant(scope, &scope_service::allowed_scopes(&data.env, &groups)) {
+        Ok(scopes) => scopes,
+        Err(message) => return Err(invalid_scope(message)),
+    };
+    let jkt = match dpop_service::verify_proof(&data.redis_client, &data.env, req, None).await {
+        Ok(jkt) => jkt,
+        Err(err) => return Err(dpop_error_response(err)),
+    };
+    let access_token_details = match token_service::generate_jwt_token(
+        user_id,
+        groups.clone(),
+        scopes.clone(),
+        jkt.clone(),
+        data.env.access_token_max_age,
+        data.env.access_token_private_key.to_owned(),
+    ) {
+        Ok(token_details) => token_details,
+        Err(e) => {
+            return Err(HttpResponse::BadGateway()
+                .json(serde_json::json!({"status": "fail", "message": format_args!("{}", e)})));
+        }
+    };
+
+    let refresh_token_details = match token_service::generate_jwt_token(
+        user_id,
+        groups,
+        scopes.clone(),
+        jkt.clone(),
+        data.env.refresh_token_max_age,
+        data.env.refresh_token_private_key.to_owned(),
+    ) {
+        Ok(token_details) => token_details,
+        Err(e) => {
+            return Err(HttpResponse::BadGateway()
+                .json(serde_json::json!({"status": "fail", "message": format_args!("{}", e)})));
+        }
+    };
+
+    Ok(json!({"status": "success", "access": access_token_details.token.clone().unwrap() , "refresh":refresh_token_details.token.clone().unwrap(), "scope": scope_service::join(&scopes), "token_type": token_type(&jkt)}))
+}
+
+// Answers with the tokens from `issue_tokens`. For browser clients the refresh
+// token, and the access token with COOKIE_ACCESS_TOKEN, move from the body
+// into HttpOnly cookies, next to a CSRF token the client echoes in a header.
+fn deliver_tokens(data: &AppState, mut tokens: serde_json::Value, as_cookies: bool) -> HttpResponse {
+    if !as_cookies {
+        return HttpResponse::Ok().json(tokens);
+    }
+    let access = tokens["access"].as_str().unwrap_or_default().to_string();
+    let refresh = tokens["refresh"].as_str().unwrap_or_default().to_string();
+    let csrf = cookie_service::new_csrf_token();
+    let body = tokens.as_object_mut().unwrap();
+    body.remove("refresh");
+    if data.env.cookie_access_token {
+        body.remove("access");
+    }
+    body.insert("csrf_token".to_string(), json!(csrf));
+
+    let mut response = HttpResponse::Ok();
+    for cookie in cookie_service::token_cookies(&data.env, &access, &refresh, &csrf) {
+        response.cookie(cookie);
+    }
+    response.json(tokens)
+}
+
+// Accepts either a TOTP code or a recovery code as the second factor. Returns
+// the number of recovery codes left when one was used.
+async fn check_second_factor(
+    data: &AppState,
+    req: &HttpRequest,
+    user_id: u64,
+    code: &Option<String>,
+    recovery_code: &Option<String>,
+) -> Result<Option<usize>, HttpResponse> {
+    match (code, recovery_code) {
+        (Some(code), _) => match mfa_service::verify_code(data.db_pool.as_ref(), &data.env, user_id, code).await {
+            Ok(_) => Ok(None),
+            Err(err) => Err(mfa_error_response(err)),
+        },
+        (None, Some(recovery_code)) => match mfa_service::use_recovery_code(data.db_pool.as_ref(), &data.redis_client, user_id, recovery_code).await {
+            Ok(remaining) => {
+                data.audit.record(
+                    AuditEvent::new(AuditEventType::RecoveryCodeUsed, true, req)
+                        .actor(user_id)
+                        .subject(user_id)
+                        .detail(format!("{} left", remaining)),
+                );
+                Ok(Some(remaining))
+            }
+            Err(MfaError::TooManyAttempts(user_id)) => {
+                data.audit.record(AuditEvent::new(AuditEventType::Lockout, false, req).subject(user_id).detail("recovery codes"));
+                Err(mfa_error_response(MfaError::TooManyAttempts(user_id)))
+            }
+            Err(err) => {
+                data.audit.record(AuditEvent::new(AuditEventType::RecoveryCodeUsed, false, req).subject(user_id));
+                Err(mfa_error_response(err))
+            }
+        },
+        (None, None) => Err(HttpResponse::BadRequest()
+            .json(serde_json::json!({"status": "fail","message": "code or recovery_code is required"}))),
+    }
+}
+
+fn mfa_error_response(err: MfaError) -> HttpResponse {
+    match err {
+        MfaError::NotConfigured => HttpResponse::ServiceUnavailable()
+            .json(serde_json::json!({"status": "error","message": "2FA is not configured on this server"})),
+        MfaError::NotEnrolled => HttpResponse::Conflict()
+            .json(serde_json::json!({"status": "fail","message": "2FA enrollment was not started"})),
+        MfaError::AlreadyEnabled => HttpResponse::Conflict()
+            .json(serde_json::json!({"status": "fail","message": "2FA is already enabled"})),
+        MfaError::InvalidCode => HttpResponse::BadRequest()
+            .json(serde_json::json!({"status": "fail","message": "Invalid code"})),
+        MfaError::InvalidChallenge => HttpResponse::Forbidden()
+            .json(serde_json::json!({"status": "fail","message": "Invalid or expired MFA challenge"})),
+        MfaError::TooManyAttempts(_) => HttpResponse::Forbidden()
+            .json(serde_json::json!({"status": "fail","message": "Too many attempts, log in again"})),
+        err => HttpResponse::InternalServerError()
+            .json(serde_json::json!({"status": "error","message": format!("{:?}", err)})),
+    }
+}
+
+#[post("/mfa/verify")]
+async fn mfa_verify_handler(
+    req: HttpRequest,
+    body: web::Json<MfaVerifySchema>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let user_id = match mfa_service::challenge_user(&data.redis_client, &data.env, &body.challenge).await {
+        Ok(user_id) => user_id,
+        Err(MfaError::TooManyAttempts(user_id)) => {
+            data.audit.record(AuditEvent::new(AuditEventType::Lockout, false, &req).subject(user_id).detail("2FA challenge"));
+            return mfa_error_response(MfaError::TooManyAttempts(user_id));
+        }
+        Err(err) => return mfa_error_response(err),
+    };
+    let recovery_codes_remaining = match check_second_factor(&data, &req, user_id, &body.code, &body.recovery_code).await {
+        Ok(remaining) => remaining,
+        Err(response) => {
+            data.audit.record(AuditEvent::new(AuditEventType::LoginFailure, false, &req).subject(user_id).detail("wrong second factor"));
+            return response;
+        }
+    };
+    if let Err(err) = mfa_service::finish_challenge(&data.redis_client, &body.challenge).await {
+        return mfa_error_response(err);
+    }
+
+    let user = match user_cache::get_user(&data, user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return HttpResponse::Conflict().json(
+                serde_json::json!({"status": "fail","message": "the user belonging to this challenge no longer exists"}),
+            );
+        }
         Err(err) => {
             return HttpResponse::InternalServerError()
                 .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
     };
+    let groups = match account_gate(&data, user).await {
+        Ok(groups) => groups,
+        Err(response) => return response,
+    };
+    let mut tokens = match issue_tokens(&data, &req, user_id, groups, body.scope.as_deref()).await {
+        Ok(tokens) => tokens,
+        Err(response) => return response,
+    };
+    let method = if recovery_codes_remaining.is_some() { "recovery_code" } else { "totp" };
+    data.audit.record(AuditEvent::new(AuditEventType::LoginSuccess, true, &req).actor(user_id).subject(user_id).detail(method));
+    if let Some(remaining) = recovery_codes_remaining {
+        tokens["recovery_codes_remaining"] = serde_json::json!(remaining);
+    }
+    deliver_tokens(&data, tokens, cookie_service::wants_cookies(&data.env, &req))
+}
 
-    let (rs,_res) = match search_result.success() {
-        Ok(search_result) => search_result,
+#[post("/mfa/enroll")]
+async fn mfa_enroll_handler(
+    data: web::Data<AppState>,
+    auth: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    if let Some(response) = jwt_auth::require_session(&auth) {
+        return response;
+    }
+    let email = match user_cache::get_user(&data, auth.user_id).await {
+        Ok(Some(user)) => user.email,
+        Ok(None) => {
+            return HttpResponse::Conflict().json(
+                serde_json::json!({"status": "fail","message": "the user belonging to this token no longer exists"}),
+            );
+        }
         Err(err) => {
             return HttpResponse::InternalServerError()
                 .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
     };
-    if !(rs.len() > 0) {
-        return HttpResponse::Conflict().json(
-            serde_json::json!({"status": "fail","message": "Invalid email or password"}),
-        );    
+
+    match mfa_service::start_enrollment(data.db_pool.as_ref(), &data.env, auth.user_id, &email).await {
+        Ok((secret, otpauth_uri)) => HttpResponse::Ok().json(serde_json::json!({"status": "success","data": {
+            "secret": secret,
+            "otpauth_uri": otpauth_uri
+        }})),
+        Err(err) => mfa_error_response(err),
+    }
+}
+
+#[post("/mfa/confirm")]
+async fn mfa_confirm_handler(
+    req: HttpRequest,
+    auth: jwt_auth::JwtMiddleware,
+    body: web::Json<MfaCodeSchema>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(response) = jwt_auth::require_session(&auth) {
+        return response;
+    }
+    match mfa_service::confirm_enrollment(data.db_pool.as_ref(), &data.env, auth.user_id, &body.code).await {
+        Ok(recovery_codes) => {
+            data.audit.record(AuditEvent::new(AuditEventType::MfaEnabled, true, &req).actor(auth.user_id).subject(auth.user_id));
+            HttpResponse::Ok().json(serde_json::json!({"status": "success","message": "2FA enabled","data": {
+                "recovery_codes": recovery_codes
+            }}))
+        }
+        Err(err) => mfa_error_response(err),
     }
+}
 
+#[post("/mfa/recovery-codes")]
+async fn mfa_recovery_codes_handler(
+    req: HttpRequest,
+    auth: jwt_auth::JwtMiddleware,
+    body: web::Json<MfaCodeSchema>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(response) = jwt_auth::require_session(&auth) {
+        return response;
+    }
+    match mfa_service::regenerate_recovery_codes(data.db_pool.as_ref(), &data.env, auth.user_id, &body.code).await {
+        Ok(recovery_codes) => {
+            data.audit.record(
+                AuditEvent::new(AuditEventType::RecoveryCodesRegenerated, true, &req)
+                    .actor(auth.user_id)
+                    .subject(auth.user_id),
+            );
+            HttpResponse::Ok().json(serde_json::json!({"status": "success","data": {
+                "recovery_codes": recovery_codes
+            }}))
+        }
+        Err(err) => {
+            data.audit.record(AuditEvent::new(AuditEventType::RecoveryCodesRegenerated, false, &req).actor(auth.user_id).subject(auth.user_id));
+            mfa_error_response(err)
+        }
+    }
+}
 
+#[post("/mfa/disable")]
+async fn mfa_disable_handler(
+    req: HttpRequest,
+    auth: jwt_auth::JwtMiddleware,
+    body: web::Json<MfaDisableSchema>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(response) = jwt_auth::require_session(&auth) {
+        return response;
+    }
+    if let Err(response) = check_second_factor(&data, &req, auth.user_id, &body.code, &body.recovery_code).await {
+        return response;
+    }
+    match mfa_service::forget_user(data.db_pool.as_ref(), auth.user_id).await {
+        Ok(_) => {
+            data.audit.record(AuditEvent::new(AuditEventType::MfaDisabled, true, &req).actor(auth.user_id).subject(auth.user_id));
+            HttpResponse::Ok().json(serde_json::json!({"status": "success","message": "2FA disabled"}))
+        }
+        Err(err) => mfa_error_response(err),
+    }
+}
 
+fn passkey_error_response(err: PasskeyError) -> HttpResponse {
+    match err {
+        PasskeyError::NotConfigured => HttpResponse::ServiceUnavailable()
+            .json(serde_json::json!({"status": "error","message": "Passkeys are not configured on this server"})),
+        PasskeyError::NoPasskeys => HttpResponse::Conflict()
+            .json(serde_json::json!({"status": "fail","message": "No passkey is registered for this account"})),
+        PasskeyError::InvalidCeremony => HttpResponse::Forbidden()
+            .json(serde_json::json!({"status": "fail","message": "Invalid or expired passkey challenge"})),
+        PasskeyError::CounterRegressed => HttpResponse::Forbidden()
+            .json(serde_json::json!({"status": "fail","message": "The passkey's signature counter went backwards"})),
+        PasskeyError::WebauthnError(err) => HttpResponse::BadRequest()
+            .json(serde_json::json!({"status": "fail","message": format!("Passkey verification failed: {:?}", err)})),
+        err => HttpResponse::InternalServerError()
+            .json(serde_json::json!({"status": "error","message": format!("{:?}", err)})),
+    }
+}
 
-    // check password in ldap
-    let password = body.password.as_str();
+fn webauthn(data: &AppState) -> Result<&Webauthn, HttpResponse> {
+    data.webauthn
+        .as_deref()
+        .ok_or_else(|| passkey_error_response(PasskeyError::NotConfigured))
+}
 
-    let mut user_ldap = match data.ldap_pool.get().await {
-        Ok(user_ldap) => user_ldap,
-        Err(e) => {
+#[post("/passkey/register/start")]
+async fn passkey_register_start_handler(
+    data: web::Data<AppState>,
+    auth: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    if let Some(response) = jwt_auth::require_session(&auth) {
+        return response;
+    }
+    let webauthn = match webauthn(&data) {
+        Ok(webauthn) => webauthn,
+        Err(response) => return response,
+    };
+    let email = match user_cache::get_user(&data, auth.user_id).await {
+        Ok(Some(user)) => user.email,
+        Ok(None) => {
+            return HttpResponse::Conflict().json(
+                serde_json::json!({"status": "fail","message": "the user belonging to this token no longer exists"}),
+            );
+        }
+        Err(err) => {
             return HttpResponse::InternalServerError()
-                .json(serde_json::json!({"status": "error","message": format!("Could not connect to LDAP: {}", e)}));
+                .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
     };
 
+    match webauthn_service::start_registration(webauthn, data.db_pool.as_ref(), &data.redis_client, auth.user_id, &email).await {
+        Ok(options) => HttpResponse::Ok().json(serde_json::json!({"status": "success","data": options})),
+        Err(err) => passkey_error_response(err),
+    }
+}
 
+#[post("/passkey/register/finish")]
+async fn passkey_register_finish_handler(
+    auth: jwt_auth::JwtMiddleware,
+    body: web::Json<RegisterPublicKeyCredential>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(response) = jwt_auth::require_session(&auth) {
+        return response;
+    }
+    let webauthn = match webauthn(&data) {
+        Ok(webauthn) => webauthn,
+        Err(response) => return response,
+    };
+    match webauthn_service::finish_registration(webauthn, data.db_pool.as_ref(), &data.redis_client, auth.user_id, &body).await {
+        Ok(credential_id) => HttpResponse::Ok().json(serde_json::json!({"status": "success","data": {
+            "credential_id": credential_id
+        }})),
+        Err(err) => passkey_error_response(err),
+    }
+}
 
-    let user_id = match rs.into_iter().next() {
-        Some(entry) => {
-            let user = SearchEntry::construct(entry);
-            let user_id = user.attrs.get("uid").unwrap().get(0).unwrap().parse::<u64>().unwrap();
-            user_id
-        },
-        None => {
+#[post("/passkey/login/start")]
+async fn passkey_login_start_handler(
+    body: web::Json<PasskeyLoginStartSchema>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let webauthn = match webauthn(&data) {
+        Ok(webauthn) => webauthn,
+        Err(response) => return response,
+    };
+    let user_id = match data.identity.find_by_email(&body.email).await {
+        Ok(Some(identity)) => identity.user_id,
+        Ok(None) => return passkey_error_response(PasskeyError::NoPasskeys),
+        Err(err) => {
             return HttpResponse::InternalServerError()
-                .json(serde_json::json!({"status": "error","message": "User not found"}));
+                .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
     };
 
-    let dn = format!("uid={},{}", user_id, base_dn);
-    let password = body.password.as_str(); // The password to check
+    match webauthn_service::start_authentication(webauthn, data.db_pool.as_ref(), &data.redis_client, user_id).await {
+        Ok((ceremony, options)) => HttpResponse::Ok().json(serde_json::json!({"status": "success","data": {
+            "ceremony": ceremony,
+            "options": options
+        }})),
+        Err(err) => passkey_error_response(err),
+    }
+}
+
+// A verified passkey proves possession and user presence on its own, so it
+// skips the TOTP challenge and issues tokens directly.
+#[post("/passkey/login/finish")]
+async fn passkey_login_finish_handler(
+    req: HttpRequest,
+    body: web::Json<PasskeyLoginFinishSchema>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let webauthn = match webauthn(&data) {
+        Ok(webauthn) => webauthn,
+        Err(response) => return response,
+    };
+    let user_id = match webauthn_service::finish_authentication(webauthn, data.db_pool.as_ref(), &data.redis_client, &body.ceremony, &body.credential).await {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            data.audit.record(AuditEvent::new(AuditEventType::LoginFailure, false, &req).detail(format!("passkey: {:?}", err)));
+            return passkey_error_response(err);
+        }
+    };
 
-    let bind_result = match user_ldap.simple_bind(dn.as_str(), password).await{
-        Ok(bind_result) =>bind_result,
+    let user = match user_cache::get_user(&data, user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return HttpResponse::Conflict().json(
+                serde_json::json!({"status": "fail","message": "the user belonging to this passkey no longer exists"}),
+            );
+        }
         Err(err) => {
             return HttpResponse::InternalServerError()
-                .json(serde_json::json!({"status": "error","message": "Invalid email or password"}));
+                .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
+        }
+    };
+    let groups = match account_gate(&data, user).await {
+        Ok(groups) => groups,
+        Err(response) => return response,
+    };
+    match issue_tokens(&data, &req, user_id, groups, body.scope.as_deref()).await {
+        Ok(tokens) => {
+            data.audit.record(AuditEvent::new(AuditEventType::LoginSuccess, true, &req).actor(user_id).subject(user_id).detail("passkey"));
+            deliver_tokens(&data, tokens, cookie_service::wants_cookies(&data.env, &req))
+        }
+        Err(response) => response,
+    }
+}
+
+// Always answers with a fresh nonce, whether or not the address has an account;
+// the lookup and the mail happen in the background so timing gives nothing away.
+#[post("/magic-link")]
+async fn magic_link_request_handler(
+    body: web::Json<MagicLinkRequestSchema>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match magic_link_service::allow_request(&data.redis_client, &data.env, &body.email).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return HttpResponse::TooManyRequests().json(
+                serde_json::json!({"status": "fail","message": "Too many sign-in links requested, try again later"}),
+            );
         }
-    };  
-    let bind_result = match bind_result.success() {
-        Ok(_) => {},
         Err(err) => {
-            return HttpResponse::BadRequest()
-                .json(serde_json::json!({"status": "error","message": "Invalid email or password"}));
+            return HttpResponse::InternalServerError()
+                .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
-    };  
+    }
 
-    
+    let nonce = magic_link_service::new_nonce();
+    let email = body.email.to_owned();
+    let mail_nonce = nonce.clone();
+    let identity = data.identity.clone();
+    let mailer = data.mailer.clone();
+    let config = data.env.clone();
+    actix_web::rt::spawn(async move {
+        let user = match identity.find_by_email(&email).await {
+            Ok(Some(user)) => user,
+            Ok(None) => return,
+            Err(err) => {
+                println!("❌Magic link lookup for {} failed: {:?}", email, err);
+                return;
+            }
+        };
+        if let Err(err) = magic_link_service::send_magic_link(mailer.as_ref(), &config, user.user_id, &user.email, &mail_nonce).await {
+            println!("❌Could not send the magic link to {}: {}", user.email, err);
+        }
+    });
 
+    HttpResponse::Accepted().json(serde_json::json!({
+        "status": "success",
+        "message": "If the address has an account, a sign-in link is on its way",
+        "nonce": nonce
+    }))
+}
 
-    let access_token_details = match token_service::generate_jwt_token(
-        user_id,
-        data.env.access_token_max_age,
-        data.env.access_token_private_key.to_owned(),
-    ) {
-        Ok(token_details) => token_details,
-        Err(e) => {
-            return HttpResponse::BadGateway()
-                .json(serde_json::json!({"status": "fail", "message": format_args!("{}", e)}));
+#[post("/magic-link/redeem")]
+async fn magic_link_redeem_handler(
+    req: HttpRequest,
+    body: web::Json<MagicLinkRedeemSchema>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let (user_id, email) = match magic_link_service::redeem(&data.redis_client, &data.env, &body.token, &body.nonce).await {
+        Ok(redeemed) => redeemed,
+        Err(MagicLinkError::InvalidLink) => {
+            data.audit.record(AuditEvent::new(AuditEventType::LoginFailure, false, &req).detail("invalid magic link"));
+            return HttpResponse::Forbidden().json(
+                serde_json::json!({"status": "fail","message": "Invalid or expired sign-in link"}),
+            );
+        }
+        Err(MagicLinkError::AlreadyUsed) => {
+            data.audit.record(AuditEvent::new(AuditEventType::LoginFailure, false, &req).detail("magic link reused"));
+            return HttpResponse::Forbidden().json(
+                serde_json::json!({"status": "fail","message": "The sign-in link has already been used"}),
+            );
+        }
+        Err(err) => {
+            return HttpResponse::InternalServerError()
+                .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
     };
 
-    let refresh_token_details = match token_service::generate_jwt_token(
-        user_id,
-        data.env.refresh_token_max_age,
-        data.env.refresh_token_private_key.to_owned(),
-    ) {
-        Ok(token_details) => token_details,
-        Err(e) => {
-            return HttpResponse::BadGateway()
-                .json(serde_json::json!({"status": "fail", "message": format_args!("{}", e)}));
+    // the link only stands for the address it was mailed to
+    let user = match data.identity.find_by_id(user_id).await {
+        Ok(Some(identity)) if identity.email.eq_ignore_ascii_case(&email) => CachedUser::from(identity),
+        Ok(_) => {
+            return HttpResponse::Forbidden().json(
+                serde_json::json!({"status": "fail","message": "Invalid or expired sign-in link"}),
+            );
+        }
+        Err(err) => {
+            return HttpResponse::InternalServerError()
+                .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
     };
+    finish_login(&data, &req, user, "magic_link", body.scope.as_deref()).await
+}
 
-    HttpResponse::Ok()
-        .json(json!({"status": "success", "access": access_token_details.token.clone().unwrap() , "refresh":refresh_token_details.token.clone().unwrap()}))
+// Marks a refresh token as spent for as long as a refresh token lives. The
+// mark is set only if absent, so of two requests racing with one token only
+// the first gets true.
+async fn spend_refresh_token(data: &AppState, details: &TokenDetails) -> redis::RedisResult<bool> {
+    let mut redis_client = data.redis_client.get_async_connection().await?;
+    let claimed: Option<String> = redis::cmd("SET")
+        .arg(details.token_uuid.to_string())
+        .arg(details.user_id.to_string())
+        .arg("NX")
+        .arg("EX")
+        .arg(data.env.refresh_token_max_age * 60)
+        .query_async(&mut redis_client)
+        .await?;
+    Ok(claimed.is_some())
 }
+
 #[post("/refresh")]
 async fn refresh_token_handler(
+    req: HttpRequest,
     data: web::Data<AppState>,
     body: web::Json<RefreshSchema>,
 ) -> impl Responder {
 
-    let refresh_token = body.refresh.to_owned();
+    // browser clients leave the body empty and send the cookie, which then
+    // needs the CSRF header like any other cookie-authenticated change
+    let from_cookie = body.refresh.is_empty();
+    let refresh_token = match cookie_service::refresh_token(&data.env, &req) {
+        Some(_) if from_cookie && !cookie_service::csrf_ok(&req) => {
+            return HttpResponse::Forbidden().json(
+                serde_json::json!({"status": "fail", "message": "The CSRF token is missing or does not match"}),
+            );
+        }
+        Some(refresh_token) if from_cookie => refresh_token,
+        _ => body.refresh.to_owned(),
+    };
     if refresh_token.is_empty() {
         return HttpResponse::BadRequest().json(
             serde_json::json!({"status": "fail", "message": "Refresh token is required"}),
@@ -163,73 +727,75 @@ async fn refresh_token_handler(
                 );
             }
         };
-    let result = data.redis_client.get_async_connection().await;
-    let mut redis_client = match result {
-        Ok(redis_client) => redis_client,
-        Err(e) => {
-            return HttpResponse::Forbidden().json(
-                serde_json::json!({"status": "fail", "message": format!("Could not connect to Redis: {}", e)}),
-            );
-        }
-    };
-
-    let redis_result: redis::RedisResult<String> = redis_client
-        .get(refresh_token_details.token_uuid.to_string())
-        .await;
-    
-    let already_consumed_token = match redis_result {
-        Ok(_token) => true,
-        Err(_) => false
-    };
-    if already_consumed_token {
-        return HttpResponse::Forbidden()
-            .json(serde_json::json!({"status": "fail", "message": "The refresh token has already been used"}));
-    }
-
     let user_id= refresh_token_details.user_id;
 
-    let base_dn = "ou=dia,dc=diditalready,dc=com";
-    let filter = format!("(&(objectClass=inetOrgPerson)(uid={}))",user_id);
-    let ldap = get_admin_ldap(&data.ldap_pool,&data.env.ldap_admin_dn,&data.env.ldap_admin_password).await;
-    let mut ldap = match ldap {
-        Ok(ldap) => ldap,
+    // refresh tokens from before the account was disabled stay revoked
+    match account_service::token_revoked(&data.redis_client, user_id, refresh_token_details.issued_at.unwrap_or_default()).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return HttpResponse::Forbidden()
+                .json(serde_json::json!({"status": "fail", "message": "The refresh token has been revoked"}));
+        }
         Err(err) => {
             return HttpResponse::InternalServerError()
                 .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
-    };
-    let search_result = ldap
-        .search(
-            &base_dn,
-            Scope::Subtree,
-            &filter, 
-            vec!["uid"],
-        )
-        .await;
-    let search_result = match search_result {
-        Ok(search_result) => search_result,
+    }
+
+    let user = match user_cache::get_user(&data, user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return HttpResponse::Conflict().json(
+                serde_json::json!({"status": "fail","message": "the user belonging to this token no longer exists"}),
+            );
+        }
         Err(err) => {
             return HttpResponse::InternalServerError()
                 .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
     };
+    let groups = match account_gate(&data, user).await {
+        Ok(groups) => groups,
+        Err(response) => return response,
+    };
+    // keep the scopes of the login, less any the user has lost since
+    let previous_scope = refresh_token_details.scopes.as_deref().map(scope_service::join);
+    let scopes = match scope_service::grant(previous_scope.as_deref(), &scope_service::allowed_scopes(&data.env, &groups)) {
+        Ok(scopes) => scopes,
+        Err(message) => return invalid_scope(message),
+    };
+    // a bound refresh token only works with a proof from the same key; an
+    // unbound one gets bound when the client starts sending proofs
+    let jkt = match dpop_service::verify_proof(&data.redis_client, &data.env, &req, None).await {
+        Ok(jkt) => jkt,
+        Err(err) => return dpop_error_response(err),
+    };
+    if refresh_token_details.jkt.is_some() && refresh_token_details.jkt != jkt {
+        return dpop_error_response(DpopError::Invalid("The refresh token is bound to another DPoP key".to_string()));
+    }
 
-    let (rs,_res) = match search_result.success() {
-        Ok(search_result) => search_result,
+    match spend_refresh_token(&data, &refresh_token_details).await {
+        Ok(true) => {}
+        Ok(false) => {
+            data.audit.record(
+                AuditEvent::new(AuditEventType::RefreshReuse, false, &req)
+                    .subject(user_id)
+                    .detail(refresh_token_details.token_uuid.to_string()),
+            );
+            return HttpResponse::Forbidden()
+                .json(serde_json::json!({"status": "fail", "message": "The refresh token has already been used"}));
+        }
         Err(err) => {
             return HttpResponse::InternalServerError()
                 .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
-    };
-    if !(rs.len() > 0) {
-        return HttpResponse::Conflict().json(
-            serde_json::json!({"status": "fail","message": "the user belonging to this token no longer exists"}),
-        );    
     }
 
-
     let access_token_details = match token_service::generate_jwt_token(
         user_id,
+        groups.clone(),
+        scopes.clone(),
+        jkt.clone(),
         data.env.access_token_max_age,
         data.env.access_token_private_key.to_owned(),
     ) {
@@ -240,18 +806,12 @@ async fn refresh_token_handler(
         }
     };
 
-    
 
-    let redis_result: redis::RedisResult<()> = redis_client
-        .set_ex(
-            refresh_token_details.token_uuid.to_string(),
-            user_id.to_string(),
-            (data.env.access_token_max_age * 60) as usize,
-        )
-        .await;
-    
     let refresh_token_details = match token_service::generate_jwt_token(
         user_id,
+        groups,
+        scopes.clone(),
+        jkt.clone(),
         data.env.refresh_token_max_age,
         data.env.refresh_token_private_key.to_owned(),
     ) {
@@ -262,17 +822,49 @@ async fn refresh_token_handler(
         }
     };
 
-    if redis_result.is_err() {
-        return HttpResponse::UnprocessableEntity().json(
-            serde_json::json!({"status": "error", "message": format_args!("{:?}", redis_result.unwrap_err())}),
-        );
+    data.audit.record(AuditEvent::new(AuditEventType::Refresh, true, &req).actor(user_id).subject(user_id));
+    let tokens = serde_json::json!({"status": "success", "access": access_token_details.token.unwrap(), "refresh": refresh_token_details.token.unwrap(), "scope": scope_service::join(&scopes), "token_type": token_type(&jkt)});
+    deliver_tokens(&data, tokens, from_cookie || cookie_service::wants_cookies(&data.env, &req))
+}
+
+
+// Spends the refresh token, when one is presented, and clears the token
+// cookies. Access tokens stay valid until they expire. The refresh cookie is
+// only sent below /api/auth/refresh, so browser clients log out there.
+#[routes]
+#[post("/logout")]
+#[post("/refresh/logout")]
+async fn logout_handler(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    body: Option<web::Json<RefreshSchema>>,
+) -> impl Responder {
+    let from_body = body.map(|body| body.into_inner().refresh).unwrap_or_default();
+    let refresh_token = match cookie_service::refresh_token(&data.env, &req) {
+        Some(_) if from_body.is_empty() && !cookie_service::csrf_ok(&req) => {
+            return HttpResponse::Forbidden().json(
+                serde_json::json!({"status": "fail", "message": "The CSRF token is missing or does not match"}),
+            );
+        }
+        Some(refresh_token) if from_body.is_empty() => refresh_token,
+        _ => from_body,
+    };
+
+    if let Ok(details) = token_service::verify_jwt_token(data.env.refresh_token_public_key.to_owned(), &refresh_token) {
+        // a token spent before is just as logged out
+        if let Err(err) = spend_refresh_token(&data, &details).await {
+            return HttpResponse::InternalServerError()
+                .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
+        }
     }
 
-    HttpResponse::Ok()
-        .json(serde_json::json!({"status": "success", "access": access_token_details.token.unwrap(), "refresh": refresh_token_details.token.unwrap()}))
+    let mut response = HttpResponse::Ok();
+    for cookie in cookie_service::removal_cookies(&data.env) {
+        response.cookie(cookie);
+    }
+    response.json(serde_json::json!({"status": "success","message": "Logged out"}))
 }
 
-
 #[get("/check")]
 async fn check_token_handler(
     req: HttpRequest,
@@ -281,56 +873,18 @@ async fn check_token_handler(
 ) -> impl Responder {
     let ext = req.extensions();
     let user_id = ext.get::<u64>().unwrap().to_owned();
-    let base_dn = "ou=dia,dc=diditalready,dc=com";
-    let filter = format!("(&(objectClass=inetOrgPerson)(uid={}))",user_id);
-    let ldap = get_admin_ldap(&data.ldap_pool,&data.env.ldap_admin_dn,&data.env.ldap_admin_password).await;
-    let mut ldap = match ldap {
-        Ok(ldap) => ldap,
-        Err(err) => {
-            return HttpResponse::InternalServerError()
-                .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
-        }
-    };
-    let search_result = ldap
-        .search(
-            &base_dn,
-            Scope::Subtree,
-            &filter, 
-            vec!["mail"],
-        )
-        .await;
-    let search_result = match search_result {
-        Ok(search_result) => search_result,
-        Err(err) => {
-            return HttpResponse::InternalServerError()
-                .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
+    let email = match user_cache::get_user(&data, user_id).await {
+        Ok(Some(user)) => user.email,
+        Ok(None) => {
+            return HttpResponse::Conflict().json(
+                serde_json::json!({"status": "fail","message": "the user belonging to this token no longer exists"}),
+            );
         }
-    };
-
-    let (rs,_res) = match search_result.success() {
-        Ok(search_result) => search_result,
         Err(err) => {
             return HttpResponse::InternalServerError()
                 .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
     };
-    if !(rs.len() > 0) {
-        return HttpResponse::Conflict().json(
-            serde_json::json!({"status": "fail","message": "the user belonging to this token no longer exists"}),
-        );    
-    }
-
-    let email = match rs.into_iter().next() {
-        Some(entry) => {
-            let user = SearchEntry::construct(entry);
-            let email = user.attrs.get("mail").unwrap().get(0).unwrap().to_string();
-            email
-        },
-        None => {
-            return HttpResponse::InternalServerError()
-                .json(serde_json::json!({"status": "error","message": "Mail not found"}));
-        }
-    };
 
     let json_response = serde_json::json!({
         "status":  "success",
@@ -352,6 +906,170 @@ pub fn config(conf: &mut web::ServiceConfig) {
     let scope = web::scope("/api/auth")
         .service(login_user_handler)
         .service(check_token_handler)
-        .service(refresh_token_handler);
+        .service(refresh_token_handler)
+        .service(mfa_verify_handler)
+        .service(mfa_enroll_handler)
+        .service(mfa_confirm_handler)
+        .service(mfa_recovery_codes_handler)
+        .service(mfa_disable_handler)
+        .service(passkey_register_start_handler)
+        .service(passkey_register_finish_handler)
+        .service(passkey_login_start_handler)
+        .service(passkey_login_finish_handler)
+        .service(magic_link_request_handler)
+        .service(magic_link_redeem_handler)
+        .service(logout_handler);
     conf.service(scope);
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity_store::IdentityStore;
+    use crate::scope_service::{SCOPE_PROFILE_READ, SCOPE_PROFILE_WRITE};
+    use crate::test_support::{access_token, memory_store, redis_available, test_state};
+    use actix_web::{cookie::Cookie, http::StatusCode, test, App};
+
+    // test_state with BROWSER_COOKIES on
+    async fn cookie_state() -> web::Data<AppState> {
+        let mut state = std::sync::Arc::try_unwrap(test_state(memory_store().await).into_inner()).ok().unwrap();
+        state.env.browser_cookies = true;
+        web::Data::new(state)
+    }
+
+    fn refresh_token(data: &AppState) -> String {
+        let scopes = vec![SCOPE_PROFILE_READ.to_string(), SCOPE_PROFILE_WRITE.to_string()];
+        token_service::generate_jwt_token(2, Vec::new(), scopes, None, data.env.refresh_token_max_age, data.env.refresh_token_private_key.clone())
+            .unwrap()
+            .token
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn wrong_passwords_are_refused() {
+        let data = test_state(memory_store().await);
+        let app = test::init_service(App::new().app_data(data.clone()).configure(config)).await;
+        let req = test::TestRequest::post()
+            .uri("/api/auth/login")
+            .set_json(serde_json::json!({"email": "user@example.com", "password": "wrong"}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn disabled_accounts_cannot_log_in() {
+        let store = memory_store().await;
+        store.set_disabled(2, true).await.unwrap();
+        let data = test_state(store);
+        // pending approvals are looked up in Redis before the disabled flag
+        if !redis_available(&data).await {
+            return;
+        }
+        let app = test::init_service(App::new().app_data(data.clone()).configure(config)).await;
+        let req = test::TestRequest::post()
+            .uri("/api/auth/login")
+            .set_json(serde_json::json!({"email": "user@example.com", "password": "correct horse"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["message"], "The account is disabled");
+    }
+
+    #[actix_web::test]
+    async fn logout_clears_the_token_cookies() {
+        let data = test_state(memory_store().await);
+        let app = test::init_service(App::new().app_data(data.clone()).configure(config)).await;
+        let req = test::TestRequest::post().uri("/api/auth/logout").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let cleared: Vec<(String, String)> = resp
+            .response()
+            .cookies()
+            .map(|cookie| {
+                assert_eq!(cookie.max_age(), Some(actix_web::cookie::time::Duration::ZERO));
+                (cookie.name().to_string(), cookie.path().unwrap_or_default().to_string())
+            })
+            .collect();
+        for (name, path) in [("access_token", "/api"), ("refresh_token", "/api/auth/refresh"), ("csrf_token", "/")] {
+            assert!(cleared.contains(&(name.to_string(), path.to_string())), "{} at {}", name, path);
+        }
+    }
+
+    #[actix_web::test]
+    async fn cookie_requests_need_the_csrf_header() {
+        let data = cookie_state().await;
+        if !redis_available(&data).await {
+            return;
+        }
+        let app = test::init_service(App::new().app_data(data.clone()).configure(config)).await;
+        let access = access_token(&data.env, 2, &[], &[SCOPE_PROFILE_READ, SCOPE_PROFILE_WRITE]);
+        let cookies = || {
+            test::TestRequest::post()
+                .uri("/api/auth/mfa/enroll")
+                .cookie(Cookie::new("access_token", access.clone()))
+                .cookie(Cookie::new("csrf_token", "double-submit"))
+        };
+
+        for req in [cookies(), cookies().insert_header(("X-CSRF-Token", "something else"))] {
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            assert_eq!(body["message"], "The CSRF token is missing or does not match");
+        }
+        let req = cookies().insert_header(("X-CSRF-Token", "double-submit")).to_request();
+        assert_ne!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+        // safe methods need no header
+        let req = test::TestRequest::get()
+            .uri("/api/auth/check")
+            .cookie(Cookie::new("access_token", access.clone()))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn refresh_tokens_work_once() {
+        let data = test_state(memory_store().await);
+        if !redis_available(&data).await {
+            return;
+        }
+        let app = test::init_service(App::new().app_data(data.clone()).configure(config)).await;
+        let refresh = refresh_token(&data);
+        let request = || {
+            test::TestRequest::post()
+                .uri("/api/auth/refresh")
+                .set_json(serde_json::json!({"refresh": refresh}))
+                .to_request()
+        };
+        assert_eq!(test::call_service(&app, request()).await.status(), StatusCode::OK);
+        let resp = test::call_service(&app, request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["message"], "The refresh token has already been used");
+    }
+
+    #[actix_web::test]
+    async fn browsers_log_out_where_the_refresh_cookie_is_sent() {
+        let data = cookie_state().await;
+        if !redis_available(&data).await {
+            return;
+        }
+        let app = test::init_service(App::new().app_data(data.clone()).configure(config)).await;
+        let refresh = refresh_token(&data);
+        let logout = || {
+            test::TestRequest::post()
+                .uri("/api/auth/refresh/logout")
+                .cookie(Cookie::new("refresh_token", refresh.clone()))
+                .cookie(Cookie::new("csrf_token", "double-submit"))
+        };
+        assert_eq!(test::call_service(&app, logout().to_request()).await.status(), StatusCode::FORBIDDEN);
+        let req = logout().insert_header(("X-CSRF-Token", "double-submit")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/api/auth/refresh")
+            .set_json(serde_json::json!({"refresh": refresh}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+    }
+}
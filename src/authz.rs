@@ -0,0 +1,303 @@
+use std::marker::PhantomData;
+
+use actix_web::error::InternalError;
+use actix_web::{dev::Payload, http, web, Error as ActixWebError, FromRequest, HttpRequest, HttpResponse};
+use futures::future::LocalBoxFuture;
+
+use crate::config::Config;
+use crate::group_service::is_admin;
+use crate::jwt_auth::JwtMiddleware;
+use crate::scope_service::{SCOPE_ADMIN, SCOPE_PROFILE_READ, SCOPE_PROFILE_WRITE};
+use crate::AppState;
+
+// Authorization on top of `JwtMiddleware`. Handlers take one of these
+// extractors instead of the bare token to state who may call them:
+//
+//   RequireRole<Admin>  members of LDAP_ADMIN_GROUP
+//   OwnerOrAdmin        the user named by the `{id}` path segment, or an admin
+//   RequireScope<S>     tokens granted the scope S
+//
+// Routes that take none of these, nor a `JwtMiddleware`, are public. The tests
+// below list every route with its guard, so a new one has to be placed there.
+
+fn forbidden(message: &str) -> ActixWebError {
+    InternalError::from_response(
+        message.to_string(),
+        HttpResponse::Forbidden().json(serde_json::json!({"status": "fail","message": message})),
+    )
+    .into()
+}
+
+// A role a caller can hold, decided from the groups in their token. Acting in
+// the role also needs the token to carry SCOPE.
+pub trait Role {
+    const NAME: &'static str;
+    const SCOPE: &'static str;
+    fn granted(config: &Config, groups: &[String]) -> bool;
+}
+
+pub struct Admin;
+
+impl Role for Admin {
+    const NAME: &'static str = "admin";
+    const SCOPE: &'static str = SCOPE_ADMIN;
+    fn granted(config: &Config, groups: &[String]) -> bool {
+        is_admin(config, groups)
+    }
+}
+
+pub struct RequireRole<R: Role> {
+    pub auth: JwtMiddleware,
+    role: PhantomData<R>,
+}
+
+impl<R: Role + 'static> FromRequest for RequireRole<R> {
+    type Error = ActixWebError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let data = req.app_data::<web::Data<AppState>>().unwrap().clone();
+        let auth = JwtMiddleware::from_request(req, payload);
+        Box::pin(async move {
+            let auth = auth.await?;
+            if !auth.has_scope(R::SCOPE) {
+                return Err(insufficient_scope(R::SCOPE));
+            }
+            if !R::granted(&data.env, &auth.groups) {
+                return Err(forbidden(&format!("The {} role is required", R::NAME)));
+            }
+            Ok(RequireRole { auth, role: PhantomData })
+        })
+    }
+}
+
+// For routes with an `{id}` segment naming a user.
+pub struct OwnerOrAdmin {
+    pub auth: JwtMiddleware,
+    pub id: u64,
+}
+
+impl FromRequest for OwnerOrAdmin {
+    type Error = ActixWebError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let data = req.app_data::<web::Data<AppState>>().unwrap().clone();
+        let id = req.match_info().get("id").and_then(|id| id.parse::<u64>().ok());
+        let auth = JwtMiddleware::from_request(req, payload);
+        Box::pin(async move {
+            let auth = auth.await?;
+            let id = match id {
+                Some(id) => id,
+                None => return Err(forbidden("You are not allowed to access this user")),
+            };
+            if auth.user_id != id && !Admin::granted(&data.env, &auth.groups) {
+                return Err(forbidden("You are not allowed to access this user"));
+            }
+            Ok(OwnerOrAdmin { auth, id })
+        })
+    }
+}
+
+// Answers 403 insufficient_scope with the challenge RFC 6750 asks for.
+fn insufficient_scope(scope: &str) -> ActixWebError {
+    let message = format!("The token lacks the {} scope", scope);
+    InternalError::from_response(
+        message.clone(),
+        HttpResponse::Forbidden()
+            .insert_header((
+                http::header::WWW_AUTHENTICATE,
+                format!("Bearer error=\"insufficient_scope\", scope=\"{}\"", scope),
+            ))
+            .json(serde_json::json!({"status": "fail","error": "insufficient_scope","message": message})),
+    )
+    .into()
+}
+
+// A scope a route requires, named by a marker type.
+pub trait RequiredScope {
+    const SCOPE: &'static str;
+}
+
+pub struct ProfileRead;
+
+impl RequiredScope for ProfileRead {
+    const SCOPE: &'static str = SCOPE_PROFILE_READ;
+}
+
+pub struct ProfileWrite;
+
+impl RequiredScope for ProfileWrite {
+    const SCOPE: &'static str = SCOPE_PROFILE_WRITE;
+}
+
+pub struct RequireScope<S: RequiredScope> {
+    pub auth: JwtMiddleware,
+    scope: PhantomData<S>,
+}
+
+impl<S: RequiredScope + 'static> FromRequest for RequireScope<S> {
+    type Error = ActixWebError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let auth = JwtMiddleware::from_request(req, payload);
+        Box::pin(async move {
+            let auth = auth.await?;
+            if !auth.has_scope(S::SCOPE) {
+                return Err(insufficient_scope(S::SCOPE));
+            }
+            Ok(RequireScope { auth, scope: PhantomData })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scope_service::{ALL_SCOPES, SCOPE_PROFILE_WRITE};
+    use crate::test_support::{access_token, memory_store, redis_available, test_state};
+    use crate::{audit_handler, auth_handler, group_handler, health_handler, pat_handler, sync_handler, user_handler};
+    use actix_web::http::{header, Method, StatusCode};
+    use actix_web::{test, App};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Guard {
+        Public,
+        // any valid token
+        Token,
+        Admin,
+        OwnerOrAdmin,
+    }
+
+    const ROUTES: [(&str, &str, Guard); 49] = [
+        ("GET", "/healthz", Guard::Public),
+        ("GET", "/readyz", Guard::Public),
+        ("POST", "/api/auth/login", Guard::Public),
+        ("POST", "/api/auth/mfa/verify", Guard::Public),
+        ("POST", "/api/auth/passkey/login/start", Guard::Public),
+        ("POST", "/api/auth/passkey/login/finish", Guard::Public),
+        ("POST", "/api/auth/magic-link", Guard::Public),
+        ("POST", "/api/auth/magic-link/redeem", Guard::Public),
+        ("POST", "/api/auth/refresh", Guard::Public),
+        ("POST", "/api/auth/logout", Guard::Public),
+        ("POST", "/api/auth/refresh/logout", Guard::Public),
+        ("POST", "/api/user/", Guard::Public),
+        ("GET", "/api/user/verify", Guard::Public),
+        ("POST", "/api/user/verify", Guard::Public),
+        ("POST", "/api/user/verify/resend", Guard::Public),
+        ("GET", "/api/auth/check", Guard::Token),
+        ("POST", "/api/auth/mfa/enroll", Guard::Token),
+        ("POST", "/api/auth/mfa/confirm", Guard::Token),
+        ("POST", "/api/auth/mfa/recovery-codes", Guard::Token),
+        ("POST", "/api/auth/mfa/disable", Guard::Token),
+        ("POST", "/api/auth/passkey/register/start", Guard::Token),
+        ("POST", "/api/auth/passkey/register/finish", Guard::Token),
+        ("POST", "/api/tokens", Guard::Token),
+        ("GET", "/api/tokens", Guard::Token),
+        ("POST", "/api/tokens/abc/rotate", Guard::Token),
+        ("DELETE", "/api/tokens/abc", Guard::Token),
+        ("GET", "/api/audit", Guard::Admin),
+        ("GET", "/api/sync/status", Guard::Admin),
+        ("POST", "/api/groups/", Guard::Admin),
+        ("DELETE", "/api/groups/staff", Guard::Admin),
+        ("GET", "/api/groups/user/2", Guard::Admin),
+        ("GET", "/api/groups/staff/members", Guard::Admin),
+        ("POST", "/api/groups/staff/members", Guard::Admin),
+        ("DELETE", "/api/groups/staff/members/2", Guard::Admin),
+        ("GET", "/api/user", Guard::Admin),
+        ("POST", "/api/user/import", Guard::Admin),
+        ("GET", "/api/user/export", Guard::Admin),
+        ("POST", "/api/user/invites", Guard::Admin),
+        ("GET", "/api/user/invites", Guard::Admin),
+        ("DELETE", "/api/user/invites/abc", Guard::Admin),
+        ("GET", "/api/user/pending", Guard::Admin),
+        ("POST", "/api/user/pending/2/approve", Guard::Admin),
+        ("POST", "/api/user/pending/2/reject", Guard::Admin),
+        ("GET", "/api/user/disabled", Guard::Admin),
+        ("POST", "/api/user/2/disable", Guard::Admin),
+        ("POST", "/api/user/2/enable", Guard::Admin),
+        ("GET", "/api/user/1", Guard::OwnerOrAdmin),
+        ("PATCH", "/api/user/1", Guard::OwnerOrAdmin),
+        ("DELETE", "/api/user/1", Guard::OwnerOrAdmin),
+    ];
+
+    fn request(method: &str, path: &str) -> test::TestRequest {
+        test::TestRequest::default().method(Method::from_bytes(method.as_bytes()).unwrap()).uri(path)
+    }
+
+    #[actix_web::test]
+    async fn only_public_routes_answer_without_a_token() {
+        let data = test_state(memory_store().await);
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .configure(user_handler::config)
+                .configure(auth_handler::config)
+                .configure(health_handler::config)
+                .configure(group_handler::config)
+                .configure(sync_handler::config)
+                .configure(pat_handler::config)
+                .configure(audit_handler::config),
+        )
+        .await;
+        for (method, path, guard) in ROUTES {
+            let resp = test::call_service(&app, request(method, path).to_request()).await;
+            let status = resp.status();
+            let body = String::from_utf8_lossy(&test::read_body(resp).await).to_string();
+            if guard == Guard::Public {
+                assert!(!body.contains("No token found"), "{} {} asks for a token", method, path);
+            } else {
+                assert_eq!(status, StatusCode::UNAUTHORIZED, "{} {} answers without a token", method, path);
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn admin_routes_refuse_other_users() {
+        let data = test_state(memory_store().await);
+        // tokens are checked against the revocation list in Redis
+        if !redis_available(&data).await {
+            return;
+        }
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .configure(user_handler::config)
+                .configure(group_handler::config)
+                .configure(sync_handler::config)
+                .configure(audit_handler::config),
+        )
+        .await;
+        // every scope, but not in the admin group
+        let token = access_token(&data.env, 2, &[], &ALL_SCOPES);
+        for (method, path, guard) in ROUTES {
+            if guard != Guard::Admin && guard != Guard::OwnerOrAdmin {
+                continue;
+            }
+            let req = request(method, path)
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .to_request();
+            let status = test::call_service(&app, req).await.status();
+            assert_eq!(status, StatusCode::FORBIDDEN, "{} {} lets a user in", method, path);
+        }
+    }
+
+    #[actix_web::test]
+    async fn missing_scopes_get_the_insufficient_scope_challenge() {
+        let data = test_state(memory_store().await);
+        if !redis_available(&data).await {
+            return;
+        }
+        let app = test::init_service(App::new().app_data(data.clone()).configure(user_handler::config)).await;
+        let token = access_token(&data.env, 2, &[], &[SCOPE_PROFILE_WRITE]);
+        let req = test::TestRequest::get()
+            .uri("/api/user/2")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            resp.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            "Bearer error=\"insufficient_scope\", scope=\"profile:read\""
+        );
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "insufficient_scope");
+    }
+}
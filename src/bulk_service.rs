@@ -0,0 +1,401 @@
+use base64::{engine::general_purpose, Engine as _};
+use serde::Serialize;
+use std::collections::HashSet;
+
+use crate::config::Config;
+use crate::group_service::user_dn;
+use crate::identity_store::{Identity, IdentityError, IdentityStore, NewIdentity};
+use crate::registration_service::validate_account;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BulkFormat {
+    Csv,
+    Ldif,
+}
+
+impl BulkFormat {
+    pub fn parse(value: &str) -> Option<BulkFormat> {
+        match value.to_ascii_lowercase().as_str() {
+            "csv" => Some(BulkFormat::Csv),
+            "ldif" => Some(BulkFormat::Ldif),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            BulkFormat::Csv => "text/csv; charset=utf-8",
+            BulkFormat::Ldif => "text/x-ldif; charset=utf-8",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            BulkFormat::Csv => "csv",
+            BulkFormat::Ldif => "ldif",
+        }
+    }
+}
+
+// One account read from an import file, before validation.
+#[derive(Debug)]
+pub struct ImportRecord {
+    pub line: usize,
+    pub user_id: String,
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportResult {
+    pub line: usize,
+    pub user_id: Option<u64>,
+    pub email: String,
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub total: usize,
+    // created, or for a dry run that would be created
+    pub accepted: usize,
+    pub failed: usize,
+    pub results: Vec<ImportResult>,
+}
+
+impl ImportReport {
+    // The uid and email of every account the import created.
+    pub fn created(&self) -> Vec<(u64, String)> {
+        self.results
+            .iter()
+            .filter(|result| result.status == "created")
+            .filter_map(|result| result.user_id.map(|uid| (uid, result.email.clone())))
+            .collect()
+    }
+}
+
+pub fn parse_import(format: BulkFormat, text: &str) -> Result<Vec<ImportRecord>, String> {
+    match format {
+        BulkFormat::Csv => parse_csv(text),
+        BulkFormat::Ldif => parse_ldif(text),
+    }
+}
+
+// Expects a header row naming the user_id, email and password columns, in any order.
+fn parse_csv(text: &str) -> Result<Vec<ImportRecord>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(text.as_bytes());
+    let headers = reader.headers().map_err(|err| err.to_string())?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|header| header.eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("CSV header is missing the {} column", name))
+    };
+    let (user_id_col, email_col, password_col) = (column("user_id")?, column("email")?, column("password")?);
+
+    let mut records = Vec::new();
+    for row in reader.records() {
+        let row = row.map_err(|err| err.to_string())?;
+        let line = row.position().map(|position| position.line() as usize).unwrap_or_default();
+        let field = |index: usize| row.get(index).unwrap_or_default().to_string();
+        records.push(ImportRecord {
+            line,
+            user_id: field(user_id_col),
+            email: field(email_col),
+            password: field(password_col),
+        });
+    }
+    Ok(records)
+}
+
+// Reads uid, mail (or userPrincipalName) and userPassword from each entry.
+// Folded lines and base64 values are supported, URL values and change
+// records other than add are not.
+fn parse_ldif(text: &str) -> Result<Vec<ImportRecord>, String> {
+    // unfold continuation lines, keeping the number of the line each one starts on
+    let mut lines: Vec<(usize, String)> = Vec::new();
+    for (index, raw) in text.lines().enumerate() {
+        if let Some(rest) = raw.strip_prefix(' ') {
+            match lines.last_mut() {
+                Some((_, previous)) if !previous.is_empty() => previous.push_str(rest),
+                _ => return Err(format!("line {}: continuation without a preceding line", index + 1)),
+            }
+        } else {
+            lines.push((index + 1, raw.to_string()));
+        }
+    }
+
+    let mut records = Vec::new();
+    let mut entry: Vec<(usize, String, String)> = Vec::new();
+    for (line, content) in lines.into_iter().chain(std::iter::once((0, String::new()))) {
+        if content.is_empty() {
+            if !entry.is_empty() {
+                records.push(ldif_record(&entry)?);
+                entry.clear();
+            }
+            continue;
+        }
+        if content.starts_with('#') {
+            continue;
+        }
+        let (name, value) = match content.split_once(':') {
+            Some(pair) => pair,
+            None => return Err(format!("line {}: expected attribute: value", line)),
+        };
+        // attribute options such as mail;lang-en do not matter here
+        let name = name.split(';').next().unwrap_or(name).trim().to_ascii_lowercase();
+        let value = if let Some(encoded) = value.strip_prefix(':') {
+            let decoded = general_purpose::STANDARD
+                .decode(encoded.trim())
+                .map_err(|err| format!("line {}: invalid base64 value: {}", line, err))?;
+            String::from_utf8(decoded).map_err(|_| format!("line {}: value is not UTF-8", line))?
+        } else if value.starts_with('<') {
+            return Err(format!("line {}: URL values are not supported", line));
+        } else {
+            value.trim_start().to_string()
+        };
+        if name == "version" && entry.is_empty() {
+            continue;
+        }
+        entry.push((line, name, value));
+    }
+    Ok(records)
+}
+
+fn ldif_record(entry: &[(usize, String, String)]) -> Result<ImportRecord, String> {
+    let line = entry[0].0;
+    let first = |name: &str| {
+        entry
+            .iter()
+            .find(|(_, attr, _)| attr == name)
+            .map(|(_, _, value)| value.clone())
+    };
+    if let Some(changetype) = first("changetype") {
+        if !changetype.eq_ignore_ascii_case("add") {
+            return Err(format!("line {}: changetype {} is not supported", line, changetype));
+        }
+    }
+    Ok(ImportRecord {
+        line,
+        user_id: first("uid").unwrap_or_default(),
+        email: first("mail").or_else(|| first("userprincipalname")).unwrap_or_default(),
+        password: first("userpassword").unwrap_or_default(),
+    })
+}
+
+fn validate(record: &ImportRecord) -> Result<u64, String> {
+    let user_id = record
+        .user_id
+        .parse::<i64>()
+        .map_err(|_| "user_id must be a positive integer".to_string())?;
+    let user_id = validate_account(user_id, &record.email, &record.password)?;
+    // {SSHA}-style values are already hashed and cannot be re-hashed
+    if record.password.starts_with('{') && record.password.contains('}') {
+        return Err("hashed passwords cannot be imported".to_string());
+    }
+    Ok(user_id)
+}
+
+// Validates every record and, unless `dry_run` is set, creates the valid ones
+// through the identity store so imports get the same uniqueness checks and
+// password hashing as registration. Created accounts start out unverified,
+// like registered ones, until their owners open the mailed link.
+pub async fn import_users(identity: &dyn IdentityStore, records: Vec<ImportRecord>, dry_run: bool) -> ImportReport {
+    let mut results = Vec::with_capacity(records.len());
+    let mut seen_ids = HashSet::new();
+    let mut seen_emails = HashSet::new();
+
+    for record in &records {
+        let mut result = ImportResult {
+            line: record.line,
+            user_id: record.user_id.parse::<u64>().ok(),
+            email: record.email.clone(),
+            status: "invalid",
+            message: None,
+        };
+        let user_id = match validate(record) {
+            Ok(user_id) => user_id,
+            Err(message) => {
+                result.message = Some(message);
+                results.push(result);
+                continue;
+            }
+        };
+        if !seen_ids.insert(user_id) || !seen_emails.insert(record.email.to_lowercase()) {
+            result.message = Some("duplicate user_id or email in the file".to_string());
+            results.push(result);
+            continue;
+        }
+
+        let outcome = if dry_run {
+            match (identity.find_by_id(user_id).await, identity.find_by_email(&record.email).await) {
+                (Ok(None), Ok(None)) => Ok(()),
+                (Err(err), _) | (_, Err(err)) => Err(err),
+                _ => Err(IdentityError::Conflict("User with that email or uid already exists".to_string())),
+            }
+        } else {
+            identity
+                .create(NewIdentity { user_id, email: &record.email, password: &record.password, verified: false, disabled: false })
+                .await
+                .map(|_| ())
+        };
+        match outcome {
+            Ok(_) => result.status = if dry_run { "valid" } else { "created" },
+            Err(IdentityError::Conflict(message)) => {
+                result.status = "conflict";
+                result.message = Some(message);
+            }
+            Err(IdentityError::Invalid(message)) => result.message = Some(message),
+            Err(err) => {
+                result.status = "error";
+                result.message = Some(format!("{:?}", err));
+            }
+        }
+        results.push(result);
+    }
+
+    let accepted = results.iter().filter(|result| matches!(result.status, "created" | "valid")).count();
+    ImportReport {
+        dry_run,
+        total: results.len(),
+        accepted,
+        failed: results.len() - accepted,
+        results,
+    }
+}
+
+pub fn export_users(config: &Config, format: BulkFormat, identities: &[Identity]) -> Result<String, String> {
+    match format {
+        BulkFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            writer.write_record(["user_id", "email"]).map_err(|err| err.to_string())?;
+            for identity in identities {
+                writer
+                    .write_record([identity.user_id.to_string(), identity.email.clone()])
+                    .map_err(|err| err.to_string())?;
+            }
+            let bytes = writer.into_inner().map_err(|err| err.to_string())?;
+            String::from_utf8(bytes).map_err(|err| err.to_string())
+        }
+        BulkFormat::Ldif => {
+            let mut ldif = String::from("version: 1\n");
+            for identity in identities {
+                ldif.push('\n');
+                ldif.push_str(&ldif_line("dn", &user_dn(config, identity.user_id)));
+                for class in config.ldap_flavor.user_object_classes() {
+                    ldif.push_str(&ldif_line("objectClass", class));
+                }
+                ldif.push_str(&ldif_line("uid", &identity.user_id.to_string()));
+                ldif.push_str(&ldif_line("mail", &identity.email));
+            }
+            Ok(ldif)
+        }
+    }
+}
+
+// Values that are not "safe strings" per RFC 2849 are written base64-encoded.
+fn ldif_line(name: &str, value: &str) -> String {
+    let safe = value.chars().all(|c| c.is_ascii() && c != '\0' && c != '\n' && c != '\r')
+        && !value.starts_with([' ', ':', '<'])
+        && !value.ends_with(' ');
+    if safe {
+        format!("{}: {}\n", name, value)
+    } else {
+        format!("{}:: {}\n", name, general_purpose::STANDARD.encode(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_identity_store::MemoryIdentityStore;
+
+    fn record(user_id: &str, email: &str, password: &str) -> ImportRecord {
+        ImportRecord { line: 1, user_id: user_id.to_string(), email: email.to_string(), password: password.to_string() }
+    }
+
+    #[test]
+    fn reads_csv_columns_in_any_order() {
+        let records = parse_csv("email, password ,USER_ID\njane@example.com,secret,3\njohn@example.com,hunter2,4\n").unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0].line, records[0].user_id.as_str(), records[0].email.as_str()), (2, "3", "jane@example.com"));
+        assert_eq!(records[1].password, "hunter2");
+        assert_eq!(parse_csv("email,password\njane@example.com,secret\n").unwrap_err(), "CSV header is missing the user_id column");
+    }
+
+    #[test]
+    fn reads_folded_and_base64_ldif_values() {
+        let ldif = "version: 1\n\n# a comment\ndn: uid=3,ou=people,dc=example,dc=com\nuid: 3\nmail: jane@exa\n mple.com\nuserPassword:: c2VjcmV0\n\ndn: uid=4,ou=people,dc=example,dc=com\nchangetype: add\nuid: 4\nuserPrincipalName: john@example.com\nuserPassword: hunter2\n";
+        let records = parse_ldif(ldif).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].line, 4);
+        assert_eq!(records[0].email, "jane@example.com");
+        assert_eq!(records[0].password, "secret");
+        assert_eq!((records[1].user_id.as_str(), records[1].email.as_str()), ("4", "john@example.com"));
+    }
+
+    #[test]
+    fn refuses_unsupported_ldif() {
+        let modify = "dn: uid=3,ou=people,dc=example,dc=com\nchangetype: modify\nreplace: mail\nmail: jane@example.com\n";
+        assert_eq!(parse_ldif(modify).unwrap_err(), "line 1: changetype modify is not supported");
+        assert!(parse_ldif(" continued\n").unwrap_err().contains("continuation without a preceding line"));
+        assert!(parse_ldif("uid: 3\nmail:< file:///etc/passwd\n").unwrap_err().contains("URL values are not supported"));
+        assert!(parse_ldif("uid: 3\nuserPassword:: !!!\n").unwrap_err().contains("invalid base64 value"));
+    }
+
+    #[test]
+    fn validates_records() {
+        assert_eq!(validate(&record("3", "jane@example.com", "secret")), Ok(3));
+        assert!(validate(&record("0", "jane@example.com", "secret")).is_err());
+        assert!(validate(&record("-1", "jane@example.com", "secret")).is_err());
+        assert!(validate(&record("2147483648", "jane@example.com", "secret")).is_err());
+        assert!(validate(&record("three", "jane@example.com", "secret")).is_err());
+        assert!(validate(&record("3", "jane", "secret")).is_err());
+        assert!(validate(&record("3", "jane doe@example.com", "secret")).is_err());
+        assert!(validate(&record("3", "jane@example.com", "")).is_err());
+        assert_eq!(
+            validate(&record("3", "jane@example.com", "{SSHA}abcdef")),
+            Err("hashed passwords cannot be imported".to_string())
+        );
+    }
+
+    #[actix_web::test]
+    async fn imports_valid_records_and_reports_the_rest() {
+        let store = MemoryIdentityStore::new();
+        store
+            .create(NewIdentity { user_id: 1, email: "admin@example.com", password: "secret", verified: true, disabled: false })
+            .await
+            .unwrap();
+        let records = vec![
+            record("3", "jane@example.com", "secret"),
+            record("4", "Jane@example.com", "secret"),
+            record("5", "admin@example.com", "secret"),
+            record("6", "john", "secret"),
+        ];
+        let report = import_users(&store, records, false).await;
+        let statuses: Vec<&str> = report.results.iter().map(|result| result.status).collect();
+        assert_eq!(statuses, vec!["created", "invalid", "conflict", "invalid"]);
+        assert_eq!((report.total, report.accepted, report.failed), (4, 1, 3));
+        assert_eq!(report.created(), vec![(3, "jane@example.com".to_string())]);
+        let created = store.find_by_id(3).await.unwrap().unwrap();
+        assert!(!created.verified);
+        assert_eq!(store.authenticate("jane@example.com", "secret").await.unwrap().user_id, 3);
+    }
+
+    #[actix_web::test]
+    async fn dry_runs_create_nothing() {
+        let store = MemoryIdentityStore::new();
+        let records = vec![record("3", "jane@example.com", "secret"), record("4", "john@example.com", "hunter2")];
+        let report = import_users(&store, records, true).await;
+        assert!(report.dry_run);
+        assert_eq!(report.accepted, 2);
+        assert!(report.results.iter().all(|result| result.status == "valid"));
+        assert!(report.created().is_empty());
+        assert!(store.list_all().await.unwrap().is_empty());
+    }
+}
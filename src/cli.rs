@@ -0,0 +1,172 @@
+use crate::bulk_service::{export_users, import_users, parse_import, BulkFormat};
+use crate::config::Config;
+use crate::group_service::is_valid_group_name;
+use crate::identity_store::{IdentityError, IdentityStore};
+use crate::mail_service::build_transport;
+use crate::user_cache;
+use crate::verification_service::send_verification_mails;
+
+const USAGE: &str = "usage:
+  auth_ms import <file> [--format csv|ldif] [--dry-run]
+  auth_ms export [--format csv|ldif] [--output <file>]
+  auth_ms group add|remove <uid> <group>";
+
+struct Options {
+    path: Option<String>,
+    format: Option<BulkFormat>,
+    dry_run: bool,
+    output: Option<String>,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options { path: None, format: None, dry_run: false, output: None };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                let value = args.next().ok_or("--format needs a value")?;
+                options.format = Some(BulkFormat::parse(value).ok_or("--format must be csv or ldif")?);
+            }
+            "--dry-run" => options.dry_run = true,
+            "--output" => options.output = Some(args.next().ok_or("--output needs a value")?.to_string()),
+            value if !value.starts_with("--") && options.path.is_none() => options.path = Some(value.to_string()),
+            value => return Err(format!("unexpected argument {}", value)),
+        }
+    }
+    Ok(options)
+}
+
+// Guesses the format from the file extension when --format is not given.
+fn format_for(options: &Options) -> BulkFormat {
+    options.format.unwrap_or_else(|| {
+        let path = options.path.as_deref().or(options.output.as_deref()).unwrap_or_default();
+        if path.to_ascii_lowercase().ends_with(".ldif") {
+            BulkFormat::Ldif
+        } else {
+            BulkFormat::Csv
+        }
+    })
+}
+
+// Adds a user to or removes them from a group, e.g. to make the first admin.
+async fn run_group(args: &[String], identity: &dyn IdentityStore, redis_client: &redis::Client) -> i32 {
+    let (member, uid, group) = match args {
+        [action, uid, group] if action == "add" || action == "remove" => match uid.parse::<u64>() {
+            Ok(uid) => (action == "add", uid, group.as_str()),
+            Err(_) => {
+                eprintln!("uid must be a number\n{}", USAGE);
+                return 2;
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    if !is_valid_group_name(group) {
+        eprintln!("Group names may only contain letters, digits, '-' and '_'");
+        return 2;
+    }
+    match identity.set_group_member(uid, group, member).await {
+        Ok(_) => {
+            user_cache::invalidate_users(redis_client, &[uid]).await;
+            println!("{} user {} {} {}", if member { "Added" } else { "Removed" }, uid, if member { "to" } else { "from" }, group);
+            0
+        }
+        Err(IdentityError::NotFound) => {
+            eprintln!("User {} does not exist", uid);
+            1
+        }
+        Err(err) => {
+            eprintln!("Could not change the groups of {}: {:?}", uid, err);
+            1
+        }
+    }
+}
+
+// Runs a bulk import/export or group subcommand and returns the process exit code.
+pub async fn run(args: &[String], config: &Config, identity: &dyn IdentityStore, redis_client: &redis::Client) -> i32 {
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), rest),
+        None => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    if command == "group" {
+        return run_group(rest, identity, redis_client).await;
+    }
+    let options = match parse_options(rest) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            return 2;
+        }
+    };
+    let format = format_for(&options);
+
+    match command {
+        "import" => {
+            let path = match &options.path {
+                Some(path) => path,
+                None => {
+                    eprintln!("{}", USAGE);
+                    return 2;
+                }
+            };
+            let text = match std::fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(err) => {
+                    eprintln!("Could not read {}: {}", path, err);
+                    return 1;
+                }
+            };
+            let records = match parse_import(format, &text) {
+                Ok(records) => records,
+                Err(err) => {
+                    eprintln!("Could not parse {}: {}", path, err);
+                    return 1;
+                }
+            };
+            let report = import_users(identity, records, options.dry_run).await;
+            let created = report.created();
+            let uids: Vec<u64> = created.iter().map(|(uid, _)| *uid).collect();
+            user_cache::invalidate_users(redis_client, &uids).await;
+            match build_transport(config) {
+                Ok(mailer) => send_verification_mails(mailer.as_ref(), config, &created).await,
+                Err(err) => eprintln!("Could not send verification mails: {}", err),
+            }
+
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            if report.failed > 0 { 1 } else { 0 }
+        }
+        "export" => {
+            let identities = match identity.list_all().await {
+                Ok(identities) => identities,
+                Err(err) => {
+                    eprintln!("Could not list users: {:?}", err);
+                    return 1;
+                }
+            };
+            let export = match export_users(config, format, &identities) {
+                Ok(export) => export,
+                Err(err) => {
+                    eprintln!("Could not export users: {}", err);
+                    return 1;
+                }
+            };
+            // stdout already carries the startup messages, so exports always go to a file
+            let path = options.output.clone().unwrap_or_else(|| format!("users.{}", format.extension()));
+            if let Err(err) = std::fs::write(&path, export) {
+                eprintln!("Could not write {}: {}", path, err);
+                return 1;
+            }
+            println!("Exported {} users to {}", identities.len(), path);
+            0
+        }
+        _ => {
+            eprintln!("unknown command {}\n{}", command, USAGE);
+            2
+        }
+    }
+}
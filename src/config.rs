@@ -1,7 +1,34 @@
+use actix_web::cookie::SameSite;
+use base64::{engine::general_purpose, Engine as _};
+use std::net::IpAddr;
+
+use crate::cookie_service;
+use crate::directory::DirectoryFlavor;
+use crate::identity_store::IdentityBackend;
+use crate::verification_service::EmailVerificationMode;
+use crate::registration_service::RegistrationMode;
+
 fn get_env_var(var_name: &str) -> String {
     std::env::var(var_name).unwrap_or_else(|_| panic!("{} must be set", var_name))
 }
 
+fn get_optional_env_var(var_name: &str) -> Option<String> {
+    std::env::var(var_name).ok().filter(|value| !value.is_empty())
+}
+
+fn get_env_var_or(var_name: &str, default: &str) -> String {
+    get_optional_env_var(var_name).unwrap_or_else(|| default.to_string())
+}
+
+fn get_bool_env_var(var_name: &str, default: bool) -> bool {
+    match get_optional_env_var(var_name) {
+        Some(value) => value
+            .parse::<bool>()
+            .unwrap_or_else(|_| panic!("{} must be true or false", var_name)),
+        None => default,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub redis_url: String,
@@ -16,12 +43,73 @@ pub struct Config {
     pub refresh_token_public_key: String,
     pub refresh_token_expires_in: String,
     pub refresh_token_max_age: i64,
-    pub ldap_url: String,
+    pub mfa_encryption_key: Option<Vec<u8>>,
+    pub mfa_issuer: String,
+    pub mfa_challenge_max_age_secs: usize,
+    pub webauthn_rp_id: Option<String>,
+    pub webauthn_origin: Option<String>,
+    pub webauthn_rp_name: String,
+    pub mail_transport: String,
+    pub smtp_url: Option<String>,
+    pub mail_from: String,
+    pub email_verification: EmailVerificationMode,
+    pub email_verification_url: String,
+    pub email_verification_resend_secs: u64,
+    pub email_token_max_age: i64,
+    pub magic_link_url: String,
+    pub magic_link_max_age: i64,
+    pub magic_link_rate_limit: u32,
+    pub magic_link_rate_window_secs: usize,
+    pub pat_default_days: i64,
+    pub pat_max_days: i64,
+    pub registration_mode: RegistrationMode,
+    pub invite_max_age_days: i64,
+    pub registration_notify_email: Option<String>,
+    pub dpop_proof_max_age_secs: u64,
+    pub browser_cookies: bool,
+    pub cookie_access_token: bool,
+    pub cookie_same_site: SameSite,
+    pub cookie_secure: bool,
+    pub cookie_domain: Option<String>,
+    pub trusted_proxies: Vec<IpAddr>,
+    pub identity_backend: IdentityBackend,
+    pub database_url: Option<String>,
+    pub user_sync_interval_secs: u64,
+    pub user_sync_incremental: bool,
+    pub user_sync_full_every: u32,
+    pub ldap_urls: Vec<String>,
+    // bounds connecting as well as every operation on a connection
+    pub ldap_conn_timeout_secs: u64,
+    pub ldap_eject_secs: u64,
+    pub user_cache_ttl_secs: usize,
+    pub user_cache_negative_ttl_secs: usize,
     pub ldap_admin_dn: String,
-    pub ldap_admin_password: String
+    pub ldap_admin_password: String,
+    pub ldap_base_dn: String,
+    pub ldap_group_base_dn: String,
+    pub ldap_admin_group: String,
+    pub ldap_flavor: DirectoryFlavor,
+    pub ldap_disable_attr: String,
+    pub ldap_disable_value: String,
+    pub ldap_disable_note_attr: String,
+    pub ldap_unverified_attr: String,
+    pub ldap_unverified_value: String,
+    pub ldap_starttls: bool,
+    pub ldap_ca_cert: Option<String>,
+    pub ldap_client_cert: Option<String>,
+    pub ldap_client_key: Option<String>,
+    pub ldap_tls_verify_hostname: bool,
 }
 
 impl Config {
+    // Whether connections to the directory are encrypted, which AD requires
+    // before it accepts password writes.
+    pub fn ldap_uses_tls(&self) -> bool {
+        self.ldap_starttls
+            || (!self.ldap_urls.is_empty()
+                && self.ldap_urls.iter().all(|url| url.to_ascii_lowercase().starts_with("ldaps://")))
+    }
+
     pub fn init() -> Config {
         let redis_url = get_env_var("REDIS_URL");
         let client_origin = get_env_var("CLIENT_ORIGIN");
@@ -35,9 +123,116 @@ impl Config {
         let refresh_token_public_key = get_env_var("REFRESH_TOKEN_PUBLIC_KEY");
         let refresh_token_expires_in = get_env_var("REFRESH_TOKEN_EXPIRED_IN");
         let refresh_token_max_age = get_env_var("REFRESH_TOKEN_MAXAGE");
-        let ldap_url = get_env_var("LDAP_URL");
-        let ldap_admin_dn = get_env_var("LDAP_ADMIN_DN");
-        let ldap_admin_password = get_env_var("LDAP_ADMIN_PASSWORD");
+        // 32 bytes, base64-encoded; 2FA enrollment is refused without it. The
+        // secrets are kept in the database, so it needs a DATABASE_URL too
+        let mfa_encryption_key = get_optional_env_var("MFA_ENCRYPTION_KEY").map(|key| {
+            match general_purpose::STANDARD.decode(key) {
+                Ok(key) if key.len() == 32 => key,
+                _ => panic!("MFA_ENCRYPTION_KEY must be 32 base64-encoded bytes"),
+            }
+        });
+        let mfa_issuer = get_env_var_or("MFA_ISSUER", "AuthAPI");
+        let mfa_challenge_max_age_secs = get_env_var_or("MFA_CHALLENGE_MAXAGE", "300");
+        // passkeys stay disabled unless both the RP ID and origin are set, and
+        // are kept in the database
+        let webauthn_rp_id = get_optional_env_var("WEBAUTHN_RP_ID");
+        let webauthn_origin = get_optional_env_var("WEBAUTHN_ORIGIN");
+        let webauthn_rp_name = get_env_var_or("WEBAUTHN_RP_NAME", "AuthAPI");
+        let mail_transport = get_env_var_or("MAIL_TRANSPORT", "log").to_ascii_lowercase();
+        let smtp_url = get_optional_env_var("SMTP_URL");
+        let mail_from = get_env_var_or("MAIL_FROM", "AuthAPI <no-reply@localhost>");
+        let email_verification = EmailVerificationMode::parse(&get_env_var_or("EMAIL_VERIFICATION", "off"))
+            .unwrap_or_else(|| panic!("EMAIL_VERIFICATION must be off, limited or required"));
+        let email_verification_url = get_env_var_or("EMAIL_VERIFICATION_URL", &format!("{}/verify-email", client_origin));
+        let email_verification_resend_secs = get_env_var_or("EMAIL_VERIFICATION_RESEND_SECS", "60");
+        let email_token_max_age = get_env_var_or("EMAIL_TOKEN_MAXAGE", "1440");
+        let magic_link_url = get_env_var_or("MAGIC_LINK_URL", &format!("{}/magic-link", client_origin));
+        let magic_link_max_age = get_env_var_or("MAGIC_LINK_MAXAGE", "15");
+        let magic_link_rate_limit = get_env_var_or("MAGIC_LINK_RATE_LIMIT", "3");
+        let magic_link_rate_window_secs = get_env_var_or("MAGIC_LINK_RATE_WINDOW_SECS", "900");
+        let pat_default_days = get_env_var_or("PAT_DEFAULT_DAYS", "90");
+        let pat_max_days = get_env_var_or("PAT_MAX_DAYS", "365");
+        let registration_mode = RegistrationMode::parse(&get_env_var_or("REGISTRATION_MODE", "open"))
+            .unwrap_or_else(|| panic!("REGISTRATION_MODE must be open, invite or approval"));
+        let invite_max_age_days = get_env_var_or("INVITE_MAX_AGE_DAYS", "7");
+        let registration_notify_email = get_optional_env_var("REGISTRATION_NOTIFY_EMAIL");
+        let dpop_proof_max_age_secs = get_env_var_or("DPOP_PROOF_MAX_AGE_SECS", "60");
+        // token cookies are only handed out when enabled, and then only on request
+        let browser_cookies = get_bool_env_var("BROWSER_COOKIES", false);
+        let cookie_access_token = get_bool_env_var("COOKIE_ACCESS_TOKEN", false);
+        let cookie_same_site = cookie_service::parse_same_site(&get_env_var_or("COOKIE_SAMESITE", "strict"))
+            .unwrap_or_else(|| panic!("COOKIE_SAMESITE must be strict, lax or none"));
+        let cookie_secure = get_bool_env_var("COOKIE_SECURE", true);
+        let cookie_domain = get_optional_env_var("COOKIE_DOMAIN");
+        // forwarding headers (X-Forwarded-For, and -Host/-Proto for DPoP) are only
+        // believed when the connection comes from one of these
+        let trusted_proxies = get_env_var_or("TRUSTED_PROXIES", "")
+            .split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .map(|proxy| proxy.parse::<IpAddr>().unwrap_or_else(|_| panic!("TRUSTED_PROXIES must be a list of IP addresses")))
+            .collect();
+        let identity_backend = IdentityBackend::parse(&get_env_var_or("IDENTITY_BACKEND", "ldap"))
+            .unwrap_or_else(|| panic!("IDENTITY_BACKEND must be ldap, postgres or memory"));
+        let database_url = match identity_backend {
+            IdentityBackend::Postgres => Some(get_env_var("DATABASE_URL")),
+            _ => get_optional_env_var("DATABASE_URL"),
+        };
+        // copying the directory into `users` is off unless an interval is set
+        let user_sync_interval_secs = get_env_var_or("USER_SYNC_INTERVAL_SECS", "0").parse::<u64>().unwrap();
+        let user_sync_incremental = get_bool_env_var("USER_SYNC_INCREMENTAL", false);
+        let user_sync_full_every = get_env_var_or("USER_SYNC_FULL_EVERY", "12");
+        if user_sync_interval_secs > 0 {
+            if identity_backend != IdentityBackend::Ldap {
+                panic!("USER_SYNC_INTERVAL_SECS requires IDENTITY_BACKEND=ldap");
+            }
+            if database_url.is_none() {
+                panic!("USER_SYNC_INTERVAL_SECS requires DATABASE_URL");
+            }
+        }
+        if mfa_encryption_key.is_some() && database_url.is_none() {
+            panic!("MFA_ENCRYPTION_KEY requires DATABASE_URL");
+        }
+        if webauthn_rp_id.is_some() && webauthn_origin.is_some() && database_url.is_none() {
+            panic!("WEBAUTHN_RP_ID requires DATABASE_URL");
+        }
+        // the directory stays optional for the other backends
+        let ldap_required = identity_backend == IdentityBackend::Ldap;
+        // the first URL is the primary, the others are read replicas
+        let ldap_urls: Vec<String> = get_optional_env_var("LDAP_URL")
+            .unwrap_or_default()
+            .split(',')
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect();
+        if ldap_required && ldap_urls.is_empty() {
+            panic!("LDAP_URL must contain at least one URL");
+        }
+        let ldap_conn_timeout_secs = get_env_var_or("LDAP_CONN_TIMEOUT_SECS", "5");
+        let ldap_eject_secs = get_env_var_or("LDAP_EJECT_SECS", "30");
+        let user_cache_ttl_secs = get_env_var_or("USER_CACHE_TTL_SECS", "60");
+        let user_cache_negative_ttl_secs = get_env_var_or("USER_CACHE_NEGATIVE_TTL_SECS", "10");
+        let ldap_admin_dn = if ldap_required { get_env_var("LDAP_ADMIN_DN") } else { get_env_var_or("LDAP_ADMIN_DN", "") };
+        let ldap_admin_password = if ldap_required { get_env_var("LDAP_ADMIN_PASSWORD") } else { get_env_var_or("LDAP_ADMIN_PASSWORD", "") };
+        let ldap_base_dn = get_env_var_or("LDAP_BASE_DN", "ou=dia,dc=diditalready,dc=com");
+        let ldap_group_base_dn = get_env_var_or("LDAP_GROUP_BASE_DN", "ou=groups,dc=diditalready,dc=com");
+        let ldap_admin_group = get_env_var_or("LDAP_ADMIN_GROUP", "admins");
+        let ldap_flavor = DirectoryFlavor::parse(&get_env_var_or("LDAP_FLAVOR", "openldap"))
+            .unwrap_or_else(|| panic!("LDAP_FLAVOR must be openldap or ad"));
+        // how disabled accounts are marked on OpenLDAP; AD uses userAccountControl.
+        // The default locks the account through the ppolicy overlay.
+        let ldap_disable_attr = get_env_var_or("LDAP_DISABLE_ATTR", "pwdAccountLockedTime");
+        let ldap_disable_value = get_env_var_or("LDAP_DISABLE_VALUE", "000001010000Z");
+        // when and why an admin disabled an account, on both flavors
+        let ldap_disable_note_attr = get_env_var_or("LDAP_DISABLE_NOTE_ATTR", "description");
+        // accounts whose address is not confirmed carry this value, on both flavors
+        let ldap_unverified_attr = get_env_var_or("LDAP_UNVERIFIED_ATTR", "employeeType");
+        let ldap_unverified_value = get_env_var_or("LDAP_UNVERIFIED_VALUE", "unverified");
+        let ldap_starttls = get_bool_env_var("LDAP_STARTTLS", false);
+        let ldap_ca_cert = get_optional_env_var("LDAP_CA_CERT");
+        let ldap_client_cert = get_optional_env_var("LDAP_CLIENT_CERT");
+        let ldap_client_key = get_optional_env_var("LDAP_CLIENT_KEY");
+        let ldap_tls_verify_hostname = get_bool_env_var("LDAP_TLS_VERIFY_HOSTNAME", true);
 
         Config {
             redis_url,
@@ -50,11 +245,61 @@ impl Config {
             refresh_token_expires_in,
             access_token_max_age: access_token_max_age.parse::<i64>().unwrap(),
             refresh_token_max_age: refresh_token_max_age.parse::<i64>().unwrap(),
-            ldap_url,
+            mfa_encryption_key,
+            mfa_issuer,
+            mfa_challenge_max_age_secs: mfa_challenge_max_age_secs.parse::<usize>().unwrap(),
+            webauthn_rp_id,
+            webauthn_origin,
+            webauthn_rp_name,
+            mail_transport,
+            smtp_url,
+            mail_from,
+            email_verification,
+            email_verification_url,
+            email_verification_resend_secs: email_verification_resend_secs.parse::<u64>().unwrap(),
+            email_token_max_age: email_token_max_age.parse::<i64>().unwrap(),
+            magic_link_url,
+            magic_link_max_age: magic_link_max_age.parse::<i64>().unwrap(),
+            magic_link_rate_limit: magic_link_rate_limit.parse::<u32>().unwrap(),
+            magic_link_rate_window_secs: magic_link_rate_window_secs.parse::<usize>().unwrap(),
+            pat_default_days: pat_default_days.parse::<i64>().unwrap(),
+            pat_max_days: pat_max_days.parse::<i64>().unwrap(),
+            registration_mode,
+            invite_max_age_days: invite_max_age_days.parse::<i64>().unwrap(),
+            registration_notify_email,
+            dpop_proof_max_age_secs: dpop_proof_max_age_secs.parse::<u64>().unwrap(),
+            browser_cookies,
+            cookie_access_token,
+            cookie_same_site,
+            cookie_secure,
+            cookie_domain,
+            trusted_proxies,
+            identity_backend,
+            database_url,
+            user_sync_interval_secs,
+            user_sync_incremental,
+            user_sync_full_every: user_sync_full_every.parse::<u32>().unwrap(),
+            ldap_urls,
+            ldap_conn_timeout_secs: ldap_conn_timeout_secs.parse::<u64>().unwrap(),
+            ldap_eject_secs: ldap_eject_secs.parse::<u64>().unwrap(),
+            user_cache_ttl_secs: user_cache_ttl_secs.parse::<usize>().unwrap(),
+            user_cache_negative_ttl_secs: user_cache_negative_ttl_secs.parse::<usize>().unwrap(),
             ldap_admin_dn,
-            ldap_admin_password
-            
-            
+            ldap_admin_password,
+            ldap_base_dn,
+            ldap_group_base_dn,
+            ldap_admin_group,
+            ldap_flavor,
+            ldap_disable_attr,
+            ldap_disable_value,
+            ldap_disable_note_attr,
+            ldap_unverified_attr,
+            ldap_unverified_value,
+            ldap_starttls,
+            ldap_ca_cert,
+            ldap_client_cert,
+            ldap_client_key,
+            ldap_tls_verify_hostname,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,125 @@
+use actix_web::cookie::{time::Duration, Cookie, SameSite};
+use actix_web::http::Method;
+use actix_web::HttpRequest;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{engine::general_purpose, Engine as _};
+
+use crate::config::Config;
+
+// Browser clients can have their tokens set as HttpOnly cookies instead of
+// reading them from the JSON body. They opt in per request with this header.
+pub const DELIVERY_HEADER: &str = "X-Token-Delivery";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+pub const ACCESS_COOKIE: &str = "access_token";
+pub const REFRESH_COOKIE: &str = "refresh_token";
+// readable by scripts, which echo it in CSRF_HEADER (double-submit)
+pub const CSRF_COOKIE: &str = "csrf_token";
+
+const ACCESS_PATH: &str = "/api";
+// only sent to /api/auth/refresh and what lies below it, /api/auth/refresh/logout
+const REFRESH_PATH: &str = "/api/auth/refresh";
+
+pub fn parse_same_site(value: &str) -> Option<SameSite> {
+    match value.to_ascii_lowercase().as_str() {
+        "strict" => Some(SameSite::Strict),
+        "lax" => Some(SameSite::Lax),
+        "none" => Some(SameSite::None),
+        _ => None,
+    }
+}
+
+pub fn wants_cookies(config: &Config, req: &HttpRequest) -> bool {
+    config.browser_cookies
+        && req
+            .headers()
+            .get(DELIVERY_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map_or(false, |value| value.eq_ignore_ascii_case("cookie"))
+}
+
+pub fn new_csrf_token() -> String {
+    let mut token = [0u8; 32];
+    OsRng.fill_bytes(&mut token);
+    general_purpose::URL_SAFE_NO_PAD.encode(token)
+}
+
+fn build_cookie(config: &Config, name: &'static str, value: String, path: &'static str, max_age_minutes: i64, http_only: bool) -> Cookie<'static> {
+    let mut cookie = Cookie::build(name, value)
+        .path(path)
+        .http_only(http_only)
+        .secure(config.cookie_secure)
+        .same_site(config.cookie_same_site)
+        .max_age(Duration::minutes(max_age_minutes))
+        .finish();
+    if let Some(domain) = &config.cookie_domain {
+        cookie.set_domain(domain.clone());
+    }
+    cookie
+}
+
+// The cookies for a freshly issued token pair. The access token only goes
+// into a cookie with COOKIE_ACCESS_TOKEN, otherwise the client keeps it in memory.
+pub fn token_cookies(config: &Config, access: &str, refresh: &str, csrf: &str) -> Vec<Cookie<'static>> {
+    let mut cookies = vec![
+        build_cookie(config, REFRESH_COOKIE, refresh.to_string(), REFRESH_PATH, config.refresh_token_max_age, true),
+        build_cookie(config, CSRF_COOKIE, csrf.to_string(), "/", config.refresh_token_max_age, false),
+    ];
+    if config.cookie_access_token {
+        cookies.push(build_cookie(config, ACCESS_COOKIE, access.to_string(), ACCESS_PATH, config.access_token_max_age, true));
+    }
+    cookies
+}
+
+// Expired copies of every token cookie, with the paths and domain they were set
+// with, so the browser drops them on logout.
+pub fn removal_cookies(config: &Config) -> Vec<Cookie<'static>> {
+    let cookies = [
+        (ACCESS_COOKIE, ACCESS_PATH, true),
+        (REFRESH_COOKIE, REFRESH_PATH, true),
+        (CSRF_COOKIE, "/", false),
+    ];
+    cookies
+        .into_iter()
+        .map(|(name, path, http_only)| {
+            let mut cookie = build_cookie(config, name, String::new(), path, 0, http_only);
+            cookie.make_removal();
+            cookie
+        })
+        .collect()
+}
+
+pub fn access_token(config: &Config, req: &HttpRequest) -> Option<String> {
+    if !config.browser_cookies {
+        return None;
+    }
+    req.cookie(ACCESS_COOKIE).map(|cookie| cookie.value().to_string())
+}
+
+pub fn refresh_token(config: &Config, req: &HttpRequest) -> Option<String> {
+    if !config.browser_cookies {
+        return None;
+    }
+    req.cookie(REFRESH_COOKIE).map(|cookie| cookie.value().to_string())
+}
+
+// Double-submit check for requests authenticated by cookie: safe methods pass,
+// anything else must echo the CSRF cookie in CSRF_HEADER.
+pub fn csrf_ok(req: &HttpRequest) -> bool {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return true;
+    }
+    let cookie = match req.cookie(CSRF_COOKIE) {
+        Some(cookie) => cookie.value().to_string(),
+        None => return false,
+    };
+    let header = match req.headers().get(CSRF_HEADER).and_then(|value| value.to_str().ok()) {
+        Some(header) => header,
+        None => return false,
+    };
+    constant_time_eq(cookie.as_bytes(), header.as_bytes())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
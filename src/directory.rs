@@ -0,0 +1,135 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use ldap3::ldap_escape;
+
+// userAccountControl flags used when creating and checking AD accounts.
+pub const AD_ACCOUNT_DISABLED: u32 = 0x2;
+pub const AD_NORMAL_ACCOUNT: u32 = 0x200;
+
+// The directory server conventions the account model follows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirectoryFlavor {
+    OpenLdap,
+    ActiveDirectory,
+}
+
+impl DirectoryFlavor {
+    pub fn parse(value: &str) -> Option<DirectoryFlavor> {
+        match value.to_ascii_lowercase().as_str() {
+            "openldap" => Some(DirectoryFlavor::OpenLdap),
+            "ad" | "activedirectory" | "active_directory" => Some(DirectoryFlavor::ActiveDirectory),
+            _ => None,
+        }
+    }
+
+    pub fn is_active_directory(&self) -> bool {
+        *self == DirectoryFlavor::ActiveDirectory
+    }
+
+    // Attribute naming user entries, e.g. uid=42,ou=... or CN=42,OU=...
+    pub fn user_rdn_attr(&self) -> &'static str {
+        match self {
+            DirectoryFlavor::OpenLdap => "uid",
+            DirectoryFlavor::ActiveDirectory => "CN",
+        }
+    }
+
+    pub fn user_object_classes(&self) -> Vec<&'static str> {
+        match self {
+            DirectoryFlavor::OpenLdap => vec!["inetOrgPerson"],
+            DirectoryFlavor::ActiveDirectory => vec!["top", "person", "organizationalPerson", "user"],
+        }
+    }
+
+    pub fn group_object_class(&self) -> &'static str {
+        match self {
+            DirectoryFlavor::OpenLdap => "groupOfNames",
+            DirectoryFlavor::ActiveDirectory => "group",
+        }
+    }
+
+    // Operational attributes holding the creation and last change times of an
+    // entry, both in GeneralizedTime syntax.
+    pub fn created_attr(&self) -> &'static str {
+        match self {
+            DirectoryFlavor::OpenLdap => "createTimestamp",
+            DirectoryFlavor::ActiveDirectory => "whenCreated",
+        }
+    }
+
+    pub fn modified_attr(&self) -> &'static str {
+        match self {
+            DirectoryFlavor::OpenLdap => "modifyTimestamp",
+            DirectoryFlavor::ActiveDirectory => "whenChanged",
+        }
+    }
+
+    // Matches every user account, enabled or not, combined with `inner`.
+    pub fn user_filter(&self, inner: &str) -> String {
+        match self {
+            DirectoryFlavor::OpenLdap => format!("(&(objectClass=inetOrgPerson){})", inner),
+            DirectoryFlavor::ActiveDirectory => {
+                format!("(&(objectClass=user)(objectCategory=person){})", inner)
+            }
+        }
+    }
+
+    // Like `user_filter`, but skips accounts AD marks as disabled.
+    pub fn active_user_filter(&self, inner: &str) -> String {
+        match self {
+            DirectoryFlavor::OpenLdap => self.user_filter(inner),
+            DirectoryFlavor::ActiveDirectory => self.user_filter(&format!(
+                "(!(userAccountControl:1.2.840.113556.1.4.803:={})){}",
+                AD_ACCOUNT_DISABLED, inner
+            )),
+        }
+    }
+
+    // Whether a WhoAmI authzid names the account bound as `bind_dn`. OpenLDAP
+    // answers dn:<bind DN>; AD answers u:DOMAIN\name, which cannot be derived
+    // from the bind DN, so there any user identity counts.
+    pub fn is_authzid_of(&self, authzid: &str, bind_dn: &str) -> bool {
+        match self {
+            DirectoryFlavor::OpenLdap => authzid.eq_ignore_ascii_case(&format!("dn:{}", bind_dn)),
+            DirectoryFlavor::ActiveDirectory => authzid.len() > 2 && authzid[..2].eq_ignore_ascii_case("u:"),
+        }
+    }
+
+    // Filter for the login name: the mail address on OpenLDAP, the
+    // userPrincipalName or sAMAccountName on AD.
+    pub fn login_filter(&self, login: &str) -> String {
+        let login = ldap_escape(login);
+        match self {
+            DirectoryFlavor::OpenLdap => self.active_user_filter(&format!("(mail={})", login)),
+            DirectoryFlavor::ActiveDirectory => self.active_user_filter(&format!(
+                "(|(userPrincipalName={0})(sAMAccountName={0}))",
+                login
+            )),
+        }
+    }
+}
+
+// AD only accepts passwords as the quoted UTF-16LE string in unicodePwd.
+pub fn ad_unicode_password(password: &str) -> Vec<u8> {
+    format!("\"{}\"", password)
+        .encode_utf16()
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect()
+}
+
+// Parses GeneralizedTime values such as 20231001043732Z or AD's 20231001043732.0Z,
+// which both servers return in UTC.
+pub fn parse_generalized_time(value: &str) -> Option<DateTime<Utc>> {
+    let seconds = value.get(..14)?;
+    NaiveDateTime::parse_from_str(seconds, "%Y%m%d%H%M%S")
+        .ok()
+        .map(|naive| DateTime::<Utc>::from_naive_utc_and_offset(naive, Utc))
+}
+
+// sAMAccountName is limited to 20 characters and a restricted character set.
+pub fn ad_sam_account_name(username: &str) -> String {
+    username
+        .chars()
+        .filter(|c| !"\"/\\[]:;|=,+*?<>@".contains(*c))
+        .take(20)
+        .collect()
+}
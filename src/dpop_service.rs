@@ -0,0 +1,266 @@
+use std::collections::HashSet;
+
+use actix_web::{http::header, HttpRequest};
+use base64::{engine::general_purpose, Engine as _};
+use jsonwebtoken::jwk::Jwk;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+
+// RFC 9449 proofs of possession. A client signs a short JWT per request with
+// its own key; tokens issued against a proof are bound to that key through
+// the `cnf.jkt` claim and only accepted together with a fresh proof.
+pub const DPOP_HEADER: &str = "DPoP";
+const PROOF_TYPE: &str = "dpop+jwt";
+const ALLOWED_ALGORITHMS: [Algorithm; 5] = [
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::RS256,
+    Algorithm::PS256,
+    Algorithm::EdDSA,
+];
+
+#[derive(Debug)]
+pub enum DpopError {
+    Invalid(String),
+    Replayed,
+    RedisError(redis::RedisError),
+}
+
+impl From<redis::RedisError> for DpopError {
+    fn from(err: redis::RedisError) -> Self {
+        DpopError::RedisError(err)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ProofClaims {
+    jti: String,
+    htm: String,
+    htu: String,
+    iat: i64,
+    // hash of the access token the proof is sent with
+    ath: Option<String>,
+}
+
+fn invalid(message: impl ToString) -> DpopError {
+    DpopError::Invalid(message.to_string())
+}
+
+fn jti_key(jti: &str) -> String {
+    format!("dpop_jti:{}", jti)
+}
+
+fn sha256_base64(value: &str) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(value.as_bytes()))
+}
+
+// The RFC 7638 thumbprint: the hash of the key's required members in
+// lexicographic order.
+pub fn thumbprint(jwk: &Jwk) -> Result<String, DpopError> {
+    let value = serde_json::to_value(jwk).map_err(invalid)?;
+    let members: &[&str] = match value.get("kty").and_then(|kty| kty.as_str()) {
+        Some("EC") => &["crv", "kty", "x", "y"],
+        Some("RSA") => &["e", "kty", "n"],
+        Some("OKP") => &["crv", "kty", "x"],
+        _ => return Err(invalid("the proof key must be an EC, RSA or OKP public key")),
+    };
+    let parts: Option<Vec<String>> = members
+        .iter()
+        .map(|member| {
+            value
+                .get(*member)
+                .and_then(|v| v.as_str())
+                .map(|v| format!("\"{}\":\"{}\"", member, v))
+        })
+        .collect();
+    let parts = parts.ok_or_else(|| invalid("the proof key is incomplete"))?;
+    Ok(sha256_base64(&format!("{{{}}}", parts.join(","))))
+}
+
+// The URL a proof must name in `htu`: the request without query or fragment,
+// as the client addressed it. Forwarded and X-Forwarded-Host/-Proto are anyone's
+// to write, so they only count on connections from TRUSTED_PROXIES.
+fn request_url(req: &HttpRequest, config: &Config) -> String {
+    let from_proxy = req
+        .peer_addr()
+        .map_or(false, |peer| config.trusted_proxies.contains(&peer.ip()));
+    if from_proxy {
+        let conn = req.connection_info();
+        return format!("{}://{}{}", conn.scheme(), conn.host(), req.path());
+    }
+    let scheme = if req.app_config().secure() { "https" } else { "http" };
+    let host = req
+        .uri()
+        .authority()
+        .map(|authority| authority.as_str())
+        .or_else(|| req.headers().get(header::HOST).and_then(|host| host.to_str().ok()))
+        .unwrap_or_else(|| req.app_config().host());
+    format!("{}://{}{}", scheme, host, req.path())
+}
+
+// Checks the DPoP header of `req` and returns the thumbprint of the key that
+// signed it, or None when the request carries no proof. `access_token` is the
+// token the proof is presented with, if any, and must match its `ath`.
+pub async fn verify_proof(
+    redis_client: &redis::Client,
+    config: &Config,
+    req: &HttpRequest,
+    access_token: Option<&str>,
+) -> Result<Option<String>, DpopError> {
+    let proofs: Vec<_> = req.headers().get_all(DPOP_HEADER).collect();
+    let proof = match proofs.as_slice() {
+        [] => return Ok(None),
+        [proof] => proof.to_str().map_err(invalid)?,
+        _ => return Err(invalid("only one DPoP proof is allowed")),
+    };
+
+    let header = jsonwebtoken::decode_header(proof).map_err(invalid)?;
+    if header.typ.as_deref() != Some(PROOF_TYPE) {
+        return Err(invalid("the proof must have typ dpop+jwt"));
+    }
+    if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+        return Err(invalid("the proof must be signed with an asymmetric algorithm"));
+    }
+    let jwk = header.jwk.ok_or_else(|| invalid("the proof carries no jwk"))?;
+    let jkt = thumbprint(&jwk)?;
+    let key = DecodingKey::from_jwk(&jwk).map_err(invalid)?;
+    let mut validation = Validation::new(header.alg);
+    validation.validate_exp = false;
+    validation.required_spec_claims = HashSet::new();
+    let claims = jsonwebtoken::decode::<ProofClaims>(proof, &key, &validation)
+        .map_err(invalid)?
+        .claims;
+
+    if !claims.htm.eq_ignore_ascii_case(req.method().as_str()) {
+        return Err(invalid("htm does not match the request method"));
+    }
+    let htu = claims.htu.split(['?', '#']).next().unwrap_or_default();
+    if htu != request_url(req, config) {
+        return Err(invalid("htu does not match the request URL"));
+    }
+    let max_age = config.dpop_proof_max_age_secs as i64;
+    if (chrono::Utc::now().timestamp() - claims.iat).abs() > max_age {
+        return Err(invalid("the proof is too old or from the future"));
+    }
+    if let Some(access_token) = access_token {
+        if claims.ath.as_deref() != Some(sha256_base64(access_token).as_str()) {
+            return Err(invalid("ath does not match the access token"));
+        }
+    }
+
+    // a jti is remembered for as long as its proof could pass the iat check
+    let mut conn = redis_client.get_async_connection().await?;
+    let claimed: Option<String> = redis::cmd("SET")
+        .arg(jti_key(&claims.jti))
+        .arg("1")
+        .arg("NX")
+        .arg("EX")
+        .arg(max_age * 2)
+        .query_async(&mut conn)
+        .await?;
+    if claimed.is_none() {
+        return Err(DpopError::Replayed);
+    }
+    Ok(Some(jkt))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{memory_store, redis_available, test_config, test_state};
+    use actix_web::test;
+    use jsonwebtoken::{EncodingKey, Header};
+
+    const PRIVATE_KEY: &str = include_str!("../tests/fixtures/token_key.pem");
+    const PUBLIC_JWK: &str = include_str!("../tests/fixtures/token_key.jwk.json");
+    const URL: &str = "http://auth.example.com/api/auth/login";
+
+    fn proof(htm: &str, htu: &str, iat: i64, jti: &str) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.typ = Some(PROOF_TYPE.to_string());
+        header.jwk = Some(serde_json::from_str(PUBLIC_JWK).unwrap());
+        let claims = serde_json::json!({"jti": jti, "htm": htm, "htu": htu, "iat": iat});
+        jsonwebtoken::encode(&header, &claims, &EncodingKey::from_rsa_pem(PRIVATE_KEY.as_bytes()).unwrap()).unwrap()
+    }
+
+    fn login_request(proof: String) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/api/auth/login")
+            .insert_header((header::HOST, "auth.example.com"))
+            .insert_header((DPOP_HEADER, proof))
+    }
+
+    // Runs the checks that come before the jti one. Nothing listens on the
+    // Redis port used here, so a proof that passes them all ends at that.
+    async fn check(config: &Config, req: HttpRequest) -> Result<(), String> {
+        let redis_client = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+        match verify_proof(&redis_client, config, &req, None).await {
+            Err(DpopError::RedisError(_)) => Ok(()),
+            Err(DpopError::Invalid(message)) => Err(message),
+            other => panic!("unexpected outcome {:?}", other),
+        }
+    }
+
+    fn jti() -> String {
+        uuid::Uuid::new_v4().to_string()
+    }
+
+    #[actix_web::test]
+    async fn rejects_proofs_for_another_request() {
+        let config = test_config();
+        let now = chrono::Utc::now().timestamp();
+        let req = login_request(proof("POST", &format!("{}?next=1", URL), now, &jti())).to_http_request();
+        assert_eq!(check(&config, req).await, Ok(()));
+
+        let req = login_request(proof("GET", URL, now, &jti())).to_http_request();
+        assert_eq!(check(&config, req).await, Err("htm does not match the request method".to_string()));
+        let req = login_request(proof("POST", "http://auth.example.com/api/auth/refresh", now, &jti())).to_http_request();
+        assert_eq!(check(&config, req).await, Err("htu does not match the request URL".to_string()));
+        let req = login_request(proof("POST", URL, now - 3600, &jti())).to_http_request();
+        assert_eq!(check(&config, req).await, Err("the proof is too old or from the future".to_string()));
+        let req = login_request(proof("POST", URL, now + 3600, &jti())).to_http_request();
+        assert_eq!(check(&config, req).await, Err("the proof is too old or from the future".to_string()));
+    }
+
+    #[actix_web::test]
+    async fn forwarded_hosts_count_only_from_trusted_proxies() {
+        let mut config = test_config();
+        let proxy: std::net::SocketAddr = "10.0.0.1:41000".parse().unwrap();
+        let forwarded = |proof: String| {
+            login_request(proof)
+                .insert_header(("X-Forwarded-Host", "evil.example"))
+                .insert_header(("X-Forwarded-Proto", "https"))
+                .peer_addr(proxy)
+        };
+        let forwarded_url = "https://evil.example/api/auth/login";
+        let htu_mismatch = Err("htu does not match the request URL".to_string());
+        let now = chrono::Utc::now().timestamp();
+        let req = forwarded(proof("POST", forwarded_url, now, &jti())).to_http_request();
+        assert_eq!(check(&config, req).await, htu_mismatch);
+        let req = forwarded(proof("POST", URL, now, &jti())).to_http_request();
+        assert_eq!(check(&config, req).await, Ok(()));
+
+        config.trusted_proxies = vec![proxy.ip()];
+        let req = forwarded(proof("POST", URL, now, &jti())).to_http_request();
+        assert_eq!(check(&config, req).await, htu_mismatch);
+        let req = forwarded(proof("POST", forwarded_url, now, &jti())).to_http_request();
+        assert_eq!(check(&config, req).await, Ok(()));
+    }
+
+    #[actix_web::test]
+    async fn proofs_work_once() {
+        let data = test_state(memory_store().await);
+        if !redis_available(&data).await {
+            return;
+        }
+        let proof = proof("POST", URL, chrono::Utc::now().timestamp(), &jti());
+        let req = login_request(proof.clone()).to_http_request();
+        let jkt = verify_proof(&data.redis_client, &data.env, &req, None).await.unwrap();
+        assert_eq!(jkt, Some(thumbprint(&serde_json::from_str(PUBLIC_JWK).unwrap()).unwrap()));
+        let req = login_request(proof).to_http_request();
+        assert!(matches!(verify_proof(&data.redis_client, &data.env, &req, None).await, Err(DpopError::Replayed)));
+    }
+}
@@ -0,0 +1,263 @@
+use crate::{
+    audit_service::{AuditEvent, AuditEventType},
+    group_model::{CreateGroupSchema, GroupMemberSchema},
+    group_service::is_valid_group_name,
+    identity_store::{Identity, IdentityError},
+    authz::{Admin, RequireRole},
+    user_cache, AppState,
+};
+use actix_web::{
+    delete, get, post, web, HttpRequest, HttpResponse, Responder,
+};
+
+fn invalid_group_name() -> HttpResponse {
+    HttpResponse::BadRequest().json(
+        serde_json::json!({"status": "fail","message": "Group names may only contain letters, digits, '-' and '_'"}),
+    )
+}
+
+fn identity_error(err: IdentityError) -> HttpResponse {
+    HttpResponse::InternalServerError()
+        .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}))
+}
+
+fn group_not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(
+        serde_json::json!({"status": "fail","message": "Group does not exist"}),
+    )
+}
+
+// The account with its groups, or the response to send when it does not exist.
+async fn find_user(data: &AppState, uid: u64) -> Result<Identity, HttpResponse> {
+    match data.identity.find_by_id(uid).await {
+        Ok(Some(identity)) => Ok(identity),
+        Ok(None) => Err(HttpResponse::NotFound().json(
+            serde_json::json!({"status": "fail","message": format!("User {} does not exist", uid)}),
+        )),
+        Err(err) => Err(identity_error(err)),
+    }
+}
+
+#[post("/")]
+async fn create_group_handler(
+    req: HttpRequest,
+    admin: RequireRole<Admin>,
+    body: web::Json<CreateGroupSchema>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let name = body.name.as_str();
+    if !is_valid_group_name(name) {
+        return invalid_group_name();
+    }
+    // groupOfNames requires at least one member, keep the other backends consistent with that
+    if body.members.is_empty() {
+        return HttpResponse::BadRequest().json(
+            serde_json::json!({"status": "fail","message": "A group needs at least one member"}),
+        );
+    }
+    for uid in body.members.iter() {
+        if let Err(response) = find_user(&data, *uid).await {
+            return response;
+        }
+    }
+
+    match data.identity.create_group(name, body.description.as_deref(), &body.members).await {
+        Ok(_) => {
+            user_cache::invalidate_users(&data.redis_client, &body.members).await;
+            data.audit.record(AuditEvent::new(AuditEventType::GroupCreated, true, &req).actor(admin.auth.user_id).detail(name));
+            for uid in body.members.iter() {
+                data.audit.record(
+                    AuditEvent::new(AuditEventType::GroupMemberAdded, true, &req)
+                        .actor(admin.auth.user_id)
+                        .subject(*uid)
+                        .detail(name),
+                );
+            }
+            HttpResponse::Ok().json(serde_json::json!({"status": "success","data": serde_json::json!({
+                "group": {
+                    "name": name,
+                    "members": body.members
+                }
+            })}))
+        }
+        Err(IdentityError::Conflict(message)) => HttpResponse::Conflict().json(
+            serde_json::json!({"status": "fail","message": message}),
+        ),
+        Err(IdentityError::NotFound) => HttpResponse::NotFound().json(
+            serde_json::json!({"status": "fail","message": "A member does not exist"}),
+        ),
+        Err(err) => identity_error(err),
+    }
+}
+
+#[delete("/{name}")]
+async fn delete_group_handler(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+    admin: RequireRole<Admin>,
+) -> impl Responder {
+    let name = path.into_inner();
+    if !is_valid_group_name(&name) {
+        return invalid_group_name();
+    }
+    match data.identity.delete_group(&name).await {
+        Ok(members) => {
+            // members' cached groups go stale once the group is gone
+            user_cache::invalidate_users(&data.redis_client, &members).await;
+            data.audit.record(
+                AuditEvent::new(AuditEventType::GroupDeleted, true, &req)
+                    .actor(admin.auth.user_id)
+                    .detail(format!("{} with {} members", name, members.len())),
+            );
+            HttpResponse::Ok().json(
+                serde_json::json!({"status": "success","message": "Group deleted successfully"}),
+            )
+        }
+        Err(IdentityError::NotFound) => group_not_found(),
+        Err(err) => identity_error(err),
+    }
+}
+
+#[get("/user/{uid}")]
+async fn user_groups_handler(
+    path: web::Path<u64>,
+    data: web::Data<AppState>,
+    _admin: RequireRole<Admin>,
+) -> impl Responder {
+    let uid = path.into_inner();
+    match find_user(&data, uid).await {
+        Ok(identity) => HttpResponse::Ok().json(serde_json::json!({"status": "success","data": serde_json::json!({
+            "user_id": uid,
+            "groups": identity.groups
+        })})),
+        Err(response) => response,
+    }
+}
+
+#[get("/{name}/members")]
+async fn list_members_handler(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+    _admin: RequireRole<Admin>,
+) -> impl Responder {
+    let name = path.into_inner();
+    if !is_valid_group_name(&name) {
+        return invalid_group_name();
+    }
+    match data.identity.group_members(&name).await {
+        Ok(Some(members)) => HttpResponse::Ok().json(serde_json::json!({"status": "success","data": serde_json::json!({
+            "group": {
+                "name": name,
+                "members": members
+            }
+        })})),
+        Ok(None) => group_not_found(),
+        Err(err) => identity_error(err),
+    }
+}
+
+#[post("/{name}/members")]
+async fn add_member_handler(
+    req: HttpRequest,
+    path: web::Path<String>,
+    admin: RequireRole<Admin>,
+    body: web::Json<GroupMemberSchema>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let name = path.into_inner();
+    if !is_valid_group_name(&name) {
+        return invalid_group_name();
+    }
+    let identity = match find_user(&data, body.uid).await {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+    // only existing groups take members, the other backends would create one
+    match data.identity.group_members(&name).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return group_not_found(),
+        Err(err) => return identity_error(err),
+    }
+    if identity.groups.contains(&name) {
+        return HttpResponse::Conflict().json(
+            serde_json::json!({"status": "fail","message": "User is already a member of this group"}),
+        );
+    }
+
+    match data.identity.set_group_member(body.uid, &name, true).await {
+        Ok(_) => {
+            user_cache::invalidate_users(&data.redis_client, &[body.uid]).await;
+            data.audit.record(
+                AuditEvent::new(AuditEventType::GroupMemberAdded, true, &req)
+                    .actor(admin.auth.user_id)
+                    .subject(body.uid)
+                    .detail(name.as_str()),
+            );
+            HttpResponse::Ok().json(
+                serde_json::json!({"status": "success","message": "Member added successfully"}),
+            )
+        }
+        Err(IdentityError::NotFound) => group_not_found(),
+        Err(err) => identity_error(err),
+    }
+}
+
+#[delete("/{name}/members/{uid}")]
+async fn remove_member_handler(
+    req: HttpRequest,
+    path: web::Path<(String, u64)>,
+    data: web::Data<AppState>,
+    admin: RequireRole<Admin>,
+) -> impl Responder {
+    let (name, uid) = path.into_inner();
+    if !is_valid_group_name(&name) {
+        return invalid_group_name();
+    }
+    let members = match data.identity.group_members(&name).await {
+        Ok(Some(members)) => members,
+        Ok(None) => return group_not_found(),
+        Err(err) => return identity_error(err),
+    };
+    if !members.contains(&uid) {
+        return HttpResponse::NotFound().json(
+            serde_json::json!({"status": "fail","message": "User is not a member of this group"}),
+        );
+    }
+    if members.len() == 1 {
+        return HttpResponse::BadRequest().json(
+            serde_json::json!({"status": "fail","message": "A group needs at least one member"}),
+        );
+    }
+
+    match data.identity.set_group_member(uid, &name, false).await {
+        Ok(_) => {
+            user_cache::invalidate_users(&data.redis_client, &[uid]).await;
+            data.audit.record(
+                AuditEvent::new(AuditEventType::GroupMemberRemoved, true, &req)
+                    .actor(admin.auth.user_id)
+                    .subject(uid)
+                    .detail(name.as_str()),
+            );
+            HttpResponse::Ok().json(
+                serde_json::json!({"status": "success","message": "Member removed successfully"}),
+            )
+        }
+        Err(IdentityError::NotFound) => group_not_found(),
+        Err(IdentityError::Invalid(message)) => HttpResponse::BadRequest().json(
+            serde_json::json!({"status": "fail","message": message}),
+        ),
+        Err(err) => identity_error(err),
+    }
+}
+
+pub fn config(conf: &mut web::ServiceConfig) {
+    let scope = web::scope("/api/groups")
+        .service(create_group_handler)
+        .service(user_groups_handler)
+        .service(list_members_handler)
+        .service(add_member_handler)
+        .service(remove_member_handler)
+        .service(delete_group_handler);
+    conf.service(scope);
+}
@@ -0,0 +1,13 @@
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateGroupSchema {
+    pub name: String,
+    pub description: Option<String>,
+    pub members: Vec<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GroupMemberSchema {
+    pub uid: u64,
+}
@@ -0,0 +1,123 @@
+use ldap3::{dn_escape, ldap_escape, Scope, SearchEntry};
+
+use crate::config::Config;
+use crate::ldap_service::{AdminConn, MyError};
+
+pub const NO_SUCH_OBJECT: u32 = 32;
+
+pub fn user_dn(config: &Config, uid: u64) -> String {
+    format!("{}={},{}", config.ldap_flavor.user_rdn_attr(), uid, config.ldap_base_dn)
+}
+
+pub fn group_dn(config: &Config, name: &str) -> String {
+    format!("cn={},{}", dn_escape(name), config.ldap_group_base_dn)
+}
+
+// Group names end up in DNs, so keep them to a conservative character set.
+pub fn is_valid_group_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub fn is_admin(config: &Config, groups: &[String]) -> bool {
+    groups.iter().any(|group| group == &config.ldap_admin_group)
+}
+
+// Extracts the uid from a member DN under the user base DN.
+pub fn uid_from_member_dn(config: &Config, dn: &str) -> Option<u64> {
+    let suffix = format!(",{}", config.ldap_base_dn);
+    if dn.len() <= suffix.len() || !dn.is_char_boundary(dn.len() - suffix.len()) {
+        return None;
+    }
+    let (rdn, dn_suffix) = dn.split_at(dn.len() - suffix.len());
+    if !dn_suffix.eq_ignore_ascii_case(&suffix) {
+        return None;
+    }
+    let (attr, value) = rdn.split_once('=')?;
+    if !attr.eq_ignore_ascii_case(config.ldap_flavor.user_rdn_attr()) {
+        return None;
+    }
+    value.parse::<u64>().ok()
+}
+
+pub async fn fetch_user_groups(ldap: &mut AdminConn, config: &Config, uid: u64) -> Result<Vec<String>, MyError> {
+    let filter = format!(
+        "(&(objectClass={})(member={}))",
+        config.ldap_flavor.group_object_class(),
+        ldap_escape(user_dn(config, uid))
+    );
+    let (rs, _res) = ldap
+        .search(&config.ldap_group_base_dn, Scope::Subtree, &filter, vec!["cn"])
+        .await?
+        .success()?;
+    let groups = rs
+        .into_iter()
+        .filter_map(|entry| {
+            let group = SearchEntry::construct(entry);
+            group.attrs.get("cn").and_then(|cn| cn.get(0).cloned())
+        })
+        .collect();
+    Ok(groups)
+}
+
+// Where the next slice of a ranged attribute starts, given the name it came
+// back under: AD returns large multi-valued attributes as member;range=0-1499
+// and marks the last slice with an end of *. None when there is no more.
+fn next_range_start(attr: &str) -> Option<usize> {
+    let (_, range) = attr.split_once(";range=")?;
+    let (_, end) = range.split_once('-')?;
+    end.parse::<usize>().ok().map(|end| end + 1)
+}
+
+// Returns None when the group does not exist. AD hands out at most 1500
+// values of `member` per search, so there the members are read in ranges.
+pub async fn fetch_group_member_uids(ldap: &mut AdminConn, config: &Config, name: &str) -> Result<Option<Vec<u64>>, MyError> {
+    let filter = format!("(objectClass={})", config.ldap_flavor.group_object_class());
+    let dn = group_dn(config, name);
+    let mut members = Vec::new();
+    let mut start = 0;
+    loop {
+        let attr = if config.ldap_flavor.is_active_directory() {
+            format!("member;range={}-*", start)
+        } else {
+            "member".to_string()
+        };
+        let search_result = ldap.search(&dn, Scope::Base, &filter, vec![attr]).await?;
+        if search_result.1.rc == NO_SUCH_OBJECT {
+            return Ok(None);
+        }
+        let (rs, _res) = search_result.success()?;
+        let group = match rs.into_iter().next() {
+            Some(entry) => SearchEntry::construct(entry),
+            None => return Ok(None),
+        };
+        let slice = group.attrs.iter().find(|(attr, _)| {
+            let attr = attr.to_ascii_lowercase();
+            attr == "member" || attr.starts_with("member;range=")
+        });
+        let (attr, values) = match slice {
+            Some(slice) => slice,
+            None => break,
+        };
+        members.extend(values.iter().filter_map(|dn| uid_from_member_dn(config, dn)));
+        match next_range_start(attr) {
+            Some(next) if next > start => start = next,
+            _ => break,
+        }
+    }
+    Ok(Some(members))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranged_attributes_continue_after_their_end() {
+        assert_eq!(next_range_start("member;range=0-1499"), Some(1500));
+        assert_eq!(next_range_start("member;range=1500-2999"), Some(3000));
+        assert_eq!(next_range_start("member;range=3000-*"), None);
+        assert_eq!(next_range_start("member"), None);
+    }
+}
@@ -0,0 +1,78 @@
+use crate::{token_service, AppState};
+use actix_web::{get, web, HttpResponse, Responder};
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Runs a single dependency check with a timeout and reports it as JSON.
+async fn run_check<F>(check: F) -> (bool, serde_json::Value)
+where
+    F: Future<Output = Result<(), String>>,
+{
+    let started = Instant::now();
+    let result = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("timed out after {}ms", CHECK_TIMEOUT.as_millis())),
+    };
+    let latency_ms = started.elapsed().as_millis() as u64;
+    match result {
+        Ok(_) => (true, serde_json::json!({"status": "up", "latency_ms": latency_ms})),
+        Err(err) => (false, serde_json::json!({"status": "down", "latency_ms": latency_ms, "message": err})),
+    }
+}
+
+#[get("/healthz")]
+async fn healthz_handler() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({"status": "success"}))
+}
+
+#[get("/readyz")]
+async fn readyz_handler(data: web::Data<AppState>) -> impl Responder {
+    let identity_check = run_check(async {
+        data.identity.ping().await.map_err(|err| format!("{:?}", err))
+    });
+    let redis_check = run_check(async {
+        let mut redis_client = data.redis_client.get_async_connection().await.map_err(|e| e.to_string())?;
+        let pong: String = redis::cmd("PING")
+            .query_async(&mut redis_client)
+            .await
+            .map_err(|e| e.to_string())?;
+        if pong != "PONG" {
+            return Err(format!("unexpected PING reply: {}", pong));
+        }
+        Ok(())
+    });
+    let keys_check = run_check(async {
+        token_service::check_key_pair(
+            data.env.access_token_private_key.to_owned(),
+            data.env.access_token_public_key.to_owned(),
+        )
+        .map_err(|e| format!("access token keys: {}", e))?;
+        token_service::check_key_pair(
+            data.env.refresh_token_private_key.to_owned(),
+            data.env.refresh_token_public_key.to_owned(),
+        )
+        .map_err(|e| format!("refresh token keys: {}", e))
+    });
+
+    let ((identity_up, identity), (redis_up, redis), (keys_up, keys)) =
+        futures::join!(identity_check, redis_check, keys_check);
+    let mut checks = serde_json::json!({"redis": redis, "keys": keys});
+    checks[data.env.identity_backend.name()] = identity;
+
+    let json_response = serde_json::json!({
+        "status": if identity_up && redis_up && keys_up { "success" } else { "fail" },
+        "checks": checks
+    });
+
+    if identity_up && redis_up && keys_up {
+        HttpResponse::Ok().json(json_response)
+    } else {
+        HttpResponse::ServiceUnavailable().json(json_response)
+    }
+}
+
+pub fn config(conf: &mut web::ServiceConfig) {
+    conf.service(healthz_handler).service(readyz_handler);
+}
@@ -0,0 +1,166 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::paging_service::UserFilter;
+use crate::user_model::UpdateUserSchema;
+
+// Which identity store backs the account endpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentityBackend {
+    Ldap,
+    Postgres,
+    Memory,
+}
+
+impl IdentityBackend {
+    pub fn parse(value: &str) -> Option<IdentityBackend> {
+        match value.to_ascii_lowercase().as_str() {
+            "ldap" => Some(IdentityBackend::Ldap),
+            "postgres" => Some(IdentityBackend::Postgres),
+            "memory" => Some(IdentityBackend::Memory),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            IdentityBackend::Ldap => "ldap",
+            IdentityBackend::Postgres => "postgres",
+            IdentityBackend::Memory => "memory",
+        }
+    }
+}
+
+// The descriptive attributes a PATCH can change besides the address, named
+// after their LDAP attributes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub cn: Option<String>,
+    pub sn: Option<String>,
+    pub display_name: Option<String>,
+    pub given_name: Option<String>,
+    pub telephone_number: Option<String>,
+}
+
+impl Profile {
+    // Replaces the attributes `changes` sets, leaving the others.
+    pub fn apply(&mut self, changes: &UpdateUserSchema) {
+        let fields = [
+            (&mut self.cn, &changes.cn),
+            (&mut self.sn, &changes.sn),
+            (&mut self.display_name, &changes.display_name),
+            (&mut self.given_name, &changes.given_name),
+            (&mut self.telephone_number, &changes.telephone_number),
+        ];
+        for (field, value) in fields {
+            if value.is_some() {
+                *field = value.clone();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub user_id: u64,
+    pub email: String,
+    pub groups: Vec<String>,
+    // disabled by an admin; the store keeps the account but refuses its sign-ins
+    pub disabled: bool,
+    // when and why, as `disable` stored them; registrations waiting for
+    // approval are disabled without a reason
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub disabled_reason: Option<String>,
+    // whether the current email address has been confirmed, see EMAIL_VERIFICATION
+    pub verified: bool,
+    pub profile: Profile,
+}
+
+#[derive(Debug)]
+pub struct NewIdentity<'a> {
+    pub user_id: u64,
+    pub email: &'a str,
+    pub password: &'a str,
+    // false for accounts that still have to confirm their address
+    pub verified: bool,
+    // created unable to sign in, for registrations waiting for approval
+    pub disabled: bool,
+}
+
+// One page of a listing, see `IdentityStore::list_page`.
+#[derive(Debug)]
+pub struct IdentityPage {
+    pub identities: Vec<Identity>,
+    // how many accounts match the filter in all
+    pub total: usize,
+}
+
+#[derive(Debug)]
+pub enum IdentityError {
+    NotFound,
+    InvalidCredentials,
+    Conflict(String),
+    Invalid(String),
+    Backend(String),
+}
+
+#[async_trait]
+pub trait IdentityStore: Send + Sync {
+    // Confirms the backend is reachable, used by the readiness probe.
+    async fn ping(&self) -> Result<(), IdentityError>;
+
+    // Finds an account by its numeric user id, disabled or not.
+    async fn find_by_id(&self, user_id: u64) -> Result<Option<Identity>, IdentityError>;
+
+    // Finds any account, enabled or not, registered with `email`.
+    async fn find_by_email(&self, email: &str) -> Result<Option<Identity>, IdentityError>;
+
+    // Every account, ordered by user id, for exports. Groups are left empty.
+    async fn list_all(&self) -> Result<Vec<Identity>, IdentityError>;
+
+    // The disabled accounts, ordered by user id. Groups are left empty.
+    async fn list_disabled(&self) -> Result<Vec<Identity>, IdentityError>;
+
+    // Up to `limit` accounts matching `filter` with a user id above `after`,
+    // ordered by user id. Groups are left empty. NotFound when the filter
+    // names a group that does not exist.
+    async fn list_page(&self, filter: &UserFilter, after: u64, limit: usize) -> Result<IdentityPage, IdentityError>;
+
+    // Checks a login name and password, NotFound or InvalidCredentials on failure.
+    async fn authenticate(&self, login: &str, password: &str) -> Result<Identity, IdentityError>;
+
+    async fn create(&self, new_identity: NewIdentity<'_>) -> Result<Identity, IdentityError>;
+
+    async fn delete(&self, user_id: u64) -> Result<(), IdentityError>;
+
+    // Marks an account disabled or enabled in the backend. This is the record
+    // of whether an account is disabled, `Identity::disabled` reads it back.
+    // Enabling also forgets the reason and time `disable` stored.
+    async fn set_disabled(&self, user_id: u64, disabled: bool) -> Result<(), IdentityError>;
+
+    // Disables an account for `reason` and returns it as stored, with the
+    // time the backend recorded.
+    async fn disable(&self, user_id: u64, reason: &str) -> Result<Identity, IdentityError>;
+
+    // Marks the email address of an account confirmed or not.
+    async fn set_verified(&self, user_id: u64, verified: bool) -> Result<(), IdentityError>;
+
+    // Applies a PATCH. A new email address leaves the account unverified.
+    async fn update(&self, user_id: u64, changes: &UpdateUserSchema) -> Result<Identity, IdentityError>;
+
+    // The user ids of the members of `group` in ascending order, None when
+    // there is no such group.
+    async fn group_members(&self, group: &str) -> Result<Option<Vec<u64>>, IdentityError>;
+
+    // Creates `group` with `members`, Conflict when it exists. Only the ldap
+    // backend keeps the description; elsewhere a group is its memberships.
+    async fn create_group(&self, group: &str, description: Option<&str>, members: &[u64]) -> Result<(), IdentityError>;
+
+    // Deletes `group` and returns its former members, NotFound when missing.
+    async fn delete_group(&self, group: &str) -> Result<Vec<u64>, IdentityError>;
+
+    // Adds the account to or removes it from `group`. Adding a member twice or
+    // removing one that is not a member changes nothing. The ldap backend
+    // answers NotFound for a group that does not exist.
+    async fn set_group_member(&self, user_id: u64, group: &str, member: bool) -> Result<(), IdentityError>;
+}
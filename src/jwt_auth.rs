@@ -1,20 +1,20 @@
 use core::fmt;
-use std::future::{ready, Ready};
+use std::future::ready;
+use futures::future::LocalBoxFuture;
 
-use actix_web::error::{ErrorInternalServerError, ErrorUnauthorized};
+use actix_web::error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized, InternalError};
 use actix_web::{dev::Payload, Error as ActixWebError};
-use actix_web::{http, web, FromRequest, HttpRequest, HttpMessage};
-use futures::executor::block_on;
+use actix_web::{http, web, FromRequest, HttpRequest, HttpMessage, HttpResponse};
 use serde::{Serialize};
-use ldap3::{LdapConn, Scope, SearchEntry};
 
-use crate::user_model::User;
 use crate::token_service;
 use crate::AppState;
-use crate::ldap_service::get_admin_ldap;
-use uuid::Uuid;
-
-use crate::user_service::{fetch_user_by_id_query};
+use crate::pat_service::{self, TOKEN_MARKER};
+use crate::scope_service::{allowed_scopes, SCOPE_ADMIN};
+use crate::dpop_service::{self, DpopError, DPOP_HEADER};
+use crate::cookie_service;
+use crate::account_service;
+use crate::user_cache;
 
 #[derive(Debug, Serialize)]
 struct ErrorResponse {
@@ -28,54 +28,189 @@ impl fmt::Display for ErrorResponse {
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct JwtMiddleware {
     pub user_id: u64,
+    // empty unless the token carries the admin scope
+    pub groups: Vec<String>,
+    pub scopes: Vec<String>,
+    // set when the caller presented a personal access token, not a login session
+    pub personal_token: bool,
+}
+
+impl JwtMiddleware {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|granted| granted == scope)
+    }
+}
+
+// Group-based privileges need the admin scope, whatever the groups say.
+fn scoped_groups(groups: Vec<String>, scopes: &[String]) -> Vec<String> {
+    if scopes.iter().any(|scope| scope == SCOPE_ADMIN) {
+        groups
+    } else {
+        Vec::new()
+    }
+}
+
+// Account security settings and token management need a login session, so a
+// leaked personal access token cannot mint others or lock the owner out.
+pub fn require_session(auth: &JwtMiddleware) -> Option<HttpResponse> {
+    if !auth.personal_token {
+        return None;
+    }
+    Some(HttpResponse::Forbidden().json(
+        serde_json::json!({"status": "fail","message": "This requires a login session, not a personal access token"}),
+    ))
+}
+
+fn unauthorized(message: &str) -> ActixWebError {
+    ErrorUnauthorized(ErrorResponse {
+        status: "fail".to_string(),
+        message: message.to_string(),
+    })
+}
+
+// Answers 401 with the DPoP challenge of RFC 9449.
+fn invalid_dpop_proof(message: &str) -> ActixWebError {
+    InternalError::from_response(
+        message.to_string(),
+        HttpResponse::Unauthorized()
+            .insert_header((http::header::WWW_AUTHENTICATE, "DPoP error=\"invalid_dpop_proof\""))
+            .json(serde_json::json!({"status": "fail","error": "invalid_dpop_proof","message": message})),
+    )
+    .into()
+}
+
+// A token bound to a DPoP key needs the DPoP scheme and a fresh proof from
+// that key for this request; an unbound token must not pose as one.
+async fn check_binding(
+    data: &AppState,
+    req: &HttpRequest,
+    dpop_scheme: bool,
+    access_token: &str,
+    jkt: Option<String>,
+) -> Result<(), ActixWebError> {
+    let jkt = match jkt {
+        Some(jkt) => jkt,
+        None if dpop_scheme => return Err(invalid_dpop_proof("The token is not bound to a DPoP key")),
+        None => return Ok(()),
+    };
+    if !dpop_scheme {
+        return Err(invalid_dpop_proof("The token must be sent with the DPoP scheme"));
+    }
+    match dpop_service::verify_proof(&data.redis_client, &data.env, req, Some(access_token)).await {
+        Ok(Some(proof_jkt)) if proof_jkt == jkt => Ok(()),
+        Ok(Some(_)) => Err(invalid_dpop_proof("The DPoP proof is signed by another key")),
+        Ok(None) => Err(invalid_dpop_proof("A DPoP proof is required")),
+        Err(DpopError::Invalid(message)) => Err(invalid_dpop_proof(&message)),
+        Err(DpopError::Replayed) => Err(invalid_dpop_proof("The DPoP proof was already used")),
+        Err(err) => Err(ErrorInternalServerError(format!("{:?}", err))),
+    }
+}
+
+// Personal access tokens carry no groups, so they are read from the directory.
+async fn authenticate_pat(data: web::Data<AppState>, access_token: String) -> Result<JwtMiddleware, ActixWebError> {
+    let token = match pat_service::authenticate(&data.redis_client, &access_token).await {
+        Ok(Some(token)) => token,
+        Ok(None) => return Err(unauthorized("Invalid Token")),
+        Err(err) => return Err(ErrorInternalServerError(format!("{:?}", err))),
+    };
+    let user = match user_cache::get_user(&data, token.user_id).await {
+        Ok(Some(user)) if user.disabled => return Err(unauthorized("The account is disabled")),
+        Ok(Some(user)) => user,
+        Ok(None) => return Err(unauthorized("Invalid Token")),
+        Err(err) => return Err(ErrorInternalServerError(format!("{:?}", err))),
+    };
+    // a token keeps only the scopes its owner may still be granted
+    let allowed = allowed_scopes(&data.env, &user.groups);
+    let scopes: Vec<String> = token.scopes.into_iter().filter(|scope| allowed.contains(scope)).collect();
+    Ok(JwtMiddleware {
+        user_id: token.user_id,
+        groups: scoped_groups(user.groups, &scopes),
+        scopes,
+        personal_token: true,
+    })
 }
 
 impl FromRequest for JwtMiddleware {
     type Error = ActixWebError;
-    type Future = Ready<Result<Self, Self::Error>>;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
-        let data = req.app_data::<web::Data<AppState>>().unwrap();
+        // several extractors of one handler may ask, the token is checked once
+        if let Some(auth) = req.extensions().get::<JwtMiddleware>() {
+            return Box::pin(ready(Ok(auth.clone())));
+        }
+        let data = req.app_data::<web::Data<AppState>>().unwrap().clone();
 
-        let access_token = req.headers()
+        let authorization = req.headers()
                     .get(http::header::AUTHORIZATION)
-                    .map(|h| h.to_str().unwrap().split_at(7).1.to_string());
-            
+                    .and_then(|h| h.to_str().ok())
+                    .and_then(|h| h.split_once(' '))
+                    .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer") || scheme.eq_ignore_ascii_case("DPoP"))
+                    .map(|(scheme, token)| (scheme.eq_ignore_ascii_case("DPoP"), token.to_string()));
 
+        // browsers send the access token cookie instead, which any page could
+        // make them send, so changes also need the CSRF header to match
+        let (dpop_scheme, access_token) = match authorization {
+            Some(authorization) => authorization,
+            None => match cookie_service::access_token(&data.env, req) {
+                Some(_) if !cookie_service::csrf_ok(req) => {
+                    return Box::pin(ready(Err(ErrorForbidden(ErrorResponse {
+                        status: "fail".to_string(),
+                        message: "The CSRF token is missing or does not match".to_string(),
+                    }))))
+                }
+                Some(access_token) => (req.headers().contains_key(DPOP_HEADER), access_token),
+                None => return Box::pin(ready(Err(unauthorized("No token found")))),
+            },
+        };
 
-        if access_token.is_none() {
-            let json_error = ErrorResponse {
-                status: "fail".to_string(),
-                message: "No token found".to_string(),
-            };
-            return ready(Err(ErrorUnauthorized(json_error)));
+        if access_token.starts_with(TOKEN_MARKER) {
+            let req = req.clone();
+            return Box::pin(async move {
+                let auth = authenticate_pat(data, access_token).await?;
+                req.extensions_mut().insert(auth.user_id);
+                req.extensions_mut().insert(auth.clone());
+                Ok(auth)
+            });
         }
 
         let token_details = match token_service::verify_jwt_token(
             data.env.access_token_public_key.to_owned(),
-            &access_token.unwrap(),
+            &access_token,
         ) {
             Ok(token_details) => token_details,
-            Err(e) => {
-                let json_error = ErrorResponse {
-                    status: "Fail".to_string(),
-                    message: "Invalid Token".to_string(),
-                };
-                return ready(Err(ErrorUnauthorized(json_error)));
-            }
+            Err(_) => return Box::pin(ready(Err(unauthorized("Invalid Token")))),
         };
 
-        let user_id= token_details.user_id;
-
-        // check if user exists in ldap 
-       
+        // tokens from before scopes existed get what their groups allow
+        let scopes = token_details
+            .scopes
+            .unwrap_or_else(|| allowed_scopes(&data.env, &token_details.groups));
+        let auth = JwtMiddleware {
+            user_id: token_details.user_id,
+            groups: scoped_groups(token_details.groups, &scopes),
+            scopes,
+            personal_token: false,
+        };
 
-        req.extensions_mut()
-            .insert(token_details.user_id);
-        
-        ready(Ok(JwtMiddleware {
-            user_id: token_details.user_id
-        }))
+        let jkt = token_details.jkt;
+        let issued_at = token_details.issued_at.unwrap_or_default();
+        let req = req.clone();
+        Box::pin(async move {
+            check_binding(&data, &req, dpop_scheme, &access_token, jkt).await?;
+            // disabling an account revokes the tokens it holds
+            match account_service::token_revoked(&data.redis_client, auth.user_id, issued_at).await {
+                Ok(false) => {}
+                Ok(true) => return Err(unauthorized("The token has been revoked")),
+                Err(err) => return Err(ErrorInternalServerError(format!("{:?}", err))),
+            }
+            req.extensions_mut()
+                .insert(auth.user_id);
+            req.extensions_mut()
+                .insert(auth.clone());
+            Ok(auth)
+        })
     }
 }
@@ -0,0 +1,612 @@
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use ldap3::adapters::{Adapter, EntriesOnly, PagedResults};
+use ldap3::{ldap_escape, Mod, Scope, SearchEntry};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::directory::{ad_sam_account_name, ad_unicode_password, DirectoryFlavor, AD_ACCOUNT_DISABLED, AD_NORMAL_ACCOUNT};
+use crate::group_service::{fetch_group_member_uids, fetch_user_groups, group_dn, user_dn, NO_SUCH_OBJECT};
+use crate::identity_store::{Identity, IdentityError, IdentityPage, IdentityStore, NewIdentity, Profile};
+use crate::ldap_service::{check_admin_bind, check_credentials, get_admin_ldap, get_read_ldap, LdapCluster, MyError};
+use crate::paging_service::UserFilter;
+use crate::user_model::UpdateUserSchema;
+
+const LIST_PAGE_SIZE: i32 = 500;
+// result codes of group and attribute changes that need translating
+const NO_SUCH_ATTRIBUTE: u32 = 16;
+const ATTRIBUTE_OR_VALUE_EXISTS: u32 = 20;
+const OBJECT_CLASS_VIOLATION: u32 = 65;
+const ENTRY_ALREADY_EXISTS: u32 = 68;
+
+impl From<MyError> for IdentityError {
+    fn from(err: MyError) -> Self {
+        IdentityError::Backend(format!("{:?}", err))
+    }
+}
+
+impl From<ldap3::LdapError> for IdentityError {
+    fn from(err: ldap3::LdapError) -> Self {
+        IdentityError::from(MyError::from(err))
+    }
+}
+
+// Accounts are entries under LDAP_BASE_DN, following the configured directory flavor.
+pub struct LdapIdentityStore {
+    pool: Arc<LdapCluster>,
+    config: Config,
+}
+
+impl LdapIdentityStore {
+    pub fn new(pool: Arc<LdapCluster>, config: Config) -> Self {
+        LdapIdentityStore { pool, config }
+    }
+
+    // The attribute that says whether an entry is disabled, see `is_disabled`.
+    fn status_attr(&self) -> &str {
+        match self.config.ldap_flavor {
+            DirectoryFlavor::ActiveDirectory => "userAccountControl",
+            DirectoryFlavor::OpenLdap => self.config.ldap_disable_attr.as_str(),
+        }
+    }
+
+    // AD keeps the flag in userAccountControl, elsewhere set_disabled puts
+    // LDAP_DISABLE_VALUE into LDAP_DISABLE_ATTR.
+    fn is_disabled(&self, entry: &SearchEntry) -> bool {
+        let values = match entry.attrs.get(self.status_attr()) {
+            Some(values) => values,
+            None => return false,
+        };
+        match self.config.ldap_flavor {
+            DirectoryFlavor::ActiveDirectory => values
+                .get(0)
+                .and_then(|uac| uac.parse::<u32>().ok())
+                .map_or(false, |uac| uac & AD_ACCOUNT_DISABLED != 0),
+            DirectoryFlavor::OpenLdap => values
+                .iter()
+                .any(|value| value.eq_ignore_ascii_case(&self.config.ldap_disable_value)),
+        }
+    }
+
+    // The note `disable` leaves in LDAP_DISABLE_NOTE_ATTR, "<RFC 3339 time> <reason>".
+    // Anything else in the attribute is not a note.
+    fn disable_note(&self, entry: &SearchEntry) -> Option<(DateTime<Utc>, String)> {
+        let note = entry.attrs.get(&self.config.ldap_disable_note_attr)?.get(0)?;
+        let (at, reason) = note.split_once(' ')?;
+        let at = DateTime::parse_from_rfc3339(at).ok()?;
+        Some((at.with_timezone(&Utc), reason.to_string()))
+    }
+
+    // The marker put on accounts that have not confirmed their address yet.
+    fn is_verified(&self, entry: &SearchEntry) -> bool {
+        !entry.attrs.get(&self.config.ldap_unverified_attr).map_or(false, |values| {
+            values
+                .iter()
+                .any(|value| value.eq_ignore_ascii_case(&self.config.ldap_unverified_value))
+        })
+    }
+
+    fn entry_attrs(&self) -> Vec<&str> {
+        vec![
+            "uid",
+            "mail",
+            "cn",
+            "sn",
+            "displayName",
+            "givenName",
+            "telephoneNumber",
+            self.status_attr(),
+            self.config.ldap_disable_note_attr.as_str(),
+            self.config.ldap_unverified_attr.as_str(),
+        ]
+    }
+
+    // The account an entry describes, without its groups. None without a numeric uid.
+    fn to_identity(&self, entry: &SearchEntry) -> Option<Identity> {
+        let user_id = entry.attrs.get("uid").and_then(|uid| uid.get(0)).and_then(|uid| uid.parse::<u64>().ok())?;
+        let attr = |name: &str| entry.attrs.get(name).and_then(|values| values.get(0).cloned());
+        let disabled = self.is_disabled(entry);
+        let note = if disabled { self.disable_note(entry) } else { None };
+        Some(Identity {
+            user_id,
+            email: attr("mail").unwrap_or_default(),
+            groups: Vec::new(),
+            disabled,
+            disabled_at: note.as_ref().map(|(at, _)| *at),
+            disabled_reason: note.map(|(_, reason)| reason),
+            verified: self.is_verified(entry),
+            profile: Profile {
+                cn: attr("cn"),
+                sn: attr("sn"),
+                display_name: attr("displayName"),
+                given_name: attr("givenName"),
+                telephone_number: attr("telephoneNumber"),
+            },
+        })
+    }
+
+    // Looks up the first entry matching `filter`, without its groups.
+    async fn search_one(&self, filter: &str, write: bool) -> Result<Option<Identity>, IdentityError> {
+        let mut ldap = if write {
+            get_admin_ldap(&self.pool).await?
+        } else {
+            get_read_ldap(&self.pool).await?
+        };
+        let (rs, _res) = ldap
+            .search(&self.config.ldap_base_dn, Scope::Subtree, filter, self.entry_attrs())
+            .await?
+            .success()?;
+        let entry = match rs.into_iter().next() {
+            Some(entry) => SearchEntry::construct(entry),
+            None => return Ok(None),
+        };
+        self.to_identity(&entry)
+            .map(Some)
+            .ok_or_else(|| IdentityError::Backend(format!("{} has no numeric uid", entry.dn)))
+    }
+
+    // Streams every account matching `filter`, a page of the search at a
+    // time, into `visit`.
+    async fn scan(&self, filter: &str, mut visit: impl FnMut(Identity) + Send) -> Result<(), IdentityError> {
+        let mut ldap = get_read_ldap(&self.pool).await?;
+        let scanned = async {
+            let adapters: Vec<Box<dyn Adapter<_, _>>> = vec![
+                Box::new(EntriesOnly::new()),
+                Box::new(PagedResults::new(LIST_PAGE_SIZE)),
+            ];
+            let mut search = ldap
+                .streaming_search_with(adapters, &self.config.ldap_base_dn, Scope::Subtree, filter, self.entry_attrs())
+                .await?;
+            while let Some(entry) = search.next().await? {
+                if let Some(identity) = self.to_identity(&SearchEntry::construct(entry)) {
+                    visit(identity);
+                }
+            }
+            search.finish().await.success()?;
+            Ok::<_, ldap3::LdapError>(())
+        }
+        .await;
+        if let Err(err) = scanned {
+            ldap.observe_error(&err);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    // The address prefix and uid conditions of a listing.
+    fn list_filter(&self, filter: &UserFilter) -> String {
+        let mut inner = String::new();
+        if let Some(email) = &filter.email {
+            inner.push_str(&format!("(mail={}*)", ldap_escape(email.as_str())));
+        }
+        if let Some(uid) = filter.uid {
+            inner.push_str(&format!("(uid={})", uid));
+        }
+        inner
+    }
+
+    // Group listings page over the sorted member uids, searching for at most
+    // `limit` of them at a time, so the filter stays small however large the
+    // group is.
+    async fn list_group_page(&self, group: &str, filter: &UserFilter, after: u64, limit: usize) -> Result<IdentityPage, IdentityError> {
+        let mut ldap = get_read_ldap(&self.pool).await?;
+        let mut members = match fetch_group_member_uids(&mut ldap, &self.config, group).await? {
+            Some(members) => members,
+            None => return Err(IdentityError::NotFound),
+        };
+        if let Some(uid) = filter.uid {
+            members.retain(|member| *member == uid);
+        }
+        members.sort_unstable();
+        members.dedup();
+        let total = members.len();
+        let remaining: Vec<u64> = members.into_iter().filter(|member| *member > after).collect();
+
+        // members the address filter drops leave room for the next ones
+        let mut identities = Vec::new();
+        for chunk in remaining.chunks(limit) {
+            if identities.len() >= limit {
+                break;
+            }
+            let mut inner = self.list_filter(&UserFilter { uid: None, ..filter.clone() });
+            inner.push_str("(|");
+            for uid in chunk {
+                inner.push_str(&format!("(uid={})", uid));
+            }
+            inner.push(')');
+            let (rs, _res) = ldap
+                .search(&self.config.ldap_base_dn, Scope::Subtree, &self.config.ldap_flavor.user_filter(&inner), self.entry_attrs())
+                .await?
+                .success()?;
+            let mut found: Vec<Identity> = rs
+                .into_iter()
+                .filter_map(|entry| self.to_identity(&SearchEntry::construct(entry)))
+                .collect();
+            found.sort_by_key(|identity| identity.user_id);
+            identities.extend(found);
+        }
+        identities.truncate(limit);
+        Ok(IdentityPage { identities, total })
+    }
+
+    async fn with_groups(&self, identity: Identity) -> Result<Identity, IdentityError> {
+        let mut ldap = get_read_ldap(&self.pool).await?;
+        let groups = fetch_user_groups(&mut ldap, &self.config, identity.user_id).await?;
+        Ok(Identity { groups, ..identity })
+    }
+}
+
+#[async_trait]
+impl IdentityStore for LdapIdentityStore {
+    async fn ping(&self) -> Result<(), IdentityError> {
+        check_admin_bind(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn find_by_id(&self, user_id: u64) -> Result<Option<Identity>, IdentityError> {
+        let filter = self.config.ldap_flavor.user_filter(&format!("(uid={})", user_id));
+        match self.search_one(&filter, false).await? {
+            Some(identity) => Ok(Some(self.with_groups(identity).await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<Identity>, IdentityError> {
+        let filter = self.config.ldap_flavor.user_filter(&format!("(mail={})", ldap_escape(email)));
+        match self.search_one(&filter, false).await? {
+            Some(identity) => Ok(Some(self.with_groups(identity).await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn list_all(&self) -> Result<Vec<Identity>, IdentityError> {
+        let mut identities = Vec::new();
+        self.scan(&self.config.ldap_flavor.user_filter(""), |identity| identities.push(identity)).await?;
+        identities.sort_by_key(|identity| identity.user_id);
+        Ok(identities)
+    }
+
+    async fn list_disabled(&self) -> Result<Vec<Identity>, IdentityError> {
+        let flag = match self.config.ldap_flavor {
+            DirectoryFlavor::ActiveDirectory => format!("(userAccountControl:1.2.840.113556.1.4.803:={})", AD_ACCOUNT_DISABLED),
+            DirectoryFlavor::OpenLdap => format!(
+                "({}={})",
+                self.config.ldap_disable_attr,
+                ldap_escape(self.config.ldap_disable_value.as_str())
+            ),
+        };
+        let mut identities = Vec::new();
+        self.scan(&self.config.ldap_flavor.user_filter(&flag), |identity| identities.push(identity)).await?;
+        identities.sort_by_key(|identity| identity.user_id);
+        Ok(identities)
+    }
+
+    // The directory cannot order by uid, so a page outside a group scans
+    // every matching entry and keeps the lowest ids above `after`.
+    async fn list_page(&self, filter: &UserFilter, after: u64, limit: usize) -> Result<IdentityPage, IdentityError> {
+        if let Some(group) = &filter.group {
+            return self.list_group_page(group, filter, after, limit).await;
+        }
+        let mut total = 0;
+        let mut identities = Vec::new();
+        let ldap_filter = self.config.ldap_flavor.user_filter(&self.list_filter(filter));
+        self.scan(&ldap_filter, |identity| {
+            total += 1;
+            if identity.user_id > after {
+                identities.push(identity);
+                if identities.len() >= limit * 2 {
+                    identities.sort_by_key(|identity: &Identity| identity.user_id);
+                    identities.truncate(limit);
+                }
+            }
+        })
+        .await?;
+        identities.sort_by_key(|identity| identity.user_id);
+        identities.truncate(limit);
+        Ok(IdentityPage { identities, total })
+    }
+
+    async fn authenticate(&self, login: &str, password: &str) -> Result<Identity, IdentityError> {
+        let filter = self.config.ldap_flavor.login_filter(login);
+        let identity = match self.search_one(&filter, false).await? {
+            Some(identity) => identity,
+            None => return Err(IdentityError::NotFound),
+        };
+        let dn = user_dn(&self.config, identity.user_id);
+        if !check_credentials(&self.pool, &dn, password).await? {
+            return Err(IdentityError::InvalidCredentials);
+        }
+        self.with_groups(identity).await
+    }
+
+    async fn create(&self, new_identity: NewIdentity<'_>) -> Result<Identity, IdentityError> {
+        let email = new_identity.email;
+        let password = new_identity.password;
+        let user_id = new_identity.user_id.to_string();
+        let username = email.split('@').next().unwrap_or(email);
+        let flavor = self.config.ldap_flavor;
+        let sam_account_name = ad_sam_account_name(username);
+        // AD rejects unicodePwd writes on unencrypted connections
+        if flavor.is_active_directory() && !self.config.ldap_uses_tls() {
+            return Err(IdentityError::Backend(
+                "Active Directory requires ldaps:// or StartTLS to set passwords".to_string(),
+            ));
+        }
+
+        let filter = match flavor {
+            DirectoryFlavor::OpenLdap => flavor.user_filter(&format!("(|(mail={})(uid={}))", ldap_escape(email), user_id)),
+            DirectoryFlavor::ActiveDirectory => flavor.user_filter(&format!(
+                "(|(mail={0})(userPrincipalName={0})(sAMAccountName={1})(uid={2}))",
+                ldap_escape(email), ldap_escape(sam_account_name.as_str()), user_id
+            )),
+        };
+        if self.search_one(&filter, true).await?.is_some() {
+            return Err(IdentityError::Conflict("User with that email or uid already exists".to_string()));
+        }
+
+        let unicode_pwd = ad_unicode_password(password);
+        let account_control = if new_identity.disabled {
+            AD_NORMAL_ACCOUNT | AD_ACCOUNT_DISABLED
+        } else {
+            AD_NORMAL_ACCOUNT
+        }
+        .to_string();
+        let mut attrs: Vec<(&[u8], HashSet<&[u8]>)> = vec![
+            ("objectClass".as_bytes(), flavor.user_object_classes().into_iter().map(|class| class.as_bytes()).collect()),
+            ("uid".as_bytes(), vec![user_id.as_bytes()].into_iter().collect()),
+            ("mail".as_bytes(), vec![email.as_bytes()].into_iter().collect()),
+            ("sn".as_bytes(), vec![username.as_bytes()].into_iter().collect()),
+        ];
+        match flavor {
+            DirectoryFlavor::OpenLdap => {
+                attrs.push(("cn".as_bytes(), vec![email.as_bytes()].into_iter().collect()));
+                attrs.push(("userPassword".as_bytes(), vec![password.as_bytes()].into_iter().collect()));
+                if new_identity.disabled {
+                    let marker = self.config.ldap_disable_value.as_bytes();
+                    attrs.push((self.config.ldap_disable_attr.as_bytes(), vec![marker].into_iter().collect()));
+                }
+            }
+            DirectoryFlavor::ActiveDirectory => {
+                // the CN is the RDN of AD user entries
+                attrs.push(("cn".as_bytes(), vec![user_id.as_bytes()].into_iter().collect()));
+                attrs.push(("userPrincipalName".as_bytes(), vec![email.as_bytes()].into_iter().collect()));
+                attrs.push(("sAMAccountName".as_bytes(), vec![sam_account_name.as_bytes()].into_iter().collect()));
+                attrs.push(("unicodePwd".as_bytes(), vec![unicode_pwd.as_slice()].into_iter().collect()));
+                attrs.push(("userAccountControl".as_bytes(), vec![account_control.as_bytes()].into_iter().collect()));
+            }
+        }
+        if !new_identity.verified {
+            let marker = self.config.ldap_unverified_value.as_bytes();
+            attrs.push((self.config.ldap_unverified_attr.as_bytes(), vec![marker].into_iter().collect()));
+        }
+
+        let mut ldap = get_admin_ldap(&self.pool).await?;
+        let dn = user_dn(&self.config, new_identity.user_id);
+        ldap.add(&dn, attrs).await?.success()?;
+        let cn = match flavor {
+            DirectoryFlavor::OpenLdap => email.to_string(),
+            DirectoryFlavor::ActiveDirectory => user_id.clone(),
+        };
+        Ok(Identity {
+            user_id: new_identity.user_id,
+            email: email.to_string(),
+            groups: Vec::new(),
+            disabled: new_identity.disabled,
+            disabled_at: None,
+            disabled_reason: None,
+            verified: new_identity.verified,
+            profile: Profile { cn: Some(cn), sn: Some(username.to_string()), ..Profile::default() },
+        })
+    }
+
+    async fn delete(&self, user_id: u64) -> Result<(), IdentityError> {
+        let filter = self.config.ldap_flavor.user_filter(&format!("(uid={})", user_id));
+        if self.search_one(&filter, true).await?.is_none() {
+            return Err(IdentityError::NotFound);
+        }
+        let mut ldap = get_admin_ldap(&self.pool).await?;
+        ldap.delete(&user_dn(&self.config, user_id)).await?.success()?;
+        Ok(())
+    }
+
+    async fn set_disabled(&self, user_id: u64, disabled: bool) -> Result<(), IdentityError> {
+        let flavor = self.config.ldap_flavor;
+        let current = match self.search_one(&flavor.user_filter(&format!("(uid={})", user_id)), true).await? {
+            Some(identity) => identity,
+            None => return Err(IdentityError::NotFound),
+        };
+        let dn = user_dn(&self.config, user_id);
+        let mut ldap = get_admin_ldap(&self.pool).await?;
+        match flavor {
+            // flip the disabled flag, keeping the other userAccountControl bits
+            DirectoryFlavor::ActiveDirectory => {
+                let (rs, _res) = ldap
+                    .search(&dn, Scope::Base, "(objectClass=*)", vec!["userAccountControl"])
+                    .await?
+                    .success()?;
+                let account_control = rs
+                    .into_iter()
+                    .next()
+                    .map(SearchEntry::construct)
+                    .and_then(|entry| entry.attrs.get("userAccountControl").and_then(|uac| uac.get(0).cloned()))
+                    .and_then(|uac| uac.parse::<u32>().ok())
+                    .unwrap_or(AD_NORMAL_ACCOUNT);
+                let account_control = if disabled {
+                    account_control | AD_ACCOUNT_DISABLED
+                } else {
+                    account_control & !AD_ACCOUNT_DISABLED
+                }
+                .to_string();
+                let mods = vec![Mod::Replace("userAccountControl", vec![account_control.as_str()].into_iter().collect())];
+                ldap.modify(&dn, mods).await?.success()?;
+            }
+            DirectoryFlavor::OpenLdap => {
+                let attr = self.config.ldap_disable_attr.as_str();
+                let mods = if disabled {
+                    vec![Mod::Replace(attr, vec![self.config.ldap_disable_value.as_str()].into_iter().collect())]
+                } else {
+                    vec![Mod::Delete(attr, HashSet::new())]
+                };
+                let result = ldap.modify(&dn, mods).await?;
+                // enabling an account that was never marked is fine
+                if !(result.rc == NO_SUCH_ATTRIBUTE && !disabled) {
+                    result.success()?;
+                }
+            }
+        }
+        // the attribute is only cleared when it holds a note `disable` left
+        if !disabled && current.disabled_reason.is_some() {
+            let mods = vec![Mod::Delete(self.config.ldap_disable_note_attr.as_str(), HashSet::new())];
+            ldap.modify(&dn, mods).await?.success()?;
+        }
+        Ok(())
+    }
+
+    // The note replaces any value LDAP_DISABLE_NOTE_ATTR had, so it should
+    // name an attribute nothing else writes.
+    async fn disable(&self, user_id: u64, reason: &str) -> Result<Identity, IdentityError> {
+        self.set_disabled(user_id, true).await?;
+        let note = format!("{} {}", Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true), reason);
+        let mods = vec![Mod::Replace(self.config.ldap_disable_note_attr.as_str(), vec![note.as_str()].into_iter().collect())];
+        let mut ldap = get_admin_ldap(&self.pool).await?;
+        ldap.modify(&user_dn(&self.config, user_id), mods).await?.success()?;
+        match self.find_by_id(user_id).await? {
+            Some(identity) => Ok(identity),
+            None => Err(IdentityError::NotFound),
+        }
+    }
+
+    async fn set_verified(&self, user_id: u64, verified: bool) -> Result<(), IdentityError> {
+        let current = match self.search_one(&self.config.ldap_flavor.user_filter(&format!("(uid={})", user_id)), true).await? {
+            Some(identity) => identity,
+            None => return Err(IdentityError::NotFound),
+        };
+        if current.verified == verified {
+            return Ok(());
+        }
+        let attr = self.config.ldap_unverified_attr.as_str();
+        let marker = vec![self.config.ldap_unverified_value.as_str()].into_iter().collect();
+        // only the marker value is touched, other values of the attribute stay
+        let mods = if verified { vec![Mod::Delete(attr, marker)] } else { vec![Mod::Add(attr, marker)] };
+        let mut ldap = get_admin_ldap(&self.pool).await?;
+        ldap.modify(&user_dn(&self.config, user_id), mods).await?.success()?;
+        Ok(())
+    }
+
+    async fn update(&self, user_id: u64, changes: &UpdateUserSchema) -> Result<Identity, IdentityError> {
+        // the CN names AD user entries and can only change through a rename
+        if self.config.ldap_flavor.is_active_directory() && changes.cn.is_some() {
+            return Err(IdentityError::Invalid("cn cannot be changed in Active Directory".to_string()));
+        }
+        let flavor = self.config.ldap_flavor;
+        let current = match self.search_one(&flavor.user_filter(&format!("(uid={})", user_id)), true).await? {
+            Some(identity) => identity,
+            None => return Err(IdentityError::NotFound),
+        };
+        if let Some(email) = &changes.mail {
+            let taken = match flavor {
+                DirectoryFlavor::OpenLdap => format!("(mail={})", ldap_escape(email.as_str())),
+                DirectoryFlavor::ActiveDirectory => format!("(|(mail={0})(userPrincipalName={0}))", ldap_escape(email.as_str())),
+            };
+            let filter = flavor.user_filter(&format!("{}(!(uid={}))", taken, user_id));
+            if self.search_one(&filter, true).await?.is_some() {
+                return Err(IdentityError::Conflict("User with that email already exists".to_string()));
+            }
+        }
+
+        let mut mods: Vec<Mod<&str>> = changes
+            .changes()
+            .into_iter()
+            .map(|(attr, value)| Mod::Replace(attr, vec![value].into_iter().collect()))
+            .collect();
+        // AD signs in with the UPN, which follows the address so the old one stops working
+        if let (DirectoryFlavor::ActiveDirectory, Some(email)) = (flavor, &changes.mail) {
+            mods.push(Mod::Replace("userPrincipalName", vec![email.as_str()].into_iter().collect()));
+        }
+        let new_address = changes.mail.as_deref().map_or(false, |email| !email.eq_ignore_ascii_case(&current.email));
+        if new_address && current.verified {
+            let marker = vec![self.config.ldap_unverified_value.as_str()].into_iter().collect();
+            mods.push(Mod::Add(self.config.ldap_unverified_attr.as_str(), marker));
+        }
+        let mut ldap = get_admin_ldap(&self.pool).await?;
+        ldap.modify(&user_dn(&self.config, user_id), mods).await?.success()?;
+        drop(ldap);
+
+        match self.search_one(&flavor.user_filter(&format!("(uid={})", user_id)), true).await? {
+            Some(identity) => self.with_groups(identity).await,
+            None => Err(IdentityError::NotFound),
+        }
+    }
+
+    async fn group_members(&self, group: &str) -> Result<Option<Vec<u64>>, IdentityError> {
+        let mut ldap = get_read_ldap(&self.pool).await?;
+        let mut members = match fetch_group_member_uids(&mut ldap, &self.config, group).await? {
+            Some(members) => members,
+            None => return Ok(None),
+        };
+        members.sort_unstable();
+        members.dedup();
+        Ok(Some(members))
+    }
+
+    async fn create_group(&self, group: &str, description: Option<&str>, members: &[u64]) -> Result<(), IdentityError> {
+        for user_id in members {
+            if self.search_one(&self.config.ldap_flavor.user_filter(&format!("(uid={})", user_id)), true).await?.is_none() {
+                return Err(IdentityError::NotFound);
+            }
+        }
+        let member_dns: Vec<String> = members.iter().map(|user_id| user_dn(&self.config, *user_id)).collect();
+        let mut attrs: Vec<(&str, HashSet<&str>)> = vec![
+            ("objectClass", vec![self.config.ldap_flavor.group_object_class()].into_iter().collect()),
+            ("cn", vec![group].into_iter().collect()),
+            ("member", member_dns.iter().map(|dn| dn.as_str()).collect()),
+        ];
+        if let Some(description) = description {
+            attrs.push(("description", vec![description].into_iter().collect()));
+        }
+        let mut ldap = get_admin_ldap(&self.pool).await?;
+        let result = ldap.add(&group_dn(&self.config, group), attrs).await?;
+        if result.rc == ENTRY_ALREADY_EXISTS {
+            return Err(IdentityError::Conflict("Group with that name already exists".to_string()));
+        }
+        result.success()?;
+        Ok(())
+    }
+
+    async fn delete_group(&self, group: &str) -> Result<Vec<u64>, IdentityError> {
+        let mut ldap = get_admin_ldap(&self.pool).await?;
+        let members = match fetch_group_member_uids(&mut ldap, &self.config, group).await? {
+            Some(members) => members,
+            None => return Err(IdentityError::NotFound),
+        };
+        let result = ldap.delete(&group_dn(&self.config, group)).await?;
+        if result.rc == NO_SUCH_OBJECT {
+            return Err(IdentityError::NotFound);
+        }
+        result.success()?;
+        Ok(members)
+    }
+
+    // groupOfNames needs a member, so removing the last one is refused
+    async fn set_group_member(&self, user_id: u64, group: &str, member: bool) -> Result<(), IdentityError> {
+        if self.search_one(&self.config.ldap_flavor.user_filter(&format!("(uid={})", user_id)), true).await?.is_none() {
+            return Err(IdentityError::NotFound);
+        }
+        let member_dn = user_dn(&self.config, user_id);
+        let values = vec![member_dn.as_str()].into_iter().collect();
+        let mods = if member { vec![Mod::Add("member", values)] } else { vec![Mod::Delete("member", values)] };
+        let mut ldap = get_admin_ldap(&self.pool).await?;
+        let result = ldap.modify(&group_dn(&self.config, group), mods).await?;
+        match result.rc {
+            NO_SUCH_OBJECT => Err(IdentityError::NotFound),
+            ATTRIBUTE_OR_VALUE_EXISTS if member => Ok(()),
+            NO_SUCH_ATTRIBUTE if !member => Ok(()),
+            OBJECT_CLASS_VIOLATION => Err(IdentityError::Invalid("A group needs at least one member".to_string())),
+            _ => {
+                result.success()?;
+                Ok(())
+            }
+        }
+    }
+}
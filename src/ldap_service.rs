@@ -1,11 +1,38 @@
-use deadpool_ldap::{Manager, Pool};
-use deadpool::managed::Object;
-use deadpool::managed::PoolError;
+use async_trait::async_trait;
+use deadpool::managed::{self, BuildError, Hook, HookError, HookErrorCause, Object, Pool, PoolError, RecycleError, RecycleResult};
+use ldap3::exop::{WhoAmI, WhoAmIResp};
 use ldap3::result::LdapError;
+use ldap3::{ExopResult, Ldap, LdapConnAsync, LdapConnSettings, LdapResult, Mod, Scope, SearchResult};
+use native_tls::{Certificate, Identity, TlsConnector};
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::config::Config;
+use crate::directory::DirectoryFlavor;
+
+const POOL_SIZE: usize = 10;
+
+// Admin connections idle for longer than this are checked with WhoAmI before
+// they are handed out again.
+const IDLE_CHECK: Duration = Duration::from_secs(60);
+
+// result codes of a server that cannot serve requests right now
+const BUSY: u32 = 51;
+const UNAVAILABLE: u32 = 52;
+// result code of an admin connection that may have lost its bind
+const INSUFFICIENT_ACCESS: u32 = 50;
+
 #[derive(Debug)]
 pub enum MyError {
     PoolError(deadpool::managed::PoolError<LdapError>),
     LdapError(LdapError),
+    TlsError(native_tls::Error),
+    IoError(std::io::Error),
+    BindError(String),
+    Unavailable(String),
 }
 
 impl From<deadpool::managed::PoolError<LdapError>> for MyError {
@@ -20,12 +47,515 @@ impl From<LdapError> for MyError {
     }
 }
 
-pub async fn get_admin_ldap(pool: &Pool, admin_dn: &str, admin_password:&str  ) -> Result<Object<Manager>, MyError> {
-    let mut ldap = pool.get().await?;
-    let bind_result = ldap.simple_bind(admin_dn, admin_password).await?;
-    match bind_result.success() {
-        Ok(_) => println!("✅LDAP Bind successful"),
-        Err(err) => return Err(MyError::from(err)),
+impl From<native_tls::Error> for MyError {
+    fn from(err: native_tls::Error) -> Self {
+        MyError::TlsError(err)
     }
+}
+
+impl From<std::io::Error> for MyError {
+    fn from(err: std::io::Error) -> Self {
+        MyError::IoError(err)
+    }
+}
+
+pub type AdminPool = Pool<AdminManager>;
+pub type UserPool = Pool<UserBindManager>;
+
+// Builds the connection settings for ldaps:// and StartTLS from the config.
+// Plain ldap:// URLs without StartTLS ignore the TLS options.
+pub fn build_ldap_settings(config: &Config) -> Result<LdapConnSettings, MyError> {
+    let mut builder = TlsConnector::builder();
+    if let Some(ca_cert) = &config.ldap_ca_cert {
+        let pem = std::fs::read(ca_cert)?;
+        builder.add_root_certificate(Certificate::from_pem(&pem)?);
+    }
+    match (&config.ldap_client_cert, &config.ldap_client_key) {
+        (Some(client_cert), Some(client_key)) => {
+            let cert = std::fs::read(client_cert)?;
+            let key = std::fs::read(client_key)?;
+            builder.identity(Identity::from_pkcs8(&cert, &key)?);
+        }
+        (None, None) => {}
+        _ => {
+            return Err(MyError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "LDAP_CLIENT_CERT and LDAP_CLIENT_KEY must be set together",
+            )));
+        }
+    }
+    builder.danger_accept_invalid_hostnames(!config.ldap_tls_verify_hostname);
+    let connector = builder.build()?;
+
+    Ok(LdapConnSettings::new()
+        .set_conn_timeout(Duration::from_secs(config.ldap_conn_timeout_secs))
+        .set_connector(connector)
+        .set_starttls(config.ldap_starttls))
+}
+
+async fn connect(url: &str, settings: &LdapConnSettings) -> Result<Ldap, LdapError> {
+    let (conn, ldap) = LdapConnAsync::with_settings(settings.clone(), url).await?;
+    ldap3::drive!(conn);
     Ok(ldap)
-}
\ No newline at end of file
+}
+
+// Returns the authorization identity of the connection, "" when anonymous.
+async fn who_am_i(ldap: &mut Ldap) -> Result<String, LdapError> {
+    let (exop, _res) = ldap.extended(WhoAmI).await?.success()?;
+    if exop.val.is_none() {
+        return Ok(String::new());
+    }
+    let resp: WhoAmIResp = exop.parse();
+    Ok(resp.authzid)
+}
+
+// The identity admin connections bind as.
+struct AdminBind {
+    flavor: DirectoryFlavor,
+    admin_dn: String,
+    admin_password: String,
+}
+
+impl AdminBind {
+    async fn bind(&self, ldap: &mut Ldap) -> Result<(), LdapError> {
+        ldap.simple_bind(&self.admin_dn, &self.admin_password).await?.success()?;
+        Ok(())
+    }
+
+    // Re-binds when the server no longer reports the admin identity.
+    async fn ensure_bound(&self, ldap: &mut Ldap) -> Result<(), LdapError> {
+        let authzid = who_am_i(ldap).await?;
+        if !self.flavor.is_authzid_of(&authzid, &self.admin_dn) {
+            self.bind(ldap).await?;
+        }
+        Ok(())
+    }
+}
+
+// Connections handed out by this manager are always bound as the admin.
+// Checking out a connection costs no round trip: connections that failed an
+// operation are dropped by LdapConn, and only those that sat idle for
+// IDLE_CHECK are asked for their identity and re-bound if needed.
+pub struct AdminManager {
+    url: String,
+    settings: LdapConnSettings,
+    admin: Arc<AdminBind>,
+}
+
+impl AdminManager {
+    pub fn new(url: &str, settings: LdapConnSettings, flavor: DirectoryFlavor, admin_dn: &str, admin_password: &str) -> Self {
+        AdminManager {
+            url: url.to_owned(),
+            settings,
+            admin: Arc::new(AdminBind {
+                flavor,
+                admin_dn: admin_dn.to_owned(),
+                admin_password: admin_password.to_owned(),
+            }),
+        }
+    }
+
+    // pre_recycle hook running the idle check; a connection that fails it is
+    // replaced by a new one.
+    pub fn idle_check(&self) -> Hook<AdminManager> {
+        let admin = self.admin.clone();
+        Hook::async_fn(move |ldap, metrics| {
+            let admin = admin.clone();
+            Box::pin(async move {
+                if metrics.last_used() < IDLE_CHECK {
+                    return Ok(());
+                }
+                admin
+                    .ensure_bound(ldap)
+                    .await
+                    .map_err(|err| HookError::Continue(Some(HookErrorCause::Backend(err))))
+            })
+        })
+    }
+}
+
+#[async_trait]
+impl managed::Manager for AdminManager {
+    type Type = Ldap;
+    type Error = LdapError;
+
+    async fn create(&self) -> Result<Ldap, LdapError> {
+        let mut ldap = connect(&self.url, &self.settings).await?;
+        self.admin.bind(&mut ldap).await?;
+        Ok(ldap)
+    }
+
+    async fn recycle(&self, ldap: &mut Ldap) -> RecycleResult<LdapError> {
+        if ldap.is_closed() {
+            return Err(RecycleError::StaticMessage("connection closed"));
+        }
+        Ok(())
+    }
+}
+
+// Connections handed out by this manager are used to check end-user credentials.
+// check_credentials resets them to an anonymous bind after every use and drops
+// those it cannot reset, so recycling only skips closed connections.
+pub struct UserBindManager {
+    url: String,
+    settings: LdapConnSettings,
+}
+
+impl UserBindManager {
+    pub fn new(url: &str, settings: LdapConnSettings) -> Self {
+        UserBindManager {
+            url: url.to_owned(),
+            settings,
+        }
+    }
+}
+
+#[async_trait]
+impl managed::Manager for UserBindManager {
+    type Type = Ldap;
+    type Error = LdapError;
+
+    async fn create(&self) -> Result<Ldap, LdapError> {
+        connect(&self.url, &self.settings).await
+    }
+
+    async fn recycle(&self, ldap: &mut Ldap) -> RecycleResult<LdapError> {
+        if ldap.is_closed() {
+            return Err(RecycleError::StaticMessage("connection closed"));
+        }
+        Ok(())
+    }
+}
+
+// Ejection state of one directory server, shared with the connections
+// checked out from it so that a failing operation can eject the server too.
+struct ServerHealth {
+    url: String,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl ServerHealth {
+    fn is_available(&self) -> bool {
+        match *self.ejected_until.lock().unwrap() {
+            Some(until) => Instant::now() >= until,
+            None => true,
+        }
+    }
+
+    fn mark_up(&self) {
+        let mut ejected_until = self.ejected_until.lock().unwrap();
+        if ejected_until.take().is_some() {
+            println!("✅LDAP server {} re-admitted", self.url);
+        }
+    }
+
+    fn mark_down(&self, eject_for: Duration) {
+        *self.ejected_until.lock().unwrap() = Some(Instant::now() + eject_for);
+        println!("❌LDAP server {} ejected for {}s", self.url, eject_for.as_secs());
+    }
+}
+
+// One directory server with its own admin and credential-check pools.
+// A server that fails to hand out a connection, or whose connection times out
+// or drops during an operation, is ejected for a while and re-admitted once the
+// ejection expires.
+struct LdapServer {
+    health: Arc<ServerHealth>,
+    admin_pool: AdminPool,
+    user_pool: UserPool,
+}
+
+fn is_connection_error(err: &LdapError) -> bool {
+    matches!(
+        err,
+        LdapError::Timeout { .. }
+            | LdapError::Io { .. }
+            | LdapError::OpSend { .. }
+            | LdapError::ResultRecv { .. }
+            | LdapError::EndOfStream
+    )
+}
+
+// A pooled connection whose operations are each bounded by LDAP_CONN_TIMEOUT_SECS.
+// When an operation times out, loses the connection or finds the server busy or
+// unavailable, the server is ejected and the connection is dropped instead of
+// going back into the pool.
+pub struct LdapConn<M: managed::Manager<Type = Ldap, Error = LdapError>> {
+    ldap: Option<Object<M>>,
+    health: Arc<ServerHealth>,
+    op_timeout: Duration,
+    eject_for: Duration,
+    broken: bool,
+}
+
+pub type AdminConn = LdapConn<AdminManager>;
+pub type UserConn = LdapConn<UserBindManager>;
+
+impl<M: managed::Manager<Type = Ldap, Error = LdapError>> LdapConn<M> {
+    fn conn(&mut self) -> &mut Ldap {
+        let ldap: &mut Ldap = self.ldap.as_mut().expect("LDAP connection already detached");
+        ldap.with_timeout(self.op_timeout)
+    }
+
+    fn check<T>(&mut self, result: Result<T, LdapError>, rc: fn(&T) -> u32) -> Result<T, LdapError> {
+        match &result {
+            Ok(value) if matches!(rc(value), BUSY | UNAVAILABLE) => self.fail(),
+            Ok(value) if rc(value) == INSUFFICIENT_ACCESS => self.discard(),
+            Err(err) => self.observe_error(err),
+            Ok(_) => {}
+        }
+        result
+    }
+
+    fn fail(&mut self) {
+        self.broken = true;
+        self.health.mark_down(self.eject_for);
+    }
+
+    // For errors of operations run on the connection directly, such as streaming searches.
+    pub fn observe_error(&mut self, err: &LdapError) {
+        if is_connection_error(err) {
+            self.fail();
+        }
+    }
+
+    // Drops the connection instead of returning it to the pool, without ejecting the server.
+    pub fn discard(&mut self) {
+        self.broken = true;
+    }
+
+    // Takes the connection out of the pool for good, e.g. to keep it across requests.
+    pub fn detach(mut self) -> Ldap {
+        Object::take(self.ldap.take().expect("LDAP connection already detached"))
+    }
+
+    pub async fn search<'a, S, A>(&mut self, base: &str, scope: Scope, filter: &str, attrs: A) -> Result<SearchResult, LdapError>
+    where
+        S: AsRef<str> + Send + Sync + 'a,
+        A: AsRef<[S]> + Send + Sync + 'a,
+    {
+        let result = self.conn().search(base, scope, filter, attrs).await;
+        self.check(result, |result| result.1.rc)
+    }
+
+    pub async fn add<S: AsRef<[u8]> + Eq + Hash>(&mut self, dn: &str, attrs: Vec<(S, HashSet<S>)>) -> Result<LdapResult, LdapError> {
+        let result = self.conn().add(dn, attrs).await;
+        self.check(result, |result| result.rc)
+    }
+
+    pub async fn modify<S: AsRef<[u8]> + Eq + Hash>(&mut self, dn: &str, mods: Vec<Mod<S>>) -> Result<LdapResult, LdapError> {
+        let result = self.conn().modify(dn, mods).await;
+        self.check(result, |result| result.rc)
+    }
+
+    pub async fn delete(&mut self, dn: &str) -> Result<LdapResult, LdapError> {
+        let result = self.conn().delete(dn).await;
+        self.check(result, |result| result.rc)
+    }
+
+    pub async fn simple_bind(&mut self, bind_dn: &str, bind_pw: &str) -> Result<LdapResult, LdapError> {
+        let result = self.conn().simple_bind(bind_dn, bind_pw).await;
+        self.check(result, |result| result.rc)
+    }
+
+    pub async fn who_am_i(&mut self) -> Result<String, LdapError> {
+        let result = self.conn().extended(WhoAmI).await;
+        let (exop, _res) = self.check(result, |result: &ExopResult| result.1.rc)?.success()?;
+        if exop.val.is_none() {
+            return Ok(String::new());
+        }
+        let resp: WhoAmIResp = exop.parse();
+        Ok(resp.authzid)
+    }
+}
+
+// Anything else (streaming searches, controls) goes to the connection with the timeout set.
+impl<M: managed::Manager<Type = Ldap, Error = LdapError>> Deref for LdapConn<M> {
+    type Target = Ldap;
+
+    fn deref(&self) -> &Ldap {
+        self.ldap.as_ref().expect("LDAP connection already detached")
+    }
+}
+
+impl<M: managed::Manager<Type = Ldap, Error = LdapError>> DerefMut for LdapConn<M> {
+    fn deref_mut(&mut self) -> &mut Ldap {
+        self.conn()
+    }
+}
+
+impl<M: managed::Manager<Type = Ldap, Error = LdapError>> Drop for LdapConn<M> {
+    fn drop(&mut self) {
+        if let Some(ldap) = self.ldap.take() {
+            if self.broken {
+                let _ = Object::take(ldap);
+            }
+        }
+    }
+}
+
+// Routes searches and credential checks round-robin over the healthy servers
+// and pins writes (add, delete, modify) to the primary, the first LDAP_URL.
+pub struct LdapCluster {
+    servers: Vec<LdapServer>,
+    next: AtomicUsize,
+    checkout_timeout: Duration,
+    op_timeout: Duration,
+    eject_for: Duration,
+}
+
+impl LdapCluster {
+    pub fn new(config: &Config, settings: LdapConnSettings) -> Result<LdapCluster, BuildError<LdapError>> {
+        let mut servers = Vec::new();
+        for url in config.ldap_urls.iter() {
+            let admin_manager = AdminManager::new(
+                url,
+                settings.clone(),
+                config.ldap_flavor,
+                &config.ldap_admin_dn,
+                &config.ldap_admin_password,
+            );
+            let idle_check = admin_manager.idle_check();
+            let user_manager = UserBindManager::new(url, settings.clone());
+            servers.push(LdapServer {
+                health: Arc::new(ServerHealth { url: url.to_owned(), ejected_until: Mutex::new(None) }),
+                admin_pool: Pool::builder(admin_manager).max_size(POOL_SIZE).pre_recycle(idle_check).build()?,
+                user_pool: Pool::builder(user_manager).max_size(POOL_SIZE).build()?,
+            });
+        }
+        Ok(LdapCluster {
+            servers,
+            next: AtomicUsize::new(0),
+            // leave room for the bind or recycle check after connecting
+            checkout_timeout: Duration::from_secs(config.ldap_conn_timeout_secs * 2),
+            op_timeout: Duration::from_secs(config.ldap_conn_timeout_secs),
+            eject_for: Duration::from_secs(config.ldap_eject_secs),
+        })
+    }
+
+    pub fn primary_url(&self) -> &str {
+        self.servers.first().map(|server| server.health.url.as_str()).unwrap_or("")
+    }
+
+    async fn checkout<M>(&self, server: &LdapServer, pool: &Pool<M>) -> Result<LdapConn<M>, MyError>
+    where
+        M: managed::Manager<Type = Ldap, Error = LdapError>,
+    {
+        let health = &server.health;
+        match tokio::time::timeout(self.checkout_timeout, pool.get()).await {
+            Ok(Ok(ldap)) => {
+                health.mark_up();
+                Ok(LdapConn {
+                    ldap: Some(ldap),
+                    health: health.clone(),
+                    op_timeout: self.op_timeout,
+                    eject_for: self.eject_for,
+                    broken: false,
+                })
+            }
+            Ok(Err(err)) => {
+                // a rejected bind is a configuration problem, not a dead server
+                if !matches!(err, PoolError::Backend(LdapError::LdapResult { .. })) {
+                    health.mark_down(self.eject_for);
+                }
+                Err(MyError::from(err))
+            }
+            Err(_) => {
+                health.mark_down(self.eject_for);
+                Err(MyError::Unavailable(format!("timed out connecting to {}", health.url)))
+            }
+        }
+    }
+
+    // Servers to try for a read, starting at the next one in rotation. When all
+    // of them are ejected the primary is tried anyway.
+    fn read_order(&self) -> Vec<&LdapServer> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.servers.len();
+        let available: Vec<&LdapServer> = (0..count)
+            .map(|offset| &self.servers[(start + offset) % count])
+            .filter(|server| server.health.is_available())
+            .collect();
+        if available.is_empty() {
+            return self.servers.first().into_iter().collect();
+        }
+        available
+    }
+
+    pub async fn write_admin(&self) -> Result<AdminConn, MyError> {
+        let primary = match self.servers.first() {
+            Some(primary) => primary,
+            None => return Err(MyError::Unavailable("no LDAP server configured".to_string())),
+        };
+        if !primary.health.is_available() {
+            return Err(MyError::Unavailable(format!("primary LDAP server {} is ejected", primary.health.url)));
+        }
+        self.checkout(primary, &primary.admin_pool).await
+    }
+
+    pub async fn read_admin(&self) -> Result<AdminConn, MyError> {
+        let mut last_err = None;
+        for server in self.read_order() {
+            match self.checkout(server, &server.admin_pool).await {
+                Ok(ldap) => return Ok(ldap),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| MyError::Unavailable("no LDAP server configured".to_string())))
+    }
+
+    pub async fn read_user(&self) -> Result<UserConn, MyError> {
+        let mut last_err = None;
+        for server in self.read_order() {
+            match self.checkout(server, &server.user_pool).await {
+                Ok(ldap) => return Ok(ldap),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| MyError::Unavailable("no LDAP server configured".to_string())))
+    }
+}
+
+// Admin connection to the primary, for handlers that write to the directory.
+pub async fn get_admin_ldap(pool: &LdapCluster) -> Result<AdminConn, MyError> {
+    pool.write_admin().await
+}
+
+// Admin connection to any healthy server, for handlers that only search.
+pub async fn get_read_ldap(pool: &LdapCluster) -> Result<AdminConn, MyError> {
+    pool.read_admin().await
+}
+
+// Binds as `dn` to verify the password, then drops the user's identity from the
+// connection. A connection that cannot be reset is removed from the pool.
+pub async fn check_credentials(pool: &LdapCluster, dn: &str, password: &str) -> Result<bool, MyError> {
+    // an empty password would be an unauthenticated bind and always succeed
+    if password.is_empty() {
+        return Ok(false);
+    }
+    let mut ldap = pool.read_user().await?;
+    let bind_result = ldap.simple_bind(dn, password).await;
+    let valid = match bind_result {
+        Ok(result) => result.success().is_ok(),
+        Err(err) => {
+            ldap.discard();
+            return Err(MyError::from(err));
+        }
+    };
+    let reset_result = ldap.simple_bind("", "").await.and_then(|result| result.success());
+    if reset_result.is_err() {
+        ldap.discard();
+    }
+    Ok(valid)
+}
+
+// Checks out an admin connection to the primary and confirms the server still
+// reports the admin identity.
+pub async fn check_admin_bind(pool: &LdapCluster) -> Result<(), MyError> {
+    let mut ldap = get_admin_ldap(pool).await?;
+    let authzid = ldap.who_am_i().await?;
+    if authzid.is_empty() {
+        return Err(MyError::BindError("admin connection is not bound".to_string()));
+    }
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{engine::general_purpose, Engine as _};
+use redis::AsyncCommands;
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::mail_service::MailTransport;
+use crate::token_service;
+
+pub const MAGIC_LINK_PURPOSE: &str = "magic_link";
+
+#[derive(Debug)]
+pub enum MagicLinkError {
+    InvalidLink,
+    AlreadyUsed,
+    RedisError(redis::RedisError),
+}
+
+impl From<redis::RedisError> for MagicLinkError {
+    fn from(err: redis::RedisError) -> Self {
+        MagicLinkError::RedisError(err)
+    }
+}
+
+fn rate_key(email: &str) -> String {
+    format!("magic_link_rate:{}", email.to_lowercase())
+}
+
+fn used_key(jti: &str) -> String {
+    format!("magic_link_used:{}", jti)
+}
+
+// The device asking for a link keeps this nonce; only a hash of it travels in
+// the mailed token, so a link forwarded to another device is useless.
+pub fn new_nonce() -> String {
+    let mut nonce = [0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    general_purpose::URL_SAFE_NO_PAD.encode(nonce)
+}
+
+fn hash_nonce(nonce: &str) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(nonce.as_bytes()))
+}
+
+// Counts a request for `email`, false once MAGIC_LINK_RATE_LIMIT requests were
+// made within MAGIC_LINK_RATE_WINDOW_SECS.
+pub async fn allow_request(redis_client: &redis::Client, config: &Config, email: &str) -> redis::RedisResult<bool> {
+    let mut conn = redis_client.get_async_connection().await?;
+    // the counter gets its TTL in the command that creates it, so a failure
+    // between the two steps cannot leave it without one
+    let _: Option<String> = redis::cmd("SET")
+        .arg(rate_key(email))
+        .arg(0)
+        .arg("NX")
+        .arg("EX")
+        .arg(config.magic_link_rate_window_secs)
+        .query_async(&mut conn)
+        .await?;
+    let count: u32 = conn.incr(rate_key(email), 1).await?;
+    Ok(count <= config.magic_link_rate_limit)
+}
+
+pub async fn send_magic_link(
+    mailer: &dyn MailTransport,
+    config: &Config,
+    uid: u64,
+    email: &str,
+    nonce: &str,
+) -> Result<(), String> {
+    let token = token_service::generate_email_token(
+        uid,
+        email,
+        MAGIC_LINK_PURPOSE,
+        Some(hash_nonce(nonce)),
+        config.magic_link_max_age,
+        config.access_token_private_key.to_owned(),
+    )
+    .map_err(|err| err.to_string())?;
+    let link = format!("{}?token={}", config.magic_link_url, token);
+    let body = format!(
+        "Open this link on the device where you asked for it to sign in:\n\n{}\n\nThe link expires in {} minutes and works once.",
+        link, config.magic_link_max_age
+    );
+    mailer
+        .send(email, "Your sign-in link", &body)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+// Checks a link against the device nonce and burns it. Returns the uid and
+// the email the link was sent to.
+pub async fn redeem(
+    redis_client: &redis::Client,
+    config: &Config,
+    token: &str,
+    nonce: &str,
+) -> Result<(u64, String), MagicLinkError> {
+    let claims = token_service::verify_email_token(config.access_token_public_key.to_owned(), token, MAGIC_LINK_PURPOSE)
+        .map_err(|_| MagicLinkError::InvalidLink)?;
+    if claims.nonce_hash.as_deref() != Some(hash_nonce(nonce).as_str()) {
+        return Err(MagicLinkError::InvalidLink);
+    }
+
+    // remember the link until it would have expired anyway
+    let ttl = (claims.exp - chrono::Utc::now().timestamp()).max(1) as usize;
+    let mut conn = redis_client.get_async_connection().await?;
+    let claimed: Option<String> = redis::cmd("SET")
+        .arg(used_key(&claims.jti))
+        .arg("1")
+        .arg("NX")
+        .arg("EX")
+        .arg(ttl)
+        .query_async(&mut conn)
+        .await?;
+    if claimed.is_none() {
+        return Err(MagicLinkError::AlreadyUsed);
+    }
+    Ok((claims.sub.parse::<u64>().unwrap(), claims.email))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{memory_store, redis_available, test_state};
+
+    fn link_for(config: &Config, nonce: &str) -> String {
+        token_service::generate_email_token(
+            2,
+            "user@example.com",
+            MAGIC_LINK_PURPOSE,
+            Some(hash_nonce(nonce)),
+            config.magic_link_max_age,
+            config.access_token_private_key.to_owned(),
+        )
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn links_work_once_on_the_device_that_asked() {
+        let data = test_state(memory_store().await);
+        if !redis_available(&data).await {
+            return;
+        }
+        let nonce = new_nonce();
+        let token = link_for(&data.env, &nonce);
+        assert!(matches!(redeem(&data.redis_client, &data.env, &token, &new_nonce()).await, Err(MagicLinkError::InvalidLink)));
+        assert_eq!(
+            redeem(&data.redis_client, &data.env, &token, &nonce).await.unwrap(),
+            (2, "user@example.com".to_string())
+        );
+        assert!(matches!(redeem(&data.redis_client, &data.env, &token, &nonce).await, Err(MagicLinkError::AlreadyUsed)));
+        assert!(matches!(redeem(&data.redis_client, &data.env, "garbage", &nonce).await, Err(MagicLinkError::InvalidLink)));
+    }
+
+    #[actix_web::test]
+    async fn requests_are_rate_limited_per_address() {
+        let data = test_state(memory_store().await);
+        if !redis_available(&data).await {
+            return;
+        }
+        // a fresh address, the counters outlive the test
+        let email = format!("{}@example.com", new_nonce());
+        for _ in 0..data.env.magic_link_rate_limit {
+            assert!(allow_request(&data.redis_client, &data.env, &email).await.unwrap());
+        }
+        assert!(!allow_request(&data.redis_client, &data.env, &email.to_uppercase()).await.unwrap());
+        let other = format!("{}@example.com", new_nonce());
+        assert!(allow_request(&data.redis_client, &data.env, &other).await.unwrap());
+    }
+}
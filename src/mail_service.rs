@@ -0,0 +1,70 @@
+use async_trait::async_trait;
+use lettre::message::Mailbox;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::sync::Arc;
+
+use crate::config::Config;
+
+#[derive(Debug)]
+pub enum MailError {
+    Address(String),
+    Transport(String),
+}
+
+// Delivers the mails the service sends, selected with MAIL_TRANSPORT.
+#[async_trait]
+pub trait MailTransport: Send + Sync {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), MailError>;
+}
+
+// Prints mails to stdout instead of sending them, for local development.
+pub struct LogTransport;
+
+#[async_trait]
+impl MailTransport for LogTransport {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), MailError> {
+        println!("📧Mail to {}: {}\n{}", to, subject, body);
+        Ok(())
+    }
+}
+
+pub struct SmtpTransport {
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+#[async_trait]
+impl MailTransport for SmtpTransport {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), MailError> {
+        let to: Mailbox = to.parse().map_err(|err| MailError::Address(format!("{:?}", err)))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .body(body.to_string())
+            .map_err(|err| MailError::Address(err.to_string()))?;
+        self.mailer
+            .send(message)
+            .await
+            .map_err(|err| MailError::Transport(err.to_string()))?;
+        Ok(())
+    }
+}
+
+pub fn build_transport(config: &Config) -> Result<Arc<dyn MailTransport>, String> {
+    match config.mail_transport.as_str() {
+        "log" => Ok(Arc::new(LogTransport)),
+        "smtp" => {
+            let url = config.smtp_url.as_deref().ok_or("SMTP_URL must be set for MAIL_TRANSPORT=smtp")?;
+            let mailer = AsyncSmtpTransport::<Tokio1Executor>::from_url(url)
+                .map_err(|err| format!("SMTP_URL: {}", err))?
+                .build();
+            let from = config
+                .mail_from
+                .parse()
+                .map_err(|err| format!("MAIL_FROM: {:?}", err))?;
+            Ok(Arc::new(SmtpTransport { mailer, from }))
+        }
+        other => Err(format!("MAIL_TRANSPORT must be log or smtp, got {}", other)),
+    }
+}
@@ -8,26 +8,73 @@ use redis::Client;
 use ldap3::{LdapConnAsync, Scope, SearchEntry};
 use ldap3::result::Result;
 use std::collections::HashSet;
-use std::sync::Mutex;
-use deadpool_ldap::{Manager, Pool};
+use std::sync::{Arc, Mutex};
+use ldap_service::LdapCluster;
+use identity_store::{IdentityBackend, IdentityStore};
+use ldap_identity_store::LdapIdentityStore;
+use memory_identity_store::MemoryIdentityStore;
+use postgres_identity_store::PostgresIdentityStore;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{Pool, Postgres};
+use sync_service::SyncState;
+use webauthn_rs::Webauthn;
+use mail_service::MailTransport;
+use audit_service::AuditLog;
 // Modules 
 mod config;
+mod directory;
 mod user_handler;
 mod auth_handler;
 mod jwt_auth;
+mod authz;
+mod scope_service;
+mod registration_service;
+mod account_service;
+mod dpop_service;
+mod cookie_service;
 mod user_model;
 mod response;
 mod token_model;
 mod token_service;
 mod user_service;
 mod ldap_service;
+mod health_handler;
+mod group_model;
+mod group_service;
+mod group_handler;
+mod paging_service;
+mod user_cache;
+mod identity_store;
+mod ldap_identity_store;
+mod postgres_identity_store;
+mod memory_identity_store;
+mod sync_service;
+mod sync_handler;
+mod bulk_service;
+mod cli;
+mod mfa_service;
+mod webauthn_service;
+mod mail_service;
+mod verification_service;
+mod magic_link_service;
+mod pat_service;
+mod pat_handler;
+mod audit_service;
+mod audit_handler;
+#[cfg(test)]
+mod test_support;
 // Types
 pub struct AppState {
     env: Config,
     redis_client: Client,
-    ldap_pool: Pool,
+    ldap_pool: Arc<LdapCluster>,
+    identity: Arc<dyn IdentityStore>,
+    db_pool: Option<Pool<Postgres>>,
+    user_sync: Arc<SyncState>,
+    webauthn: Option<Arc<Webauthn>>,
+    mailer: Arc<dyn MailTransport>,
+    audit: Arc<AuditLog>,
 }
-pub struct LdapConnAsyncManager;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -51,11 +98,17 @@ async fn main() -> std::io::Result<()> {
         }
     };
 
-    let manager = Manager::new(config.ldap_url.to_owned());
-    let pool = match Pool::builder(manager).max_size(10).build() {
+    let ldap_settings = match ldap_service::build_ldap_settings(&config) {
+        Ok(settings) => settings,
+        Err(e) => {
+            println!("Error configuring LDAP TLS: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let pool = match LdapCluster::new(&config, ldap_settings) {
         Ok(pool) => {
             println!("✅Connection to the LDAP is successful!");
-            pool
+            Arc::new(pool)
         }
         Err(e) => {
             println!("Error connecting to LDAP: {}", e);
@@ -63,12 +116,107 @@ async fn main() -> std::io::Result<()> {
         }
     };
 
-    //exec a mock search
-    let mut ldap = pool.get().await.unwrap();
-    let bind_result = ldap.simple_bind(&config.ldap_admin_dn.to_owned(), &config.ldap_admin_password.to_owned()).await.unwrap();
-    match bind_result.success() {
-        Ok(_) => println!("✅LDAP Bind successful"),
-        Err(err) => println!("❌LDAP Bind failed: {}", err),
+    // admin connections bind on creation, so a failed TLS handshake,
+    // StartTLS negotiation or admin bind on the primary surfaces here
+    if !config.ldap_urls.is_empty() {
+        match ldap_service::get_admin_ldap(&pool).await {
+            Ok(_) => println!("✅LDAP Bind successful"),
+            Err(e) => {
+                println!("❌Error connecting to LDAP at {}: {:?}", pool.primary_url(), e);
+                std::process::exit(1);
+            }
+        };
+    }
+
+    let db_pool = match &config.database_url {
+        Some(database_url) => {
+            let db_pool = match PgPoolOptions::new().max_connections(10).connect(database_url).await {
+                Ok(db_pool) => {
+                    println!("✅Connection to the database is successful!");
+                    db_pool
+                }
+                Err(e) => {
+                    println!("Error connecting to the database: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if let Err(e) = sqlx::migrate!("./migrations").run(&db_pool).await {
+                println!("Error running database migrations: {}", e);
+                std::process::exit(1);
+            }
+            Some(db_pool)
+        }
+        None => None,
+    };
+
+    let identity: Arc<dyn IdentityStore> = match config.identity_backend {
+        IdentityBackend::Ldap => Arc::new(LdapIdentityStore::new(pool.clone(), config.clone())),
+        IdentityBackend::Postgres => Arc::new(PostgresIdentityStore::new(db_pool.clone().unwrap())),
+        IdentityBackend::Memory => Arc::new(MemoryIdentityStore::new()),
+    };
+    println!("✅Identity backend: {}", config.identity_backend.name());
+
+    // `auth_ms import|export|group ...` runs a one-off job instead of the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args, &config, identity.as_ref(), &redis_client).await);
+    }
+
+    match verification_service::import_legacy_markers(identity.as_ref(), &redis_client).await {
+        Ok(0) => {}
+        Ok(imported) => println!("✅Moved {} verification markers from Redis to the identity store", imported),
+        Err(e) => {
+            println!("Error moving verification markers to the identity store: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(db_pool) = &db_pool {
+        match mfa_service::import_legacy_records(db_pool, &redis_client).await {
+            Ok(0) => {}
+            Ok(imported) => println!("✅Moved {} 2FA records from Redis to the database", imported),
+            Err(e) => {
+                println!("Error moving 2FA records to the database: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        match webauthn_service::import_legacy_passkeys(db_pool, &redis_client).await {
+            Ok(0) => {}
+            Ok(imported) => println!("✅Moved {} passkeys from Redis to the database", imported),
+            Err(e) => {
+                println!("Error moving passkeys to the database: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let audit = Arc::new(AuditLog::new(db_pool.clone()));
+    println!("✅Audit events go to {}", if audit.is_persistent() { "the database" } else { "stdout" });
+
+    let mailer = match mail_service::build_transport(&config) {
+        Ok(mailer) => mailer,
+        Err(e) => {
+            println!("Error configuring mail: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let webauthn = match webauthn_service::build_webauthn(&config) {
+        Ok(Some(webauthn)) => {
+            println!("✅Passkeys enabled for {}", config.webauthn_rp_id.as_deref().unwrap_or_default());
+            Some(Arc::new(webauthn))
+        }
+        Ok(None) => None,
+        Err(e) => {
+            println!("Error configuring WebAuthn: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let user_sync = Arc::new(SyncState::default());
+    if config.user_sync_interval_secs > 0 {
+        // Config::init only allows the sync with the ldap backend and a DATABASE_URL
+        sync_service::spawn_user_sync(pool.clone(), db_pool.clone().unwrap(), config.clone(), user_sync.clone());
+        println!("✅User sync every {}s", config.user_sync_interval_secs);
     }
 
     println!("🚀  Server started successfully ");
@@ -76,11 +224,14 @@ async fn main() -> std::io::Result<()> {
     HttpServer::new(move || { 
         let cors = Cors::permissive()
             .allowed_origin("http://0.0.0.0:5000")
-            .allowed_methods(vec!["GET", "POST"])
+            .allowed_methods(vec!["GET", "POST", "PATCH", "DELETE"])
             .allowed_headers(vec![
                 header::CONTENT_TYPE,
                 header::AUTHORIZATION, 
-                header::ACCEPT
+                header::ACCEPT,
+                header::HeaderName::from_static("dpop"),
+                header::HeaderName::from_static("x-csrf-token"),
+                header::HeaderName::from_static("x-token-delivery")
                 ])
             .supports_credentials();
         App::new()
@@ -88,10 +239,21 @@ async fn main() -> std::io::Result<()> {
                 env: config.clone(),
                 redis_client: redis_client.clone(),
                 ldap_pool: pool.clone(),
+                identity: identity.clone(),
+                db_pool: db_pool.clone(),
+                user_sync: user_sync.clone(),
+                webauthn: webauthn.clone(),
+                mailer: mailer.clone(),
+                audit: audit.clone(),
             }))
             .configure(|cfg| {
                 user_handler::config(cfg);
                 auth_handler::config(cfg);
+                health_handler::config(cfg);
+                group_handler::config(cfg);
+                sync_handler::config(cfg);
+                pat_handler::config(cfg);
+                audit_handler::config(cfg);
             })
             .wrap(cors)
             .wrap(Logger::default())
@@ -0,0 +1,375 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::identity_store::{Identity, IdentityError, IdentityPage, IdentityStore, NewIdentity, Profile};
+use crate::paging_service::UserFilter;
+use crate::user_service::{hash_password, verify_password};
+use crate::user_model::UpdateUserSchema;
+
+struct MemoryUser {
+    email: String,
+    password: String,
+    groups: Vec<String>,
+    disabled: bool,
+    disabled_at: Option<DateTime<Utc>>,
+    disabled_reason: Option<String>,
+    verified: bool,
+    profile: Profile,
+}
+
+// Keeps accounts in process memory, for tests and local development.
+// Every worker shares the same store, but nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryIdentityStore {
+    users: Mutex<HashMap<u64, MemoryUser>>,
+}
+
+impl MemoryIdentityStore {
+    pub fn new() -> Self {
+        MemoryIdentityStore::default()
+    }
+}
+
+fn to_identity(user_id: u64, user: &MemoryUser) -> Identity {
+    Identity {
+        user_id,
+        email: user.email.clone(),
+        groups: user.groups.clone(),
+        disabled: user.disabled,
+        disabled_at: user.disabled_at,
+        disabled_reason: user.disabled_reason.clone(),
+        verified: user.verified,
+        profile: user.profile.clone(),
+    }
+}
+
+#[async_trait]
+impl IdentityStore for MemoryIdentityStore {
+    async fn ping(&self) -> Result<(), IdentityError> {
+        Ok(())
+    }
+
+    async fn find_by_id(&self, user_id: u64) -> Result<Option<Identity>, IdentityError> {
+        let users = self.users.lock().unwrap();
+        Ok(users.get(&user_id).map(|user| to_identity(user_id, user)))
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<Identity>, IdentityError> {
+        let users = self.users.lock().unwrap();
+        Ok(users
+            .iter()
+            .find(|(_, user)| user.email.eq_ignore_ascii_case(email))
+            .map(|(user_id, user)| to_identity(*user_id, user)))
+    }
+
+    async fn list_all(&self) -> Result<Vec<Identity>, IdentityError> {
+        let users = self.users.lock().unwrap();
+        let mut identities: Vec<Identity> = users
+            .iter()
+            .map(|(user_id, user)| Identity { groups: Vec::new(), ..to_identity(*user_id, user) })
+            .collect();
+        identities.sort_by_key(|identity| identity.user_id);
+        Ok(identities)
+    }
+
+    async fn list_disabled(&self) -> Result<Vec<Identity>, IdentityError> {
+        let users = self.users.lock().unwrap();
+        let mut identities: Vec<Identity> = users
+            .iter()
+            .filter(|(_, user)| user.disabled)
+            .map(|(user_id, user)| Identity { groups: Vec::new(), ..to_identity(*user_id, user) })
+            .collect();
+        identities.sort_by_key(|identity| identity.user_id);
+        Ok(identities)
+    }
+
+    async fn list_page(&self, filter: &UserFilter, after: u64, limit: usize) -> Result<IdentityPage, IdentityError> {
+        let users = self.users.lock().unwrap();
+        let mut matching: Vec<Identity> = users
+            .iter()
+            .filter(|(user_id, user)| filter.matches(**user_id, &user.email, &user.groups))
+            .map(|(user_id, user)| Identity { groups: Vec::new(), ..to_identity(*user_id, user) })
+            .collect();
+        matching.sort_by_key(|identity| identity.user_id);
+        let total = matching.len();
+        let identities = matching.into_iter().filter(|identity| identity.user_id > after).take(limit).collect();
+        Ok(IdentityPage { identities, total })
+    }
+
+    async fn authenticate(&self, login: &str, password: &str) -> Result<Identity, IdentityError> {
+        let users = self.users.lock().unwrap();
+        let (user_id, user) = match users.iter().find(|(_, user)| user.email.eq_ignore_ascii_case(login)) {
+            Some(found) => found,
+            None => return Err(IdentityError::NotFound),
+        };
+        if !verify_password(password, &user.password) {
+            return Err(IdentityError::InvalidCredentials);
+        }
+        Ok(to_identity(*user_id, user))
+    }
+
+    async fn create(&self, new_identity: NewIdentity<'_>) -> Result<Identity, IdentityError> {
+        let password = hash_password(new_identity.password)?;
+        let mut users = self.users.lock().unwrap();
+        let taken = users.contains_key(&new_identity.user_id)
+            || users.values().any(|user| user.email.eq_ignore_ascii_case(new_identity.email));
+        if taken {
+            return Err(IdentityError::Conflict("User with that email or uid already exists".to_string()));
+        }
+        let user = MemoryUser {
+            email: new_identity.email.to_string(),
+            password,
+            groups: Vec::new(),
+            disabled: new_identity.disabled,
+            disabled_at: new_identity.disabled.then(Utc::now),
+            disabled_reason: None,
+            verified: new_identity.verified,
+            profile: Profile::default(),
+        };
+        let identity = to_identity(new_identity.user_id, &user);
+        users.insert(new_identity.user_id, user);
+        Ok(identity)
+    }
+
+    async fn delete(&self, user_id: u64) -> Result<(), IdentityError> {
+        let mut users = self.users.lock().unwrap();
+        match users.remove(&user_id) {
+            Some(_) => Ok(()),
+            None => Err(IdentityError::NotFound),
+        }
+    }
+
+    async fn set_disabled(&self, user_id: u64, disabled: bool) -> Result<(), IdentityError> {
+        let mut users = self.users.lock().unwrap();
+        match users.get_mut(&user_id) {
+            Some(user) => {
+                user.disabled = disabled;
+                if !disabled {
+                    user.disabled_at = None;
+                    user.disabled_reason = None;
+                } else if user.disabled_at.is_none() {
+                    user.disabled_at = Some(Utc::now());
+                }
+                Ok(())
+            }
+            None => Err(IdentityError::NotFound),
+        }
+    }
+
+    async fn disable(&self, user_id: u64, reason: &str) -> Result<Identity, IdentityError> {
+        let mut users = self.users.lock().unwrap();
+        match users.get_mut(&user_id) {
+            Some(user) => {
+                user.disabled = true;
+                user.disabled_at = Some(Utc::now());
+                user.disabled_reason = Some(reason.to_string());
+                Ok(to_identity(user_id, user))
+            }
+            None => Err(IdentityError::NotFound),
+        }
+    }
+
+    async fn set_verified(&self, user_id: u64, verified: bool) -> Result<(), IdentityError> {
+        let mut users = self.users.lock().unwrap();
+        match users.get_mut(&user_id) {
+            Some(user) => {
+                user.verified = verified;
+                Ok(())
+            }
+            None => Err(IdentityError::NotFound),
+        }
+    }
+
+    async fn update(&self, user_id: u64, changes: &UpdateUserSchema) -> Result<Identity, IdentityError> {
+        let mut users = self.users.lock().unwrap();
+        if let Some(email) = &changes.mail {
+            let taken = users
+                .iter()
+                .any(|(id, user)| *id != user_id && user.email.eq_ignore_ascii_case(email));
+            if taken {
+                return Err(IdentityError::Conflict("User with that email already exists".to_string()));
+            }
+        }
+        let user = match users.get_mut(&user_id) {
+            Some(user) => user,
+            None => return Err(IdentityError::NotFound),
+        };
+        if let Some(email) = &changes.mail {
+            if !user.email.eq_ignore_ascii_case(email) {
+                user.verified = false;
+            }
+            user.email = email.clone();
+        }
+        user.profile.apply(changes);
+        Ok(to_identity(user_id, user))
+    }
+
+    async fn group_members(&self, group: &str) -> Result<Option<Vec<u64>>, IdentityError> {
+        let users = self.users.lock().unwrap();
+        let mut members: Vec<u64> = users
+            .iter()
+            .filter(|(_, user)| user.groups.iter().any(|name| name == group))
+            .map(|(user_id, _)| *user_id)
+            .collect();
+        if members.is_empty() {
+            return Ok(None);
+        }
+        members.sort_unstable();
+        Ok(Some(members))
+    }
+
+    async fn create_group(&self, group: &str, _description: Option<&str>, members: &[u64]) -> Result<(), IdentityError> {
+        let mut users = self.users.lock().unwrap();
+        if users.values().any(|user| user.groups.iter().any(|name| name == group)) {
+            return Err(IdentityError::Conflict("Group with that name already exists".to_string()));
+        }
+        if members.iter().any(|user_id| !users.contains_key(user_id)) {
+            return Err(IdentityError::NotFound);
+        }
+        for user_id in members {
+            if let Some(user) = users.get_mut(user_id) {
+                user.groups.push(group.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    async fn delete_group(&self, group: &str) -> Result<Vec<u64>, IdentityError> {
+        let mut users = self.users.lock().unwrap();
+        let mut members = Vec::new();
+        for (user_id, user) in users.iter_mut() {
+            if user.groups.iter().any(|name| name == group) {
+                user.groups.retain(|name| name != group);
+                members.push(*user_id);
+            }
+        }
+        if members.is_empty() {
+            return Err(IdentityError::NotFound);
+        }
+        members.sort_unstable();
+        Ok(members)
+    }
+
+    async fn set_group_member(&self, user_id: u64, group: &str, member: bool) -> Result<(), IdentityError> {
+        let mut users = self.users.lock().unwrap();
+        let user = match users.get_mut(&user_id) {
+            Some(user) => user,
+            None => return Err(IdentityError::NotFound),
+        };
+        user.groups.retain(|name| name != group);
+        if member {
+            user.groups.push(group.to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn store_with_user() -> MemoryIdentityStore {
+        let store = MemoryIdentityStore::new();
+        store
+            .create(NewIdentity { user_id: 7, email: "someone@example.com", password: "secret", verified: true, disabled: false })
+            .await
+            .unwrap();
+        store
+    }
+
+    #[actix_web::test]
+    async fn authenticates_by_email_and_password() {
+        let store = store_with_user().await;
+        assert_eq!(store.authenticate("Someone@example.com", "secret").await.unwrap().user_id, 7);
+        assert!(matches!(store.authenticate("someone@example.com", "wrong").await, Err(IdentityError::InvalidCredentials)));
+        assert!(matches!(store.authenticate("nobody@example.com", "secret").await, Err(IdentityError::NotFound)));
+    }
+
+    #[actix_web::test]
+    async fn keeps_disabled_accounts() {
+        let store = store_with_user().await;
+        store.set_disabled(7, true).await.unwrap();
+        assert!(store.find_by_id(7).await.unwrap().unwrap().disabled);
+        store.set_disabled(7, false).await.unwrap();
+        assert!(!store.find_by_id(7).await.unwrap().unwrap().disabled);
+        assert!(matches!(store.set_disabled(8, true).await, Err(IdentityError::NotFound)));
+    }
+
+    #[actix_web::test]
+    async fn keeps_why_and_when_accounts_were_disabled() {
+        let store = store_with_user().await;
+        let disabled = store.disable(7, "left the company").await.unwrap();
+        let stored = store.find_by_id(7).await.unwrap().unwrap();
+        assert_eq!(stored.disabled_reason.as_deref(), Some("left the company"));
+        assert_eq!(stored.disabled_at, disabled.disabled_at);
+        assert_eq!(store.list_disabled().await.unwrap().len(), 1);
+
+        store.set_disabled(7, false).await.unwrap();
+        let stored = store.find_by_id(7).await.unwrap().unwrap();
+        assert!(stored.disabled_at.is_none() && stored.disabled_reason.is_none());
+        assert!(store.list_disabled().await.unwrap().is_empty());
+        assert!(matches!(store.disable(8, "typo").await, Err(IdentityError::NotFound)));
+    }
+
+    #[actix_web::test]
+    async fn a_new_address_needs_verifying() {
+        let store = store_with_user().await;
+        let same = UpdateUserSchema { mail: Some("Someone@example.com".to_string()), ..UpdateUserSchema::default() };
+        assert!(store.update(7, &same).await.unwrap().verified);
+        let other = UpdateUserSchema { mail: Some("someone@example.org".to_string()), ..UpdateUserSchema::default() };
+        assert!(!store.update(7, &other).await.unwrap().verified);
+        store.set_verified(7, true).await.unwrap();
+        assert!(store.find_by_id(7).await.unwrap().unwrap().verified);
+    }
+
+    #[actix_web::test]
+    async fn keeps_profile_attributes() {
+        let store = store_with_user().await;
+        let changes = UpdateUserSchema { sn: Some("Doe".to_string()), ..UpdateUserSchema::default() };
+        store.update(7, &changes).await.unwrap();
+        let changes = UpdateUserSchema { given_name: Some("Jane".to_string()), ..UpdateUserSchema::default() };
+        let profile = store.update(7, &changes).await.unwrap().profile;
+        assert_eq!(profile.sn.as_deref(), Some("Doe"));
+        assert_eq!(profile.given_name.as_deref(), Some("Jane"));
+        assert_eq!(store.find_by_id(7).await.unwrap().unwrap().profile, profile);
+    }
+
+    #[actix_web::test]
+    async fn keeps_group_memberships() {
+        let store = store_with_user().await;
+        store.set_group_member(7, "admins", true).await.unwrap();
+        store.set_group_member(7, "admins", true).await.unwrap();
+        assert_eq!(store.find_by_id(7).await.unwrap().unwrap().groups, vec!["admins".to_string()]);
+        store.set_group_member(7, "admins", false).await.unwrap();
+        assert!(store.find_by_id(7).await.unwrap().unwrap().groups.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn lists_pages_in_user_id_order() {
+        let store = store_with_user().await;
+        for (user_id, email) in [(3, "third@example.com"), (9, "ninth@example.com"), (5, "fifth@example.com")] {
+            store
+                .create(NewIdentity { user_id, email, password: "secret", verified: true, disabled: false })
+                .await
+                .unwrap();
+        }
+        store.set_group_member(9, "staff", true).await.unwrap();
+
+        let page = store.list_page(&UserFilter::default(), 0, 2).await.unwrap();
+        let ids: Vec<u64> = page.identities.iter().map(|identity| identity.user_id).collect();
+        assert_eq!((ids, page.total), (vec![3, 5], 4));
+        let page = store.list_page(&UserFilter::default(), 5, 2).await.unwrap();
+        let ids: Vec<u64> = page.identities.iter().map(|identity| identity.user_id).collect();
+        assert_eq!(ids, vec![7, 9]);
+
+        let staff = UserFilter { group: Some("staff".to_string()), ..UserFilter::default() };
+        let page = store.list_page(&staff, 0, 10).await.unwrap();
+        assert_eq!(page.identities.len(), 1);
+        assert_eq!(page.identities[0].user_id, 9);
+        assert_eq!(store.group_members("staff").await.unwrap(), Some(vec![9]));
+        assert_eq!(store.delete_group("staff").await.unwrap(), vec![9]);
+        assert_eq!(store.group_members("staff").await.unwrap(), None);
+    }
+}
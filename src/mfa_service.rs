@@ -0,0 +1,523 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use redis::AsyncCommands;
+use serde::Deserialize;
+use sha1::Sha1;
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::user_service::{hash_password, verify_password};
+
+// RFC 6238 defaults, which every authenticator app supports.
+const TOTP_STEP_SECS: u64 = 30;
+const TOTP_DIGITS: u32 = 6;
+// accept codes one step either side of now to absorb clock drift
+const TOTP_WINDOW: u64 = 1;
+const SECRET_BYTES: usize = 20;
+const NONCE_BYTES: usize = 12;
+const PENDING_ENROLLMENT_SECS: i64 = 600;
+const MAX_CHALLENGE_ATTEMPTS: u32 = 5;
+// wrong codes across all challenges of a user, so fresh logins buy no extra guesses
+const MAX_USER_ATTEMPTS: u32 = 10;
+const USER_ATTEMPTS_WINDOW_SECS: usize = 900;
+// every recovery code attempt runs up to RECOVERY_CODE_COUNT argon2 checks
+const MAX_RECOVERY_ATTEMPTS: u32 = 5;
+const RECOVERY_ATTEMPTS_WINDOW_SECS: usize = 900;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10;
+// 32 symbols, so a random byte maps onto it without bias
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+#[derive(Debug)]
+pub enum MfaError {
+    NotConfigured,
+    NotEnrolled,
+    AlreadyEnabled,
+    InvalidCode,
+    InvalidChallenge,
+    // the challenge of this user was dropped after too many wrong codes
+    TooManyAttempts(u64),
+    RedisError(redis::RedisError),
+    DatabaseError(sqlx::Error),
+    CryptoError(String),
+}
+
+impl From<redis::RedisError> for MfaError {
+    fn from(err: redis::RedisError) -> Self {
+        MfaError::RedisError(err)
+    }
+}
+
+impl From<sqlx::Error> for MfaError {
+    fn from(err: sqlx::Error) -> Self {
+        MfaError::DatabaseError(err)
+    }
+}
+
+// Per-user TOTP state, a row of `mfa_credentials`. The secret is AES-256-GCM
+// encrypted with MFA_ENCRYPTION_KEY and bound to the uid. Only the short-lived
+// login challenges live in Redis.
+#[derive(Debug, sqlx::FromRow)]
+struct MfaRecord {
+    secret: String,
+    enabled: bool,
+    // last accepted time step, so a code cannot be replayed
+    last_step: i64,
+    // argon2 hashes of the unused recovery codes
+    recovery_codes: Vec<String>,
+    created_at: DateTime<Utc>,
+}
+
+// Records of earlier versions, which kept them in Redis under "mfa:{uid}".
+#[derive(Debug, Deserialize)]
+struct LegacyMfaRecord {
+    secret: String,
+    enabled: bool,
+    #[serde(default)]
+    last_step: u64,
+    #[serde(default)]
+    recovery_codes: Vec<String>,
+}
+
+const LEGACY_RECORD_PATTERN: &str = "mfa:*";
+
+fn challenge_key(challenge: &str) -> String {
+    format!("mfa_challenge:{}", challenge)
+}
+
+fn attempts_key(challenge: &str) -> String {
+    format!("mfa_challenge_attempts:{}", challenge)
+}
+
+fn user_attempts_key(uid: u64) -> String {
+    format!("mfa_attempts:{}", uid)
+}
+
+fn recovery_attempts_key(uid: u64) -> String {
+    format!("mfa_recovery_attempts:{}", uid)
+}
+
+fn pool(db: Option<&Pool<Postgres>>) -> Result<&Pool<Postgres>, MfaError> {
+    db.ok_or(MfaError::NotConfigured)
+}
+
+fn cipher(config: &Config) -> Result<Aes256Gcm, MfaError> {
+    let key = config.mfa_encryption_key.as_ref().ok_or(MfaError::NotConfigured)?;
+    Aes256Gcm::new_from_slice(key).map_err(|err| MfaError::CryptoError(err.to_string()))
+}
+
+fn encrypt_secret(config: &Config, uid: u64, secret: &[u8]) -> Result<String, MfaError> {
+    let mut nonce = [0u8; NONCE_BYTES];
+    OsRng.fill_bytes(&mut nonce);
+    let aad = uid.to_string();
+    let ciphertext = cipher(config)?
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: secret, aad: aad.as_bytes() })
+        .map_err(|err| MfaError::CryptoError(err.to_string()))?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Ok(general_purpose::STANDARD.encode(sealed))
+}
+
+fn decrypt_secret(config: &Config, uid: u64, sealed: &str) -> Result<Vec<u8>, MfaError> {
+    let sealed = general_purpose::STANDARD
+        .decode(sealed)
+        .map_err(|err| MfaError::CryptoError(err.to_string()))?;
+    if sealed.len() <= NONCE_BYTES {
+        return Err(MfaError::CryptoError("stored secret is truncated".to_string()));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_BYTES);
+    let aad = uid.to_string();
+    cipher(config)?
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: aad.as_bytes() })
+        .map_err(|err| MfaError::CryptoError(err.to_string()))
+}
+
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]]) & 0x7fff_ffff;
+    binary % 10u32.pow(TOTP_DIGITS)
+}
+
+// Returns the time step `code` belongs to, if it is valid and newer than `last_step`.
+fn verify_totp(secret: &[u8], code: &str, last_step: u64) -> Option<u64> {
+    let now = Utc::now().timestamp().max(0) as u64 / TOTP_STEP_SECS;
+    verify_totp_at(secret, code, last_step, now)
+}
+
+fn verify_totp_at(secret: &[u8], code: &str, last_step: u64, now: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != TOTP_DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code = code.parse::<u32>().ok()?;
+    (now.saturating_sub(TOTP_WINDOW)..=now + TOTP_WINDOW)
+        .filter(|step| *step > last_step)
+        .find(|step| hotp(secret, *step) == code)
+}
+
+fn otpauth_uri(issuer: &str, account: &str, secret: &str) -> String {
+    let encode = |value: &str| -> String {
+        value
+            .bytes()
+            .map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'@' => (byte as char).to_string(),
+                _ => format!("%{:02X}", byte),
+            })
+            .collect()
+    };
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        encode(issuer), encode(account), secret, encode(issuer), TOTP_DIGITS, TOTP_STEP_SECS
+    )
+}
+
+async fn load_record(db: &Pool<Postgres>, uid: u64) -> Result<Option<MfaRecord>, MfaError> {
+    let record = sqlx::query_as::<_, MfaRecord>(
+        "SELECT secret, enabled, last_step, recovery_codes, created_at FROM mfa_credentials WHERE user_id = $1",
+    )
+    .bind(uid as i64)
+    .fetch_optional(db)
+    .await?;
+    // unconfirmed enrollments lapse if nobody confirms them
+    let pending_since = Utc::now() - Duration::seconds(PENDING_ENROLLMENT_SECS);
+    Ok(record.filter(|record| record.enabled || record.created_at > pending_since))
+}
+
+pub async fn is_enabled(db: Option<&Pool<Postgres>>, uid: u64) -> Result<bool, MfaError> {
+    let db = match db {
+        Some(db) => db,
+        None => return Ok(false),
+    };
+    Ok(load_record(db, uid).await?.map(|record| record.enabled).unwrap_or(false))
+}
+
+// Creates a new pending secret and returns it base32-encoded with its otpauth:// URI.
+pub async fn start_enrollment(
+    db: Option<&Pool<Postgres>>,
+    config: &Config,
+    uid: u64,
+    account: &str,
+) -> Result<(String, String), MfaError> {
+    let db = pool(db)?;
+    let mut secret = [0u8; SECRET_BYTES];
+    OsRng.fill_bytes(&mut secret);
+    // replaces a pending enrollment, never an enabled one
+    let stored = sqlx::query(
+        "INSERT INTO mfa_credentials (user_id, secret) VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE SET
+            secret = EXCLUDED.secret, last_step = 0, recovery_codes = '{}', created_at = NOW()
+        WHERE mfa_credentials.enabled = FALSE",
+    )
+    .bind(uid as i64)
+    .bind(encrypt_secret(config, uid, &secret)?)
+    .execute(db)
+    .await?
+    .rows_affected();
+    if stored == 0 {
+        return Err(MfaError::AlreadyEnabled);
+    }
+
+    let encoded = data_encoding::BASE32_NOPAD.encode(&secret);
+    let uri = otpauth_uri(&config.mfa_issuer, account, &encoded);
+    Ok((encoded, uri))
+}
+
+// Checks `code` against the user's secret and claims its time step.
+async fn accept_code(
+    db: &Pool<Postgres>,
+    config: &Config,
+    uid: u64,
+    code: &str,
+    want_enabled: bool,
+) -> Result<MfaRecord, MfaError> {
+    let record = match load_record(db, uid).await? {
+        Some(record) if record.enabled == want_enabled => record,
+        Some(_) if !want_enabled => return Err(MfaError::AlreadyEnabled),
+        _ => return Err(MfaError::NotEnrolled),
+    };
+    let secret = decrypt_secret(config, uid, &record.secret)?;
+    let step = verify_totp(&secret, code, record.last_step.max(0) as u64).ok_or(MfaError::InvalidCode)?;
+    // the step is checked again as it is stored, so of two requests racing
+    // with the same code only one gets through
+    let claimed = sqlx::query(
+        "UPDATE mfa_credentials SET last_step = $2 WHERE user_id = $1 AND enabled = $3 AND last_step < $2",
+    )
+    .bind(uid as i64)
+    .bind(step as i64)
+    .bind(want_enabled)
+    .execute(db)
+    .await?
+    .rows_affected();
+    if claimed == 0 {
+        return Err(MfaError::InvalidCode);
+    }
+    Ok(record)
+}
+
+// Returns the recovery codes to show once alongside their argon2 hashes.
+fn generate_recovery_codes() -> Result<(Vec<String>, Vec<String>), MfaError> {
+    let mut codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    let mut hashes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let mut bytes = [0u8; RECOVERY_CODE_LEN];
+        OsRng.fill_bytes(&mut bytes);
+        let code: String = bytes
+            .iter()
+            .map(|byte| RECOVERY_CODE_ALPHABET[*byte as usize % RECOVERY_CODE_ALPHABET.len()] as char)
+            .collect();
+        hashes.push(hash_password(&code).map_err(|err| MfaError::CryptoError(format!("{:?}", err)))?);
+        codes.push(format!("{}-{}", &code[..RECOVERY_CODE_LEN / 2], &code[RECOVERY_CODE_LEN / 2..]));
+    }
+    Ok((codes, hashes))
+}
+
+// Users may type recovery codes without the dash or in upper case.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+// Enables 2FA and returns the first set of recovery codes.
+pub async fn confirm_enrollment(db: Option<&Pool<Postgres>>, config: &Config, uid: u64, code: &str) -> Result<Vec<String>, MfaError> {
+    let db = pool(db)?;
+    accept_code(db, config, uid, code, false).await?;
+    let (codes, hashes) = generate_recovery_codes()?;
+    let enabled = sqlx::query("UPDATE mfa_credentials SET enabled = TRUE, recovery_codes = $2 WHERE user_id = $1 AND enabled = FALSE")
+        .bind(uid as i64)
+        .bind(hashes)
+        .execute(db)
+        .await?
+        .rows_affected();
+    if enabled == 0 {
+        return Err(MfaError::AlreadyEnabled);
+    }
+    Ok(codes)
+}
+
+pub async fn verify_code(db: Option<&Pool<Postgres>>, config: &Config, uid: u64, code: &str) -> Result<(), MfaError> {
+    accept_code(pool(db)?, config, uid, code, true).await?;
+    Ok(())
+}
+
+// Replaces every recovery code, which takes a current TOTP code.
+pub async fn regenerate_recovery_codes(db: Option<&Pool<Postgres>>, config: &Config, uid: u64, code: &str) -> Result<Vec<String>, MfaError> {
+    let db = pool(db)?;
+    accept_code(db, config, uid, code, true).await?;
+    let (codes, hashes) = generate_recovery_codes()?;
+    sqlx::query("UPDATE mfa_credentials SET recovery_codes = $2 WHERE user_id = $1")
+        .bind(uid as i64)
+        .bind(hashes)
+        .execute(db)
+        .await?;
+    Ok(codes)
+}
+
+// Consumes a recovery code in place of a TOTP code and returns how many are left.
+pub async fn use_recovery_code(
+    db: Option<&Pool<Postgres>>,
+    redis_client: &redis::Client,
+    uid: u64,
+    code: &str,
+) -> Result<usize, MfaError> {
+    let db = pool(db)?;
+    let mut conn = redis_client.get_async_connection().await?;
+    if count_attempt(&mut conn, &recovery_attempts_key(uid), RECOVERY_ATTEMPTS_WINDOW_SECS).await? > MAX_RECOVERY_ATTEMPTS {
+        return Err(MfaError::TooManyAttempts(uid));
+    }
+    let record = match load_record(db, uid).await? {
+        Some(record) if record.enabled => record,
+        _ => return Err(MfaError::NotEnrolled),
+    };
+    let code = normalize_recovery_code(code);
+    let hash = record
+        .recovery_codes
+        .into_iter()
+        .find(|hash| verify_password(&code, hash))
+        .ok_or(MfaError::InvalidCode)?;
+    // removing the hash only if it is still there lets a code be spent once,
+    // however many requests race with it
+    let remaining: Option<i32> = sqlx::query_scalar(
+        "UPDATE mfa_credentials SET recovery_codes = array_remove(recovery_codes, $2)
+        WHERE user_id = $1 AND enabled = TRUE AND $2 = ANY(recovery_codes)
+        RETURNING cardinality(recovery_codes)",
+    )
+    .bind(uid as i64)
+    .bind(hash)
+    .fetch_optional(db)
+    .await?;
+    remaining.map(|remaining| remaining as usize).ok_or(MfaError::InvalidCode)
+}
+
+// Drops the 2FA state of a user, when 2FA is disabled or the account deleted.
+pub async fn forget_user(db: Option<&Pool<Postgres>>, uid: u64) -> Result<(), MfaError> {
+    let db = match db {
+        Some(db) => db,
+        None => return Ok(()),
+    };
+    sqlx::query("DELETE FROM mfa_credentials WHERE user_id = $1")
+        .bind(uid as i64)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+// Moves enabled 2FA records left in Redis by earlier versions into the
+// database, so upgrading does not switch 2FA off. Returns how many moved.
+pub async fn import_legacy_records(db: &Pool<Postgres>, redis_client: &redis::Client) -> Result<usize, MfaError> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let keys: Vec<String> = {
+        let mut iter = conn.scan_match::<_, String>(LEGACY_RECORD_PATTERN).await?;
+        let mut keys = Vec::new();
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        keys
+    };
+    let mut imported = 0;
+    for key in keys {
+        let uid = match key.strip_prefix("mfa:").and_then(|uid| uid.parse::<u64>().ok()) {
+            Some(uid) => uid,
+            None => continue,
+        };
+        let value: Option<String> = conn.get(&key).await?;
+        let record = match value.and_then(|value| serde_json::from_str::<LegacyMfaRecord>(&value).ok()) {
+            Some(record) => record,
+            None => continue,
+        };
+        // pending enrollments are simply started again
+        if record.enabled {
+            sqlx::query(
+                "INSERT INTO mfa_credentials (user_id, secret, enabled, last_step, recovery_codes)
+                VALUES ($1, $2, TRUE, $3, $4) ON CONFLICT (user_id) DO NOTHING",
+            )
+            .bind(uid as i64)
+            .bind(record.secret)
+            .bind(record.last_step as i64)
+            .bind(record.recovery_codes)
+            .execute(db)
+            .await?;
+            imported += 1;
+        }
+        let _: () = conn.del(&key).await?;
+    }
+    Ok(imported)
+}
+
+// Issues the opaque token a client trades, with a code, for access and refresh tokens.
+pub async fn create_challenge(redis_client: &redis::Client, config: &Config, uid: u64) -> Result<String, MfaError> {
+    let challenge = Uuid::new_v4().to_string();
+    let mut conn = redis_client.get_async_connection().await?;
+    let _: () = conn
+        .set_ex(challenge_key(&challenge), uid.to_string(), config.mfa_challenge_max_age_secs)
+        .await?;
+    Ok(challenge)
+}
+
+// Counts an attempt under `key`. The counter is created with its TTL in one
+// command, so it cannot be left behind without one.
+async fn count_attempt(conn: &mut redis::aio::Connection, key: &str, ttl: usize) -> Result<u32, MfaError> {
+    let _: Option<String> = redis::cmd("SET").arg(key).arg(0).arg("NX").arg("EX").arg(ttl).query_async(conn).await?;
+    let attempts: u32 = conn.incr(key, 1).await?;
+    Ok(attempts)
+}
+
+// Resolves a challenge to its user; the challenge is dropped after too many
+// attempts and consumed by `finish_challenge` once a code has been accepted.
+pub async fn challenge_user(redis_client: &redis::Client, config: &Config, challenge: &str) -> Result<u64, MfaError> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let uid: Option<String> = conn.get(challenge_key(challenge)).await?;
+    let uid = match uid.and_then(|uid| uid.parse::<u64>().ok()) {
+        Some(uid) => uid,
+        None => return Err(MfaError::InvalidChallenge),
+    };
+    let attempts = count_attempt(&mut conn, &attempts_key(challenge), config.mfa_challenge_max_age_secs).await?;
+    let user_attempts = count_attempt(&mut conn, &user_attempts_key(uid), USER_ATTEMPTS_WINDOW_SECS).await?;
+    if attempts > MAX_CHALLENGE_ATTEMPTS || user_attempts > MAX_USER_ATTEMPTS {
+        finish_challenge(redis_client, challenge).await?;
+        return Err(MfaError::TooManyAttempts(uid));
+    }
+    Ok(uid)
+}
+
+pub async fn finish_challenge(redis_client: &redis::Client, challenge: &str) -> Result<(), MfaError> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let _: () = conn.del(&[challenge_key(challenge), attempts_key(challenge)]).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn step_at(unix_time: u64) -> u64 {
+        unix_time / TOTP_STEP_SECS
+    }
+
+    #[test]
+    fn hotp_matches_rfc_4226() {
+        let expected = [755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(RFC_SECRET, counter as u64), *code, "counter {}", counter);
+        }
+    }
+
+    // RFC 6238 Appendix B, SHA-1, truncated to our 6 digits
+    #[test]
+    fn totp_matches_rfc_6238() {
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+        for (unix_time, code) in vectors {
+            let step = step_at(unix_time);
+            assert_eq!(format!("{:06}", hotp(RFC_SECRET, step)), code, "T = {}", unix_time);
+            assert_eq!(verify_totp_at(RFC_SECRET, code, 0, step), Some(step), "T = {}", unix_time);
+        }
+    }
+
+    #[test]
+    fn accepts_one_step_of_drift() {
+        let now = step_at(1234567890);
+        let code = |step: u64| format!("{:06}", hotp(RFC_SECRET, step));
+        assert_eq!(verify_totp_at(RFC_SECRET, &code(now - 1), 0, now), Some(now - 1));
+        assert_eq!(verify_totp_at(RFC_SECRET, &code(now + 1), 0, now), Some(now + 1));
+        assert_eq!(verify_totp_at(RFC_SECRET, &code(now - 2), 0, now), None);
+        assert_eq!(verify_totp_at(RFC_SECRET, &code(now + 2), 0, now), None);
+    }
+
+    #[test]
+    fn rejects_replayed_codes() {
+        let now = step_at(1234567890);
+        let code = format!("{:06}", hotp(RFC_SECRET, now));
+        assert_eq!(verify_totp_at(RFC_SECRET, &code, now, now), None);
+        assert_eq!(verify_totp_at(RFC_SECRET, &code, now + 1, now), None);
+        // an older code stays dead once a newer step was accepted
+        let previous = format!("{:06}", hotp(RFC_SECRET, now - 1));
+        assert_eq!(verify_totp_at(RFC_SECRET, &previous, now, now), None);
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        let now = step_at(59);
+        assert_eq!(verify_totp_at(RFC_SECRET, " 287082 ", 0, now), Some(now));
+        assert_eq!(verify_totp_at(RFC_SECRET, "28708", 0, now), None);
+        assert_eq!(verify_totp_at(RFC_SECRET, "2870820", 0, now), None);
+        assert_eq!(verify_totp_at(RFC_SECRET, "28708a", 0, now), None);
+    }
+}
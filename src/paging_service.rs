@@ -0,0 +1,97 @@
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 500;
+
+// What a user listing is narrowed to.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserFilter {
+    pub group: Option<String>,
+    // matches addresses starting with it, ignoring case
+    pub email: Option<String>,
+    pub uid: Option<u64>,
+}
+
+impl UserFilter {
+    // For backends that filter in process; the ldap and postgres stores build
+    // the same conditions into their queries.
+    pub fn matches(&self, user_id: u64, email: &str, groups: &[String]) -> bool {
+        let email_matches = self.email.as_deref().map_or(true, |prefix| {
+            email.len() >= prefix.len()
+                && email.is_char_boundary(prefix.len())
+                && email[..prefix.len()].eq_ignore_ascii_case(prefix)
+        });
+        email_matches
+            && self.uid.map_or(true, |uid| uid == user_id)
+            && self.group.as_ref().map_or(true, |group| groups.contains(group))
+    }
+}
+
+// Listings page over the accounts ordered by user id and continue after the
+// last one returned. The cursor carries the filter and that position, so any
+// instance can continue a listing another one started.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserCursor {
+    pub filter: UserFilter,
+    pub after: u64,
+    pub limit: usize,
+}
+
+impl UserCursor {
+    // The first page of a listing, with the page size clamped to MAX_PAGE_SIZE.
+    pub fn first(filter: UserFilter, limit: Option<i32>) -> Self {
+        let limit = limit.map_or(DEFAULT_PAGE_SIZE, |limit| limit.clamp(1, MAX_PAGE_SIZE as i32) as usize);
+        UserCursor { filter, after: 0, limit }
+    }
+
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap();
+        general_purpose::URL_SAFE_NO_PAD.encode(json)
+    }
+
+    // None when `cursor` is not a valid cursor. The page size comes from the
+    // client, so it is clamped again.
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let json = general_purpose::URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        let cursor: UserCursor = serde_json::from_slice(&json).ok()?;
+        Some(UserCursor { limit: cursor.limit.clamp(1, MAX_PAGE_SIZE), ..cursor })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursors_round_trip() {
+        let filter = UserFilter { group: Some("staff".to_string()), email: Some("jane".to_string()), uid: None };
+        let cursor = UserCursor { after: 42, ..UserCursor::first(filter, Some(20)) };
+        assert_eq!(UserCursor::decode(&cursor.encode()), Some(cursor));
+    }
+
+    #[test]
+    fn page_sizes_are_clamped() {
+        assert_eq!(UserCursor::first(UserFilter::default(), None).limit, DEFAULT_PAGE_SIZE);
+        assert_eq!(UserCursor::first(UserFilter::default(), Some(-1)).limit, 1);
+        assert_eq!(UserCursor::first(UserFilter::default(), Some(100_000)).limit, MAX_PAGE_SIZE);
+
+        let forged = UserCursor { filter: UserFilter::default(), after: 0, limit: usize::MAX };
+        assert_eq!(UserCursor::decode(&forged.encode()).unwrap().limit, MAX_PAGE_SIZE);
+        let forged = general_purpose::URL_SAFE_NO_PAD.encode(r#"{"filter":{"group":null,"email":null,"uid":null},"after":0,"limit":-1}"#);
+        assert_eq!(UserCursor::decode(&forged), None);
+        assert_eq!(UserCursor::decode("not a cursor"), None);
+    }
+
+    #[test]
+    fn filters_match_prefix_uid_and_group() {
+        let groups = vec!["staff".to_string()];
+        let filter = UserFilter { email: Some("JANE".to_string()), ..UserFilter::default() };
+        assert!(filter.matches(1, "jane@example.com", &[]));
+        assert!(!filter.matches(1, "ja", &[]));
+        let filter = UserFilter { group: Some("staff".to_string()), uid: Some(2), email: None };
+        assert!(filter.matches(2, "x@example.com", &groups));
+        assert!(!filter.matches(3, "x@example.com", &groups));
+        assert!(!filter.matches(2, "x@example.com", &[]));
+    }
+}
@@ -0,0 +1,206 @@
+use crate::{
+    audit_service::{AuditEvent, AuditEventType},
+    jwt_auth,
+    pat_service::{self, PatError, PersonalAccessToken},
+    scope_service::allowed_scopes,
+    user_cache,
+    user_model::CreateTokenSchema,
+    AppState,
+};
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+
+fn pat_error_response(err: PatError) -> HttpResponse {
+    match err {
+        PatError::NotFound => HttpResponse::NotFound()
+            .json(serde_json::json!({"status": "fail","message": "Token does not exist"})),
+        PatError::Invalid(message) => HttpResponse::BadRequest()
+            .json(serde_json::json!({"status": "fail","message": message})),
+        err => HttpResponse::InternalServerError()
+            .json(serde_json::json!({"status": "error","message": format!("{:?}", err)})),
+    }
+}
+
+// The scopes a token of `uid` may carry, going by the account's current groups.
+async fn token_scopes_allowed(data: &AppState, uid: u64) -> Result<Vec<String>, HttpResponse> {
+    match user_cache::get_user(data, uid).await {
+        Ok(Some(user)) => Ok(allowed_scopes(&data.env, &user.groups)),
+        Ok(None) => Err(HttpResponse::NotFound()
+            .json(serde_json::json!({"status": "fail","message": "User does not exist"}))),
+        Err(err) => Err(HttpResponse::InternalServerError()
+            .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}))),
+    }
+}
+
+fn created_response(token: PersonalAccessToken, secret: String) -> HttpResponse {
+    HttpResponse::Created().json(serde_json::json!({"status": "success","data": serde_json::json!({
+        "token": token,
+        "secret": secret
+    })}))
+}
+
+#[post("")]
+async fn create_token_handler(
+    req: HttpRequest,
+    auth: jwt_auth::JwtMiddleware,
+    body: web::Json<CreateTokenSchema>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(response) = jwt_auth::require_session(&auth) {
+        return response;
+    }
+    if body.name.trim().is_empty() {
+        return HttpResponse::BadRequest().json(
+            serde_json::json!({"status": "fail","message": "name must not be empty"}),
+        );
+    }
+    let expires_in_days = body.expires_in_days.unwrap_or(data.env.pat_default_days);
+    if expires_in_days < 1 || expires_in_days > data.env.pat_max_days {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "status": "fail",
+            "message": format!("expires_in_days must be between 1 and {}", data.env.pat_max_days)
+        }));
+    }
+
+    let allowed = match token_scopes_allowed(&data, auth.user_id).await {
+        Ok(allowed) => allowed,
+        Err(response) => return response,
+    };
+
+    match pat_service::create(&data.redis_client, auth.user_id, body.name.trim(), &body.scopes, &allowed, expires_in_days).await {
+        Ok((token, secret)) => {
+            data.audit.record(
+                AuditEvent::new(AuditEventType::TokenCreated, true, &req)
+                    .actor(auth.user_id)
+                    .subject(auth.user_id)
+                    .detail(token.prefix.as_str()),
+            );
+            created_response(token, secret)
+        }
+        Err(err) => pat_error_response(err),
+    }
+}
+
+#[get("")]
+async fn list_tokens_handler(
+    data: web::Data<AppState>,
+    auth: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    if let Some(response) = jwt_auth::require_session(&auth) {
+        return response;
+    }
+    match pat_service::list(&data.redis_client, auth.user_id).await {
+        Ok(tokens) => HttpResponse::Ok().json(serde_json::json!({"status": "success","data": serde_json::json!({
+            "tokens": tokens
+        })})),
+        Err(err) => pat_error_response(err),
+    }
+}
+
+#[post("/{prefix}/rotate")]
+async fn rotate_token_handler(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+    auth: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    if let Some(response) = jwt_auth::require_session(&auth) {
+        return response;
+    }
+    let prefix = path.into_inner();
+    let allowed = match token_scopes_allowed(&data, auth.user_id).await {
+        Ok(allowed) => allowed,
+        Err(response) => return response,
+    };
+    match pat_service::rotate(&data.redis_client, auth.user_id, &prefix, &allowed).await {
+        Ok((token, secret)) => {
+            data.audit.record(
+                AuditEvent::new(AuditEventType::TokenCreated, true, &req)
+                    .actor(auth.user_id)
+                    .subject(auth.user_id)
+                    .detail(format!("{} rotated from {}", token.prefix, prefix)),
+            );
+            created_response(token, secret)
+        }
+        Err(err) => pat_error_response(err),
+    }
+}
+
+#[delete("/{prefix}")]
+async fn revoke_token_handler(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+    auth: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    if let Some(response) = jwt_auth::require_session(&auth) {
+        return response;
+    }
+    let prefix = path.into_inner();
+    match pat_service::revoke(&data.redis_client, auth.user_id, &prefix).await {
+        Ok(_) => {
+            data.audit.record(
+                AuditEvent::new(AuditEventType::TokenRevoked, true, &req)
+                    .actor(auth.user_id)
+                    .subject(auth.user_id)
+                    .detail(prefix.as_str()),
+            );
+            HttpResponse::Ok().json(serde_json::json!({"status": "success","message": "Token revoked"}))
+        }
+        Err(err) => pat_error_response(err),
+    }
+}
+
+pub fn config(conf: &mut web::ServiceConfig) {
+    let scope = web::scope("/api/tokens")
+        .service(create_token_handler)
+        .service(list_tokens_handler)
+        .service(rotate_token_handler)
+        .service(revoke_token_handler);
+    conf.service(scope);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scope_service::{SCOPE_PROFILE_READ, SCOPE_PROFILE_WRITE};
+    use crate::test_support::{access_token, memory_store, redis_available, test_state};
+    use crate::user_handler;
+    use actix_web::{http::StatusCode, test, App};
+
+    #[actix_web::test]
+    async fn tokens_work_with_their_secret_only() {
+        let data = test_state(memory_store().await);
+        if !redis_available(&data).await {
+            return;
+        }
+        let app = test::init_service(
+            App::new().app_data(data.clone()).configure(config).configure(user_handler::config),
+        )
+        .await;
+        let session = access_token(&data.env, 2, &[], &[SCOPE_PROFILE_READ, SCOPE_PROFILE_WRITE]);
+        let req = test::TestRequest::post()
+            .uri("/api/tokens")
+            .insert_header(("Authorization", format!("Bearer {}", session)))
+            .set_json(serde_json::json!({"name": "ci", "scopes": [SCOPE_PROFILE_READ, "admin"]}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        // admin is left out, the account is not in the admin group
+        assert_eq!(body["data"]["token"]["scopes"], serde_json::json!([SCOPE_PROFILE_READ]));
+        let secret = body["data"]["secret"].as_str().unwrap().to_string();
+
+        let req = test::TestRequest::get()
+            .uri("/api/user/2")
+            .insert_header(("Authorization", format!("Bearer {}", secret)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let (prefix, _) = secret.rsplit_once('_').unwrap();
+        let req = test::TestRequest::get()
+            .uri("/api/user/2")
+            .insert_header(("Authorization", format!("Bearer {}_wrong", prefix)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,300 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Duration, Utc};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::scope_service;
+use crate::user_service::verify_password;
+
+// Tokens look like pat_<prefix>_<secret>. The prefix is public and names the
+// token in listings and logs, the secret is only ever shown at creation.
+pub const TOKEN_MARKER: &str = "pat_";
+const PREFIX_BYTES: usize = 6;
+const SECRET_BYTES: usize = 32;
+
+#[derive(Debug)]
+pub enum PatError {
+    NotFound,
+    Invalid(String),
+    RedisError(redis::RedisError),
+}
+
+impl From<redis::RedisError> for PatError {
+    fn from(err: redis::RedisError) -> Self {
+        PatError::RedisError(err)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersonalAccessToken {
+    pub prefix: String,
+    pub user_id: u64,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+// The record kept in Redis, which adds the hash of the secret.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredToken {
+    #[serde(flatten)]
+    token: PersonalAccessToken,
+    hash: String,
+}
+
+fn token_key(prefix: &str) -> String {
+    format!("pat:{}", prefix)
+}
+
+fn user_tokens_key(uid: u64) -> String {
+    format!("pats:{}", uid)
+}
+
+fn random_string(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes).replace(['-', '_'], "x")
+}
+
+// Splits pat_<prefix>_<secret>; the prefix never contains '_'.
+fn split_token(token: &str) -> Option<(&str, &str)> {
+    token.strip_prefix(TOKEN_MARKER)?.split_once('_')
+}
+
+// The secret is 32 random bytes, so a fast hash protects it as well as a slow
+// one would and keeps argon2 out of every request made with a token.
+fn hash_secret(secret: &str) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(secret.as_bytes()))
+}
+
+fn secret_matches(secret: &str, hash: &str) -> bool {
+    // tokens created before the switch still carry an argon2 hash
+    if hash.starts_with("$argon2") {
+        return verify_password(secret, hash);
+    }
+    hash_secret(secret) == hash
+}
+
+// Keeps the requested scopes the owner may be granted, by the rule logins
+// follow: unknown scopes are an error, ones outside `allowed` are left out.
+pub fn grant_scopes(scopes: &[String], allowed: &[String]) -> Result<Vec<String>, PatError> {
+    if scopes.is_empty() {
+        return Err(PatError::Invalid("at least one scope is required".to_string()));
+    }
+    scope_service::grant(Some(&scope_service::join(scopes)), allowed).map_err(PatError::Invalid)
+}
+
+async fn store(redis_client: &redis::Client, stored: &StoredToken) -> Result<(), PatError> {
+    let token = &stored.token;
+    let value = serde_json::to_string(stored).unwrap();
+    let ttl = (token.expires_at - Utc::now()).num_seconds().max(1) as usize;
+    let mut conn = redis_client.get_async_connection().await?;
+    let _: () = conn.set_ex(token_key(&token.prefix), value, ttl).await?;
+    let _: () = conn.sadd(user_tokens_key(token.user_id), &token.prefix).await?;
+    Ok(())
+}
+
+async fn load(redis_client: &redis::Client, prefix: &str) -> Result<Option<StoredToken>, PatError> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let value: Option<String> = conn.get(token_key(prefix)).await?;
+    match value {
+        Some(value) => serde_json::from_str(&value)
+            .map(Some)
+            .map_err(|err| PatError::Invalid(err.to_string())),
+        None => Ok(None),
+    }
+}
+
+// Creates a token and returns it together with the secret, which is not kept.
+// `allowed` are the scopes the owner may be granted.
+pub async fn create(
+    redis_client: &redis::Client,
+    user_id: u64,
+    name: &str,
+    scopes: &[String],
+    allowed: &[String],
+    expires_in_days: i64,
+) -> Result<(PersonalAccessToken, String), PatError> {
+    let scopes = grant_scopes(scopes, allowed)?;
+    let prefix = random_string(PREFIX_BYTES);
+    let secret = random_string(SECRET_BYTES);
+    let now = Utc::now();
+    let stored = StoredToken {
+        token: PersonalAccessToken {
+            prefix: prefix.clone(),
+            user_id,
+            name: name.to_string(),
+            scopes,
+            created_at: now,
+            expires_at: now + Duration::days(expires_in_days),
+        },
+        hash: hash_secret(&secret),
+    };
+    store(redis_client, &stored).await?;
+    Ok((stored.token, format!("{}{}_{}", TOKEN_MARKER, prefix, secret)))
+}
+
+// Lists the live tokens of a user, forgetting the ones that have expired.
+pub async fn list(redis_client: &redis::Client, user_id: u64) -> Result<Vec<PersonalAccessToken>, PatError> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let prefixes: Vec<String> = conn.smembers(user_tokens_key(user_id)).await?;
+    let mut tokens = Vec::new();
+    for prefix in prefixes {
+        match load(redis_client, &prefix).await? {
+            Some(stored) => tokens.push(stored.token),
+            None => {
+                let _: () = conn.srem(user_tokens_key(user_id), &prefix).await?;
+            }
+        }
+    }
+    tokens.sort_by_key(|token| token.created_at);
+    Ok(tokens)
+}
+
+pub async fn revoke(redis_client: &redis::Client, user_id: u64, prefix: &str) -> Result<(), PatError> {
+    match load(redis_client, prefix).await? {
+        Some(stored) if stored.token.user_id == user_id => {}
+        _ => return Err(PatError::NotFound),
+    }
+    let mut conn = redis_client.get_async_connection().await?;
+    let _: () = conn.del(token_key(prefix)).await?;
+    let _: () = conn.srem(user_tokens_key(user_id), prefix).await?;
+    Ok(())
+}
+
+// Replaces a token with a new one of the same name and scopes, valid for as
+// long as the original was. Scopes the owner has lost since are dropped.
+pub async fn rotate(
+    redis_client: &redis::Client,
+    user_id: u64,
+    prefix: &str,
+    allowed: &[String],
+) -> Result<(PersonalAccessToken, String), PatError> {
+    let old = match load(redis_client, prefix).await? {
+        Some(stored) if stored.token.user_id == user_id => stored.token,
+        _ => return Err(PatError::NotFound),
+    };
+    let lifetime_days = (old.expires_at - old.created_at).num_days().max(1);
+    let rotated = create(redis_client, user_id, &old.name, &old.scopes, allowed, lifetime_days).await?;
+    revoke(redis_client, user_id, prefix).await?;
+    Ok(rotated)
+}
+
+// Resolves a presented token to its record, or None if it is unknown,
+// expired or the secret does not match.
+pub async fn authenticate(redis_client: &redis::Client, presented: &str) -> Result<Option<PersonalAccessToken>, PatError> {
+    let (prefix, secret) = match split_token(presented) {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    let stored = match load(redis_client, prefix).await? {
+        Some(stored) => stored,
+        None => return Ok(None),
+    };
+    if stored.token.expires_at <= Utc::now() || !secret_matches(secret, &stored.hash) {
+        return Ok(None);
+    }
+    Ok(Some(stored.token))
+}
+
+// Revokes every token of a deleted account.
+pub async fn forget_user(redis_client: &redis::Client, user_id: u64) -> Result<(), PatError> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let prefixes: Vec<String> = conn.smembers(user_tokens_key(user_id)).await?;
+    let mut keys: Vec<String> = prefixes.iter().map(|prefix| token_key(prefix)).collect();
+    keys.push(user_tokens_key(user_id));
+    let _: () = conn.del(keys).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scope_service::{SCOPE_ADMIN, SCOPE_PROFILE_READ, SCOPE_PROFILE_WRITE};
+    use crate::test_support::{memory_store, redis_available, test_state};
+
+    fn scopes(scopes: &[&str]) -> Vec<String> {
+        scopes.iter().map(|scope| scope.to_string()).collect()
+    }
+
+    // Redis outlives the tests, so each one works on its own user id.
+    fn fresh_uid() -> u64 {
+        1_000_000 + OsRng.next_u32() as u64
+    }
+
+    #[test]
+    fn splits_prefix_and_secret() {
+        assert_eq!(split_token("pat_abc_def_ghi"), Some(("abc", "def_ghi")));
+        assert_eq!(split_token("pat_abc"), None);
+        assert_eq!(split_token("abc_def"), None);
+    }
+
+    #[test]
+    fn grants_only_allowed_scopes() {
+        let allowed = scopes(&[SCOPE_PROFILE_READ, SCOPE_PROFILE_WRITE]);
+        let granted = grant_scopes(&scopes(&[SCOPE_PROFILE_READ, SCOPE_ADMIN]), &allowed).unwrap();
+        assert_eq!(granted, scopes(&[SCOPE_PROFILE_READ]));
+        assert!(grant_scopes(&scopes(&[SCOPE_ADMIN]), &allowed).is_err());
+        assert!(grant_scopes(&scopes(&["everything"]), &allowed).is_err());
+        assert!(grant_scopes(&[], &allowed).is_err());
+    }
+
+    #[actix_web::test]
+    async fn authenticates_only_the_full_secret() {
+        let data = test_state(memory_store().await);
+        if !redis_available(&data).await {
+            return;
+        }
+        let uid = fresh_uid();
+        let allowed = scopes(&[SCOPE_PROFILE_READ]);
+        let (token, secret) = create(&data.redis_client, uid, "ci", &allowed, &allowed, 1).await.unwrap();
+        let found = authenticate(&data.redis_client, &secret).await.unwrap().unwrap();
+        assert_eq!((found.prefix.as_str(), found.user_id), (token.prefix.as_str(), uid));
+
+        let wrong = format!("{}{}_{}", TOKEN_MARKER, token.prefix, "x".repeat(43));
+        assert!(authenticate(&data.redis_client, &wrong).await.unwrap().is_none());
+        assert!(authenticate(&data.redis_client, "pat_unknown_secret").await.unwrap().is_none());
+        assert!(authenticate(&data.redis_client, "not a token").await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn rotating_replaces_the_token() {
+        let data = test_state(memory_store().await);
+        if !redis_available(&data).await {
+            return;
+        }
+        let uid = fresh_uid();
+        let all = scopes(&[SCOPE_PROFILE_READ, SCOPE_PROFILE_WRITE]);
+        let (old, old_secret) = create(&data.redis_client, uid, "ci", &all, &all, 30).await.unwrap();
+        assert!(matches!(rotate(&data.redis_client, uid + 1, &old.prefix, &all).await, Err(PatError::NotFound)));
+
+        // the owner lost profile:write in the meantime
+        let (new, new_secret) = rotate(&data.redis_client, uid, &old.prefix, &all[..1]).await.unwrap();
+        assert_ne!(new.prefix, old.prefix);
+        assert_eq!((new.name.as_str(), new.scopes.clone()), ("ci", scopes(&[SCOPE_PROFILE_READ])));
+        assert_eq!((new.expires_at - new.created_at).num_days(), 30);
+        assert!(authenticate(&data.redis_client, &old_secret).await.unwrap().is_none());
+        assert!(authenticate(&data.redis_client, &new_secret).await.unwrap().is_some());
+    }
+
+    #[actix_web::test]
+    async fn only_the_owner_revokes() {
+        let data = test_state(memory_store().await);
+        if !redis_available(&data).await {
+            return;
+        }
+        let uid = fresh_uid();
+        let allowed = scopes(&[SCOPE_PROFILE_READ]);
+        let (token, secret) = create(&data.redis_client, uid, "ci", &allowed, &allowed, 1).await.unwrap();
+        assert!(matches!(revoke(&data.redis_client, uid + 1, &token.prefix).await, Err(PatError::NotFound)));
+        assert!(authenticate(&data.redis_client, &secret).await.unwrap().is_some());
+
+        revoke(&data.redis_client, uid, &token.prefix).await.unwrap();
+        assert!(authenticate(&data.redis_client, &secret).await.unwrap().is_none());
+        assert!(list(&data.redis_client, uid).await.unwrap().is_empty());
+        assert!(matches!(revoke(&data.redis_client, uid, &token.prefix).await, Err(PatError::NotFound)));
+    }
+}
@@ -0,0 +1,359 @@
+use async_trait::async_trait;
+use sqlx::{Pool, Postgres};
+
+use crate::identity_store::{Identity, IdentityError, IdentityPage, IdentityStore, NewIdentity, Profile};
+use crate::paging_service::UserFilter;
+use crate::user_model::{UpdateUserSchema, User};
+use crate::user_service::{fetch_user_by_id_query, hash_password, verify_password};
+
+impl From<sqlx::Error> for IdentityError {
+    fn from(err: sqlx::Error) -> Self {
+        IdentityError::Backend(err.to_string())
+    }
+}
+
+// Accounts are rows of the `users` table with argon2 password hashes, their
+// groups rows of `user_groups` (see `auth_ms group`).
+pub struct PostgresIdentityStore {
+    pool: Pool<Postgres>,
+}
+
+impl PostgresIdentityStore {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        PostgresIdentityStore { pool }
+    }
+
+    async fn fetch_user(&self, user_id: u64) -> Result<Option<User>, IdentityError> {
+        let user_id = match i32::try_from(user_id) {
+            Ok(user_id) => user_id,
+            Err(_) => return Ok(None),
+        };
+        let (query, _) = fetch_user_by_id_query(&user_id);
+        let user = sqlx::query_as::<_, User>(query)
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(user)
+    }
+
+    // The user with their groups.
+    async fn identity(&self, user: User) -> Result<Identity, IdentityError> {
+        let groups: Vec<String> = sqlx::query_scalar("SELECT group_name FROM user_groups WHERE user_id = $1 ORDER BY group_name")
+            .bind(user.user_id)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(Identity { groups, ..to_identity(user) })
+    }
+}
+
+fn to_identity(user: User) -> Identity {
+    Identity {
+        user_id: user.user_id as u64,
+        email: user.email,
+        groups: Vec::new(),
+        disabled: user.disabled_at.is_some(),
+        disabled_at: user.disabled_at,
+        disabled_reason: user.disabled_reason,
+        verified: user.verified,
+        profile: Profile {
+            cn: user.cn,
+            sn: user.sn,
+            display_name: user.display_name,
+            given_name: user.given_name,
+            telephone_number: user.telephone_number,
+        },
+    }
+}
+
+// Conditions of a listing over $1 (address prefix as a LIKE pattern), $2 (uid)
+// and $3 (group), each ignored when NULL.
+const LIST_FILTER: &str = "($1::TEXT IS NULL OR email LIKE $1)
+    AND ($2::BIGINT IS NULL OR user_id = $2)
+    AND ($3::TEXT IS NULL OR user_id IN (SELECT user_id FROM user_groups WHERE group_name = $3))";
+
+// A LIKE pattern matching addresses that start with `prefix`.
+fn prefix_pattern(prefix: &str) -> String {
+    let escaped = prefix.to_lowercase().replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("{}%", escaped)
+}
+
+fn is_unique_violation(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Database(db_err) => db_err.is_unique_violation(),
+        _ => false,
+    }
+}
+
+#[async_trait]
+impl IdentityStore for PostgresIdentityStore {
+    async fn ping(&self) -> Result<(), IdentityError> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn find_by_id(&self, user_id: u64) -> Result<Option<Identity>, IdentityError> {
+        match self.fetch_user(user_id).await? {
+            Some(user) => Ok(Some(self.identity(user).await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<Identity>, IdentityError> {
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1")
+            .bind(email.to_lowercase())
+            .fetch_optional(&self.pool)
+            .await?;
+        match user {
+            Some(user) => Ok(Some(self.identity(user).await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn list_all(&self) -> Result<Vec<Identity>, IdentityError> {
+        let users = sqlx::query_as::<_, User>("SELECT * FROM users ORDER BY user_id")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(users.into_iter().map(to_identity).collect())
+    }
+
+    async fn list_disabled(&self) -> Result<Vec<Identity>, IdentityError> {
+        let users = sqlx::query_as::<_, User>("SELECT * FROM users WHERE disabled_at IS NOT NULL ORDER BY user_id")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(users.into_iter().map(to_identity).collect())
+    }
+
+    async fn list_page(&self, filter: &UserFilter, after: u64, limit: usize) -> Result<IdentityPage, IdentityError> {
+        let pattern = filter.email.as_deref().map(prefix_pattern);
+        let uid = filter.uid.map(|uid| i64::try_from(uid).unwrap_or(-1));
+        let users = sqlx::query_as::<_, User>(&format!(
+            "SELECT * FROM users WHERE {} AND user_id > $4 ORDER BY user_id LIMIT $5",
+            LIST_FILTER
+        ))
+        .bind(&pattern)
+        .bind(uid)
+        .bind(&filter.group)
+        .bind(i64::try_from(after).unwrap_or(i64::MAX))
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        let total: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM users WHERE {}", LIST_FILTER))
+            .bind(&pattern)
+            .bind(uid)
+            .bind(&filter.group)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(IdentityPage {
+            identities: users.into_iter().map(to_identity).collect(),
+            total: total as usize,
+        })
+    }
+
+    async fn authenticate(&self, login: &str, password: &str) -> Result<Identity, IdentityError> {
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1")
+            .bind(login.to_lowercase())
+            .fetch_optional(&self.pool)
+            .await?;
+        let user = match user {
+            Some(user) => user,
+            None => return Err(IdentityError::NotFound),
+        };
+        if !verify_password(password, &user.password) {
+            return Err(IdentityError::InvalidCredentials);
+        }
+        self.identity(user).await
+    }
+
+    async fn create(&self, new_identity: NewIdentity<'_>) -> Result<Identity, IdentityError> {
+        let user_id = i32::try_from(new_identity.user_id)
+            .map_err(|_| IdentityError::Invalid("user_id is out of range".to_string()))?;
+        let password = hash_password(new_identity.password)?;
+        let result = sqlx::query_as::<_, User>(
+            "INSERT INTO users (email, password, user_id, verified, disabled_at)
+            VALUES ($1, $2, $3, $4, CASE WHEN $5 THEN NOW() END) RETURNING *",
+        )
+        .bind(new_identity.email.to_lowercase())
+        .bind(password)
+        .bind(user_id)
+        .bind(new_identity.verified)
+        .bind(new_identity.disabled)
+        .fetch_one(&self.pool)
+        .await;
+        match result {
+            Ok(user) => Ok(to_identity(user)),
+            Err(err) if is_unique_violation(&err) => Err(IdentityError::Conflict(
+                "User with that email or uid already exists".to_string(),
+            )),
+            Err(err) => Err(IdentityError::from(err)),
+        }
+    }
+
+    async fn delete(&self, user_id: u64) -> Result<(), IdentityError> {
+        let user = match self.fetch_user(user_id).await? {
+            Some(user) => user,
+            None => return Err(IdentityError::NotFound),
+        };
+        sqlx::query("DELETE FROM users WHERE id = $1")
+            .bind(user.id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // keeps the time the account was first disabled while it stays disabled
+    async fn set_disabled(&self, user_id: u64, disabled: bool) -> Result<(), IdentityError> {
+        let user = match self.fetch_user(user_id).await? {
+            Some(user) => user,
+            None => return Err(IdentityError::NotFound),
+        };
+        sqlx::query(
+            "UPDATE users SET disabled_at = CASE WHEN $1 THEN COALESCE(disabled_at, NOW()) ELSE NULL END,
+            disabled_reason = CASE WHEN $1 THEN disabled_reason ELSE NULL END WHERE id = $2",
+        )
+        .bind(disabled)
+        .bind(user.id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn disable(&self, user_id: u64, reason: &str) -> Result<Identity, IdentityError> {
+        let user = match self.fetch_user(user_id).await? {
+            Some(user) => user,
+            None => return Err(IdentityError::NotFound),
+        };
+        let user = sqlx::query_as::<_, User>(
+            "UPDATE users SET disabled_at = NOW(), disabled_reason = $1 WHERE id = $2 RETURNING *",
+        )
+        .bind(reason)
+        .bind(user.id)
+        .fetch_one(&self.pool)
+        .await?;
+        self.identity(user).await
+    }
+
+    async fn set_verified(&self, user_id: u64, verified: bool) -> Result<(), IdentityError> {
+        let user = match self.fetch_user(user_id).await? {
+            Some(user) => user,
+            None => return Err(IdentityError::NotFound),
+        };
+        sqlx::query("UPDATE users SET verified = $1 WHERE id = $2")
+            .bind(verified)
+            .bind(user.id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn update(&self, user_id: u64, changes: &UpdateUserSchema) -> Result<Identity, IdentityError> {
+        let user = match self.fetch_user(user_id).await? {
+            Some(user) => user,
+            None => return Err(IdentityError::NotFound),
+        };
+        // attributes left out of the PATCH keep their value, and an unchanged
+        // address keeps its verification
+        let result = sqlx::query_as::<_, User>(
+            "UPDATE users SET
+                verified = verified AND email = COALESCE($1, email),
+                email = COALESCE($1, email),
+                cn = COALESCE($2, cn),
+                sn = COALESCE($3, sn),
+                display_name = COALESCE($4, display_name),
+                given_name = COALESCE($5, given_name),
+                telephone_number = COALESCE($6, telephone_number)
+            WHERE id = $7 RETURNING *",
+        )
+            .bind(changes.mail.as_ref().map(|email| email.to_lowercase()))
+            .bind(&changes.cn)
+            .bind(&changes.sn)
+            .bind(&changes.display_name)
+            .bind(&changes.given_name)
+            .bind(&changes.telephone_number)
+            .bind(user.id)
+            .fetch_one(&self.pool)
+            .await;
+        match result {
+            Ok(user) => self.identity(user).await,
+            Err(err) if is_unique_violation(&err) => Err(IdentityError::Conflict(
+                "User with that email already exists".to_string(),
+            )),
+            Err(err) => Err(IdentityError::from(err)),
+        }
+    }
+
+    async fn group_members(&self, group: &str) -> Result<Option<Vec<u64>>, IdentityError> {
+        let members: Vec<i32> = sqlx::query_scalar("SELECT user_id FROM user_groups WHERE group_name = $1 ORDER BY user_id")
+            .bind(group)
+            .fetch_all(&self.pool)
+            .await?;
+        if members.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(members.into_iter().map(|user_id| user_id as u64).collect()))
+    }
+
+    async fn create_group(&self, group: &str, _description: Option<&str>, members: &[u64]) -> Result<(), IdentityError> {
+        let members: Vec<i32> = match members.iter().map(|user_id| i32::try_from(*user_id)).collect() {
+            Ok(members) => members,
+            Err(_) => return Err(IdentityError::NotFound),
+        };
+        let mut tx = self.pool.begin().await?;
+        // two creations of the same group serialize on this lock
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+            .bind(group)
+            .execute(&mut *tx)
+            .await?;
+        let exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM user_groups WHERE group_name = $1)")
+            .bind(group)
+            .fetch_one(&mut *tx)
+            .await?;
+        if exists {
+            return Err(IdentityError::Conflict("Group with that name already exists".to_string()));
+        }
+        let inserted = sqlx::query(
+            "INSERT INTO user_groups (user_id, group_name) SELECT user_id, $2 FROM users WHERE user_id = ANY($1)",
+        )
+        .bind(&members)
+        .bind(group)
+        .execute(&mut *tx)
+        .await?;
+        let mut distinct = members.clone();
+        distinct.sort_unstable();
+        distinct.dedup();
+        if inserted.rows_affected() != distinct.len() as u64 {
+            return Err(IdentityError::NotFound);
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn delete_group(&self, group: &str) -> Result<Vec<u64>, IdentityError> {
+        let mut members: Vec<i32> = sqlx::query_scalar("DELETE FROM user_groups WHERE group_name = $1 RETURNING user_id")
+            .bind(group)
+            .fetch_all(&self.pool)
+            .await?;
+        if members.is_empty() {
+            return Err(IdentityError::NotFound);
+        }
+        members.sort_unstable();
+        Ok(members.into_iter().map(|user_id| user_id as u64).collect())
+    }
+
+    async fn set_group_member(&self, user_id: u64, group: &str, member: bool) -> Result<(), IdentityError> {
+        let user = match self.fetch_user(user_id).await? {
+            Some(user) => user,
+            None => return Err(IdentityError::NotFound),
+        };
+        let query = if member {
+            "INSERT INTO user_groups (user_id, group_name) VALUES ($1, $2) ON CONFLICT DO NOTHING"
+        } else {
+            "DELETE FROM user_groups WHERE user_id = $1 AND group_name = $2"
+        };
+        sqlx::query(query)
+            .bind(user.user_id)
+            .bind(group)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,257 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Utc};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::mail_service::MailTransport;
+
+// Who may create an account through POST /api/user/.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationMode {
+    // anyone
+    Open,
+    // holders of an invite code from an admin
+    Invite,
+    // anyone, but the account stays pending until an admin approves it
+    Approval,
+}
+
+impl RegistrationMode {
+    pub fn parse(value: &str) -> Option<RegistrationMode> {
+        match value.to_ascii_lowercase().as_str() {
+            "open" => Some(RegistrationMode::Open),
+            "invite" => Some(RegistrationMode::Invite),
+            "approval" => Some(RegistrationMode::Approval),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RegistrationError {
+    InvalidInvite,
+    NotPending,
+    RedisError(redis::RedisError),
+}
+
+impl From<redis::RedisError> for RegistrationError {
+    fn from(err: redis::RedisError) -> Self {
+        RegistrationError::RedisError(err)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invite {
+    pub code: String,
+    // only this address may use the invite when set
+    pub email: Option<String>,
+    pub created_by: u64,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingRegistration {
+    pub user_id: u64,
+    pub email: String,
+    pub requested_at: DateTime<Utc>,
+}
+
+// The checks every new account passes, whether registered or imported.
+// Returns the uid, which has to fit the i32 user_id column.
+pub fn validate_account(user_id: i64, email: &str, password: &str) -> Result<u64, String> {
+    if user_id <= 0 || user_id > i32::MAX as i64 {
+        return Err("user_id must be a positive integer".to_string());
+    }
+    match email.split_once('@') {
+        Some((local, domain)) if !local.is_empty() && domain.contains('.') && !email.contains(char::is_whitespace) => {}
+        _ => return Err("email is not a valid address".to_string()),
+    }
+    if password.is_empty() {
+        return Err("password is required".to_string());
+    }
+    Ok(user_id as u64)
+}
+
+fn invite_key(code: &str) -> String {
+    format!("invite:{}", code)
+}
+
+const INVITES_KEY: &str = "invites";
+
+fn pending_key(uid: u64) -> String {
+    format!("pending:{}", uid)
+}
+
+// uids awaiting approval, scored by the time they registered
+const PENDING_QUEUE_KEY: &str = "pending_queue";
+
+pub async fn create_invite(
+    redis_client: &redis::Client,
+    config: &Config,
+    created_by: u64,
+    email: Option<String>,
+) -> Result<Invite, RegistrationError> {
+    let mut code = [0u8; 18];
+    OsRng.fill_bytes(&mut code);
+    let now = Utc::now();
+    let invite = Invite {
+        code: general_purpose::URL_SAFE_NO_PAD.encode(code),
+        email: email.map(|email| email.to_lowercase()),
+        created_by,
+        created_at: now,
+        expires_at: now + chrono::Duration::days(config.invite_max_age_days),
+    };
+    let mut conn = redis_client.get_async_connection().await?;
+    let _: () = conn
+        .set_ex(
+            invite_key(&invite.code),
+            serde_json::to_string(&invite).unwrap(),
+            (config.invite_max_age_days * 24 * 60 * 60) as usize,
+        )
+        .await?;
+    let _: () = conn.sadd(INVITES_KEY, &invite.code).await?;
+    Ok(invite)
+}
+
+async fn load_invite(conn: &mut redis::aio::Connection, code: &str) -> Result<Option<Invite>, RegistrationError> {
+    let value: Option<String> = conn.get(invite_key(code)).await?;
+    Ok(value.and_then(|value| serde_json::from_str(&value).ok()))
+}
+
+// Lists the invites that are still open, forgetting the used and expired ones.
+pub async fn list_invites(redis_client: &redis::Client) -> Result<Vec<Invite>, RegistrationError> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let codes: Vec<String> = conn.smembers(INVITES_KEY).await?;
+    let mut invites = Vec::new();
+    for code in codes {
+        match load_invite(&mut conn, &code).await? {
+            Some(invite) => invites.push(invite),
+            None => {
+                let _: () = conn.srem(INVITES_KEY, &code).await?;
+            }
+        }
+    }
+    invites.sort_by_key(|invite| invite.created_at);
+    Ok(invites)
+}
+
+pub async fn revoke_invite(redis_client: &redis::Client, code: &str) -> Result<(), RegistrationError> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let deleted: u32 = conn.del(invite_key(code)).await?;
+    let _: () = conn.srem(INVITES_KEY, code).await?;
+    if deleted == 0 {
+        return Err(RegistrationError::InvalidInvite);
+    }
+    Ok(())
+}
+
+// Uses up an invite for `email`. Only the caller whose DEL removes the key
+// wins, so a code cannot be redeemed twice by racing requests.
+pub async fn claim_invite(redis_client: &redis::Client, code: &str, email: &str) -> Result<Invite, RegistrationError> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let invite = match load_invite(&mut conn, code).await? {
+        Some(invite) => invite,
+        None => return Err(RegistrationError::InvalidInvite),
+    };
+    if let Some(bound) = &invite.email {
+        if !bound.eq_ignore_ascii_case(email) {
+            return Err(RegistrationError::InvalidInvite);
+        }
+    }
+    let deleted: u32 = conn.del(invite_key(code)).await?;
+    if deleted == 0 {
+        return Err(RegistrationError::InvalidInvite);
+    }
+    let _: () = conn.srem(INVITES_KEY, code).await?;
+    Ok(invite)
+}
+
+// Puts an invite back after the registration it was claimed for failed.
+pub async fn restore_invite(redis_client: &redis::Client, invite: &Invite) -> Result<(), RegistrationError> {
+    let ttl = (invite.expires_at - Utc::now()).num_seconds();
+    if ttl <= 0 {
+        return Ok(());
+    }
+    let mut conn = redis_client.get_async_connection().await?;
+    let _: () = conn
+        .set_ex(invite_key(&invite.code), serde_json::to_string(invite).unwrap(), ttl as usize)
+        .await?;
+    let _: () = conn.sadd(INVITES_KEY, &invite.code).await?;
+    Ok(())
+}
+
+pub async fn mark_pending(redis_client: &redis::Client, uid: u64, email: &str) -> Result<PendingRegistration, RegistrationError> {
+    let pending = PendingRegistration {
+        user_id: uid,
+        email: email.to_string(),
+        requested_at: Utc::now(),
+    };
+    let mut conn = redis_client.get_async_connection().await?;
+    let _: () = conn.set(pending_key(uid), serde_json::to_string(&pending).unwrap()).await?;
+    let _: () = conn.zadd(PENDING_QUEUE_KEY, uid, pending.requested_at.timestamp()).await?;
+    Ok(pending)
+}
+
+pub async fn is_pending(redis_client: &redis::Client, uid: u64) -> redis::RedisResult<bool> {
+    let mut conn = redis_client.get_async_connection().await?;
+    conn.exists(pending_key(uid)).await
+}
+
+// The queue, oldest first.
+pub async fn list_pending(redis_client: &redis::Client) -> Result<Vec<PendingRegistration>, RegistrationError> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let uids: Vec<u64> = conn.zrange(PENDING_QUEUE_KEY, 0, -1).await?;
+    let mut pending = Vec::new();
+    for uid in uids {
+        let value: Option<String> = conn.get(pending_key(uid)).await?;
+        match value.and_then(|value| serde_json::from_str(&value).ok()) {
+            Some(registration) => pending.push(registration),
+            None => {
+                let _: () = conn.zrem(PENDING_QUEUE_KEY, uid).await?;
+            }
+        }
+    }
+    Ok(pending)
+}
+
+// Takes a registration off the queue, for approval, rejection or deletion.
+pub async fn take_pending(redis_client: &redis::Client, uid: u64) -> Result<PendingRegistration, RegistrationError> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let value: Option<String> = conn.get(pending_key(uid)).await?;
+    let deleted: u32 = conn.del(pending_key(uid)).await?;
+    let _: () = conn.zrem(PENDING_QUEUE_KEY, uid).await?;
+    match value.and_then(|value| serde_json::from_str(&value).ok()) {
+        Some(registration) if deleted > 0 => Ok(registration),
+        _ => Err(RegistrationError::NotPending),
+    }
+}
+
+// Tells the admins about a registration waiting for them, when
+// REGISTRATION_NOTIFY_EMAIL is set.
+pub async fn notify_admins(mailer: &dyn MailTransport, config: &Config, registration: &PendingRegistration) {
+    let to = match &config.registration_notify_email {
+        Some(to) => to,
+        None => return,
+    };
+    let body = format!(
+        "{} (user {}) registered and is waiting for approval.",
+        registration.email, registration.user_id
+    );
+    if let Err(err) = mailer.send(to, "A registration is waiting for approval", &body).await {
+        println!("❌Could not notify {} about registration {}: {:?}", to, registration.user_id, err);
+    }
+}
+
+pub async fn notify_user(mailer: &dyn MailTransport, registration: &PendingRegistration, approved: bool) {
+    let (subject, body) = if approved {
+        ("Your account was approved", "Your account was approved, you can sign in now.")
+    } else {
+        ("Your registration was declined", "Your registration was declined and the account was removed.")
+    };
+    if let Err(err) = mailer.send(&registration.email, subject, body).await {
+        println!("❌Could not notify {} about their registration: {:?}", registration.email, err);
+    }
+}
@@ -0,0 +1,79 @@
+use crate::config::Config;
+use crate::group_service::is_admin;
+
+// The scopes access tokens and personal access tokens can carry. In token
+// claims they travel as one space-separated `scope` string, as in OAuth 2.0.
+pub const SCOPE_PROFILE_READ: &str = "profile:read";
+pub const SCOPE_PROFILE_WRITE: &str = "profile:write";
+pub const SCOPE_ADMIN: &str = "admin";
+pub const ALL_SCOPES: [&str; 3] = [SCOPE_PROFILE_READ, SCOPE_PROFILE_WRITE, SCOPE_ADMIN];
+
+// What a user may be granted: everyone gets the profile scopes, only members
+// of the admin group get `admin`.
+pub fn allowed_scopes(config: &Config, groups: &[String]) -> Vec<String> {
+    ALL_SCOPES
+        .iter()
+        .filter(|scope| **scope != SCOPE_ADMIN || is_admin(config, groups))
+        .map(|scope| scope.to_string())
+        .collect()
+}
+
+pub fn split(scope: &str) -> Vec<String> {
+    scope.split_whitespace().map(|scope| scope.to_string()).collect()
+}
+
+pub fn join(scopes: &[String]) -> String {
+    scopes.join(" ")
+}
+
+// Intersects a requested scope list with the allowed one; no request means
+// everything allowed. Unknown scopes are an error, scopes the user may not
+// have are quietly left out.
+pub fn grant(requested: Option<&str>, allowed: &[String]) -> Result<Vec<String>, String> {
+    let requested = match requested {
+        Some(requested) => split(requested),
+        None => return Ok(allowed.to_vec()),
+    };
+    if let Some(scope) = requested.iter().find(|scope| !ALL_SCOPES.contains(&scope.as_str())) {
+        return Err(format!("unknown scope {}", scope));
+    }
+    let granted: Vec<String> = requested.into_iter().filter(|scope| allowed.contains(scope)).collect();
+    if granted.is_empty() {
+        return Err("none of the requested scopes can be granted".to_string());
+    }
+    Ok(granted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scopes(scopes: &[&str]) -> Vec<String> {
+        scopes.iter().map(|scope| scope.to_string()).collect()
+    }
+
+    #[test]
+    fn grants_the_allowed_part_of_a_request() {
+        let allowed = scopes(&[SCOPE_PROFILE_READ, SCOPE_PROFILE_WRITE]);
+        assert_eq!(grant(None, &allowed).unwrap(), allowed);
+        assert_eq!(grant(Some("profile:read"), &allowed).unwrap(), scopes(&[SCOPE_PROFILE_READ]));
+        assert_eq!(grant(Some(" profile:write  admin "), &allowed).unwrap(), scopes(&[SCOPE_PROFILE_WRITE]));
+        assert_eq!(grant(Some("admin"), &allowed).unwrap_err(), "none of the requested scopes can be granted");
+        assert_eq!(grant(Some(""), &allowed).unwrap_err(), "none of the requested scopes can be granted");
+    }
+
+    #[test]
+    fn refuses_unknown_scopes() {
+        let allowed = scopes(&ALL_SCOPES);
+        assert_eq!(grant(Some("profile:read profile:delete"), &allowed).unwrap_err(), "unknown scope profile:delete");
+        assert_eq!(grant(Some("PROFILE:READ"), &allowed).unwrap_err(), "unknown scope PROFILE:READ");
+    }
+
+    #[test]
+    fn only_admins_may_get_the_admin_scope() {
+        let config = crate::test_support::test_config();
+        let admins = vec![config.ldap_admin_group.clone()];
+        assert!(allowed_scopes(&config, &admins).contains(&SCOPE_ADMIN.to_string()));
+        assert_eq!(allowed_scopes(&config, &[]), scopes(&[SCOPE_PROFILE_READ, SCOPE_PROFILE_WRITE]));
+    }
+}
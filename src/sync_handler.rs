@@ -0,0 +1,47 @@
+use crate::{authz::{Admin, RequireRole}, AppState};
+use actix_web::{get, web, HttpResponse, Responder};
+
+#[get("/status")]
+async fn sync_status_handler(
+    data: web::Data<AppState>,
+    _admin: RequireRole<Admin>,
+) -> impl Responder {
+    let enabled = data.env.user_sync_interval_secs > 0;
+
+    HttpResponse::Ok().json(serde_json::json!({"status": "success","data": serde_json::json!({
+        "enabled": enabled,
+        "interval_secs": data.env.user_sync_interval_secs,
+        "incremental": data.env.user_sync_incremental,
+        "sync": data.user_sync.status()
+    })}))
+}
+
+pub fn config(conf: &mut web::ServiceConfig) {
+    let scope = web::scope("/api/sync").service(sync_status_handler);
+    conf.service(scope);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scope_service::SCOPE_ADMIN;
+    use crate::test_support::{access_token, memory_store, redis_available, test_state};
+    use actix_web::{http::StatusCode, test, App};
+
+    #[actix_web::test]
+    async fn sync_status_is_for_admins() {
+        let data = test_state(memory_store().await);
+        if !redis_available(&data).await {
+            return;
+        }
+        let app = test::init_service(App::new().app_data(data.clone()).configure(config)).await;
+        for (uid, groups, status) in [(2, &[][..], StatusCode::FORBIDDEN), (1, &["admins"][..], StatusCode::OK)] {
+            let token = access_token(&data.env, uid, groups, &[SCOPE_ADMIN]);
+            let req = test::TestRequest::get()
+                .uri("/api/sync/status")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), status);
+        }
+    }
+}
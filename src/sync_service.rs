@@ -0,0 +1,232 @@
+use chrono::{DateTime, Utc};
+use ldap3::adapters::{Adapter, EntriesOnly, PagedResults};
+use ldap3::{ldap_escape, Scope, SearchEntry};
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::directory::parse_generalized_time;
+use crate::ldap_service::{get_read_ldap, LdapCluster};
+
+const SYNC_PAGE_SIZE: i32 = 500;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SyncStatus {
+    pub running: bool,
+    pub mode: Option<&'static str>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub last_success_at: Option<DateTime<Utc>>,
+    pub seen: usize,
+    pub upserted: usize,
+    pub deleted: u64,
+    pub failed: usize,
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+struct SyncCounts {
+    seen: usize,
+    upserted: usize,
+    deleted: u64,
+    failed: usize,
+}
+
+// Progress of the directory copy, shared between the sync task and the
+// status endpoint.
+#[derive(Default)]
+pub struct SyncState {
+    status: Mutex<SyncStatus>,
+    // newest modification time seen, where the next incremental run starts
+    high_water: Mutex<Option<String>>,
+}
+
+impl SyncState {
+    pub fn status(&self) -> SyncStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    fn start(&self, mode: &'static str) {
+        let mut status = self.status.lock().unwrap();
+        status.running = true;
+        status.mode = Some(mode);
+        status.started_at = Some(Utc::now());
+    }
+
+    fn finish(&self, result: Result<SyncCounts, String>) {
+        let mut status = self.status.lock().unwrap();
+        let now = Utc::now();
+        status.running = false;
+        status.finished_at = Some(now);
+        match result {
+            Ok(counts) => {
+                status.last_success_at = Some(now);
+                status.seen = counts.seen;
+                status.upserted = counts.upserted;
+                status.deleted = counts.deleted;
+                status.failed = counts.failed;
+                status.error = None;
+            }
+            Err(err) => status.error = Some(err),
+        }
+    }
+}
+
+// Copies the directory into `users` every USER_SYNC_INTERVAL_SECS. Incremental
+// runs only fetch entries changed since the previous run and cannot notice
+// deletions, so every USER_SYNC_FULL_EVERY-th run is a full one.
+pub fn spawn_user_sync(ldap: Arc<LdapCluster>, db: Pool<Postgres>, config: Config, state: Arc<SyncState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.user_sync_interval_secs));
+        let full_every = config.user_sync_full_every.max(1);
+        let mut run: u32 = 0;
+        loop {
+            interval.tick().await;
+            let since = if config.user_sync_incremental && run % full_every != 0 {
+                state.high_water.lock().unwrap().clone()
+            } else {
+                None
+            };
+            run = run.wrapping_add(1);
+
+            state.start(if since.is_some() { "incremental" } else { "full" });
+            let result = sync_users(&ldap, &db, &config, &state, since).await;
+            if let Err(err) = &result {
+                println!("❌User sync failed: {}", err);
+            }
+            state.finish(result);
+        }
+    });
+}
+
+async fn sync_users(
+    ldap: &LdapCluster,
+    db: &Pool<Postgres>,
+    config: &Config,
+    state: &SyncState,
+    since: Option<String>,
+) -> Result<SyncCounts, String> {
+    let flavor = config.ldap_flavor;
+    let created_attr = flavor.created_attr();
+    let modified_attr = flavor.modified_attr();
+    let filter = match &since {
+        Some(mark) => flavor.user_filter(&format!("({}>={})", modified_attr, ldap_escape(mark.as_str()))),
+        None => flavor.user_filter(""),
+    };
+
+    let mut conn = get_read_ldap(ldap).await.map_err(|err| format!("{:?}", err))?;
+    let adapters: Vec<Box<dyn Adapter<_, _>>> = vec![
+        Box::new(EntriesOnly::new()),
+        Box::new(PagedResults::new(SYNC_PAGE_SIZE)),
+    ];
+    let mut search = conn
+        .streaming_search_with(
+            adapters,
+            &config.ldap_base_dn,
+            Scope::Subtree,
+            &filter,
+            vec!["uid", "mail", created_attr, modified_attr],
+        )
+        .await;
+    let mut search = match search {
+        Ok(search) => search,
+        Err(err) => {
+            conn.observe_error(&err);
+            return Err(format!("{:?}", err));
+        }
+    };
+
+    let mut counts = SyncCounts::default();
+    let mut seen: Vec<i32> = Vec::new();
+    let mut high_water = since.clone();
+    loop {
+        let entry = match search.next().await {
+            Ok(Some(entry)) => SearchEntry::construct(entry),
+            Ok(None) => break,
+            Err(err) => {
+                drop(search);
+                conn.observe_error(&err);
+                return Err(format!("{:?}", err));
+            }
+        };
+        counts.seen += 1;
+        let first = |attr: &str| entry.attrs.get(attr).and_then(|values| values.get(0)).cloned();
+
+        if let Some(modified) = first(modified_attr) {
+            if high_water.as_deref().map_or(true, |mark| modified.as_str() > mark) {
+                high_water = Some(modified);
+            }
+        }
+        // `users.user_id` is an INT and `users.email` is NOT NULL
+        let user_id = match first("uid").and_then(|uid| uid.parse::<i32>().ok()) {
+            Some(user_id) => user_id,
+            None => {
+                counts.failed += 1;
+                continue;
+            }
+        };
+        seen.push(user_id);
+        let email = match first("mail") {
+            Some(email) => email.to_lowercase(),
+            None => {
+                counts.failed += 1;
+                continue;
+            }
+        };
+        let created_at = first(created_attr).and_then(|created| parse_generalized_time(&created));
+
+        match upsert_user(db, user_id, &email, created_at).await {
+            Ok(_) => counts.upserted += 1,
+            Err(err) => {
+                println!("User sync could not store {}: {}", entry.dn, err);
+                counts.failed += 1;
+            }
+        }
+    }
+    search
+        .finish()
+        .await
+        .success()
+        .map_err(|err| format!("{:?}", err))?;
+
+    // an empty result more likely means a wrong base DN than an empty directory
+    if since.is_none() && !seen.is_empty() {
+        counts.deleted = mark_deleted(db, &seen).await.map_err(|err| err.to_string())?;
+    }
+    *state.high_water.lock().unwrap() = high_water;
+    Ok(counts)
+}
+
+// Synced rows carry no password: logins keep going to the directory.
+async fn upsert_user(
+    db: &Pool<Postgres>,
+    user_id: i32,
+    email: &str,
+    created_at: Option<DateTime<Utc>>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO users (email, password, user_id, created_at) VALUES ($1, '', $2, COALESCE($3, NOW()))
+        ON CONFLICT (user_id) DO UPDATE SET
+            email = EXCLUDED.email,
+            created_at = COALESCE($3, users.created_at),
+            deleted_at = NULL",
+    )
+    .bind(email)
+    .bind(user_id)
+    .bind(created_at)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+async fn mark_deleted(db: &Pool<Postgres>, seen: &[i32]) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE users SET deleted_at = NOW() WHERE deleted_at IS NULL AND NOT (user_id = ANY($1))",
+    )
+    .bind(seen)
+    .execute(db)
+    .await?;
+    Ok(result.rows_affected())
+}
@@ -5,6 +5,12 @@ pub struct TokenDetails {
     pub token: Option<String>,
     pub token_uuid: uuid::Uuid,
     pub user_id:u64,
+    pub groups: Vec<String>,
+    // None for tokens signed before scopes existed
+    pub scopes: Option<Vec<String>>,
+    // thumbprint of the DPoP key the token is bound to
+    pub jkt: Option<String>,
+    pub issued_at: Option<i64>,
     pub expires_in: Option<i64>,
 }
 
@@ -14,4 +20,32 @@ pub struct TokenClaims {
     pub token_uuid: String,
     pub exp: i64,
     pub iat: i64,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    // space-separated granted scopes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<ConfirmationClaim>,
+}
+
+// RFC 9449 key confirmation of a DPoP-bound token.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfirmationClaim {
+    pub jkt: String,
+}
+
+// Claims of the single-purpose tokens mailed to users, such as email
+// verification links. `purpose` keeps one kind from being accepted as another.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmailTokenClaims {
+    pub sub: String,
+    pub email: String,
+    pub purpose: String,
+    pub jti: String,
+    pub exp: i64,
+    pub iat: i64,
+    // hash of the nonce held by the device that asked for the mail
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce_hash: Option<String>,
 }
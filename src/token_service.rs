@@ -1,9 +1,13 @@
 use base64::{engine::general_purpose, Engine as _};
 use uuid::Uuid;
-use crate::token_model::{TokenDetails, TokenClaims};
+use crate::token_model::{ConfirmationClaim, EmailTokenClaims, TokenDetails, TokenClaims};
+use crate::scope_service;
 
 pub fn generate_jwt_token(
     user_id: u64,
+    groups: Vec<String>,
+    scopes: Vec<String>,
+    jkt: Option<String>,
     ttl: i64,
     private_key: String,
 ) -> Result<TokenDetails, jsonwebtoken::errors::Error> {
@@ -13,7 +17,11 @@ pub fn generate_jwt_token(
     let now = chrono::Utc::now();
     let mut token_details = TokenDetails {
         user_id,
+        groups,
+        scopes: Some(scopes),
+        jkt,
         token_uuid: Uuid::new_v4(),
+        issued_at: Some(now.timestamp()),
         expires_in: Some((now + chrono::Duration::minutes(ttl)).timestamp()),
         token: None,
     };
@@ -23,6 +31,9 @@ pub fn generate_jwt_token(
         token_uuid: token_details.token_uuid.to_string(),
         exp: token_details.expires_in.unwrap(),
         iat: now.timestamp(),
+        groups: token_details.groups.clone(),
+        scope: token_details.scopes.as_deref().map(scope_service::join),
+        cnf: token_details.jkt.clone().map(|jkt| ConfirmationClaim { jkt }),
     };
 
     let header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256);
@@ -57,6 +68,67 @@ pub fn verify_jwt_token(
         token: None,
         token_uuid,
         user_id,
+        groups: decoded.claims.groups,
+        scopes: decoded.claims.scope.as_deref().map(scope_service::split),
+        jkt: decoded.claims.cnf.map(|cnf| cnf.jkt),
+        issued_at: Some(decoded.claims.iat),
         expires_in: None,
     })
-}
\ No newline at end of file
+}
+pub fn check_key_pair(private_key: String, public_key: String) -> Result<(), String> {
+    let bytes_private_key = general_purpose::STANDARD.decode(private_key).map_err(|e| e.to_string())?;
+    let bytes_public_key = general_purpose::STANDARD.decode(public_key).map_err(|e| e.to_string())?;
+    jsonwebtoken::EncodingKey::from_rsa_pem(&bytes_private_key).map_err(|e| e.to_string())?;
+    jsonwebtoken::DecodingKey::from_rsa_pem(&bytes_public_key).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+pub fn generate_email_token(
+    user_id: u64,
+    email: &str,
+    purpose: &str,
+    nonce_hash: Option<String>,
+    ttl: i64,
+    private_key: String,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let bytes_private_key = general_purpose::STANDARD.decode(private_key).unwrap();
+    let decoded_private_key = String::from_utf8(bytes_private_key).unwrap();
+
+    let now = chrono::Utc::now();
+    let claims = EmailTokenClaims {
+        sub: user_id.to_string(),
+        email: email.to_string(),
+        purpose: purpose.to_string(),
+        jti: Uuid::new_v4().to_string(),
+        exp: (now + chrono::Duration::minutes(ttl)).timestamp(),
+        iat: now.timestamp(),
+        nonce_hash,
+    };
+
+    let header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256);
+    jsonwebtoken::encode(
+        &header,
+        &claims,
+        &jsonwebtoken::EncodingKey::from_rsa_pem(decoded_private_key.as_bytes())?,
+    )
+}
+
+pub fn verify_email_token(
+    public_key: String,
+    token: &str,
+    purpose: &str,
+) -> Result<EmailTokenClaims, jsonwebtoken::errors::Error> {
+    let bytes_public_key = general_purpose::STANDARD.decode(public_key).unwrap();
+    let decoded_public_key = String::from_utf8(bytes_public_key).unwrap();
+
+    let validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::RS256);
+    let decoded = jsonwebtoken::decode::<EmailTokenClaims>(
+        token,
+        &jsonwebtoken::DecodingKey::from_rsa_pem(decoded_public_key.as_bytes())?,
+        &validation,
+    )?;
+    if decoded.claims.purpose != purpose || decoded.claims.sub.parse::<u64>().is_err() {
+        return Err(jsonwebtoken::errors::Error::from(jsonwebtoken::errors::ErrorKind::InvalidToken));
+    }
+    Ok(decoded.claims)
+}
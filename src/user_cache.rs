@@ -0,0 +1,80 @@
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::identity_store::{Identity, IdentityError};
+use crate::AppState;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedUser {
+    pub user_id: u64,
+    pub email: String,
+    pub groups: Vec<String>,
+    pub disabled: bool,
+    pub verified: bool,
+}
+
+impl From<Identity> for CachedUser {
+    fn from(identity: Identity) -> Self {
+        CachedUser {
+            user_id: identity.user_id,
+            email: identity.email,
+            groups: identity.groups,
+            disabled: identity.disabled,
+            verified: identity.verified,
+        }
+    }
+}
+
+// Negative entries store this instead of a user record.
+const MISSING: &str = "missing";
+
+fn cache_key(uid: u64) -> String {
+    format!("user:{}", uid)
+}
+
+// Read-through lookup of a user by uid, disabled or not. Redis is only a cache here, so
+// its errors fall back to the directory instead of failing the request.
+pub async fn get_user(data: &AppState, uid: u64) -> Result<Option<CachedUser>, IdentityError> {
+    let mut redis_client = data.redis_client.get_async_connection().await.ok();
+    if let Some(redis_client) = redis_client.as_mut() {
+        let cached: redis::RedisResult<Option<String>> = redis_client.get(cache_key(uid)).await;
+        if let Ok(Some(cached)) = cached {
+            if cached == MISSING {
+                return Ok(None);
+            }
+            if let Ok(user) = serde_json::from_str::<CachedUser>(&cached) {
+                return Ok(Some(user));
+            }
+        }
+    }
+
+    let user = fetch_user(data, uid).await?;
+
+    if let Some(redis_client) = redis_client.as_mut() {
+        let (value, ttl) = match &user {
+            Some(user) => (serde_json::to_string(user).unwrap(), data.env.user_cache_ttl_secs),
+            None => (MISSING.to_string(), data.env.user_cache_negative_ttl_secs),
+        };
+        if ttl > 0 {
+            let _: redis::RedisResult<()> = redis_client.set_ex(cache_key(uid), value, ttl).await;
+        }
+    }
+    Ok(user)
+}
+
+async fn fetch_user(data: &AppState, uid: u64) -> Result<Option<CachedUser>, IdentityError> {
+    let identity = data.identity.find_by_id(uid).await?;
+    Ok(identity.map(CachedUser::from))
+}
+
+// Drops cached records after a change in the directory. Failures are ignored,
+// the entries still expire with their TTL.
+pub async fn invalidate_users(redis_client: &redis::Client, uids: &[u64]) {
+    if uids.is_empty() {
+        return;
+    }
+    if let Ok(mut redis_client) = redis_client.get_async_connection().await {
+        let keys: Vec<String> = uids.iter().map(|uid| cache_key(*uid)).collect();
+        let _: redis::RedisResult<()> = redis_client.del(keys).await;
+    }
+}
@@ -1,165 +1,751 @@
 use crate::{
-    user_model::{RegisterUserSchema, User},
-    user_service::filter_user_record, AppState,
-    ldap_service::get_admin_ldap
+    user_model::{CreateInviteSchema, DisableUserSchema, ExportUsersQuery, ImportUsersQuery, ListUsersQuery, RegisterUserSchema, ResendVerificationSchema, UpdateUserSchema, VerifyEmailSchema},
+    AppState,
+    group_service::is_valid_group_name,
+    identity_store::{Identity, IdentityError, NewIdentity},
+    paging_service::{UserCursor, UserFilter},
+    bulk_service::{export_users, import_users, parse_import, BulkFormat},
+    user_cache,
+    mfa_service,
+    webauthn_service,
+    verification_service::{self, VERIFY_EMAIL_PURPOSE},
+    token_service,
+    pat_service,
+    audit_service::{AuditEvent, AuditEventType},
+    authz::{Admin, OwnerOrAdmin, ProfileRead, ProfileWrite, RequireRole, RequireScope},
+    registration_service::{self, RegistrationError, RegistrationMode},
+    account_service,
+    jwt_auth
 };
 use actix_web::{
-     post, web, HttpResponse, Responder,delete
+     get, patch, post, web, HttpRequest, HttpResponse, Responder,delete
 };
-use argon2::{
-    password_hash::{rand_core::OsRng,  PasswordHasher, SaltString},
-    Argon2,
-};
-use ldap3::{LdapConnAsync, Scope, SearchEntry};
-use ldap3::result::Result;
-use std::collections::HashSet;
-use sqlx::Row;
-use uuid::Uuid;
 
 #[post("/")]
 async fn register_user_handler(
+    req: HttpRequest,
     body: web::Json<RegisterUserSchema>,
     data: web::Data<AppState>,
 ) -> impl Responder {
-    
+    // the same checks bulk imports apply
+    let uid = match registration_service::validate_account(body.user_id.into(), &body.email, &body.password) {
+        Ok(uid) => uid,
+        Err(message) => {
+            return HttpResponse::BadRequest().json(
+                serde_json::json!({"status": "fail","message": message}),
+            );
+        }
+    };
     let email = body.email.as_str();
     let password = body.password.as_str();
     let user_id = body.user_id.to_string();
-    let username: Vec<&str> = email.split('@').collect();
-    let username = username[0];
-    let base_dn = "ou=dia,dc=diditalready,dc=com";
-    let dn = format!("uid={},{}", user_id, base_dn);
-    let filter = format!("(&(objectClass=inetOrgPerson)(|(mail={})(uid={})))", email,user_id);
-    // check if user exists
-    let ldap = get_admin_ldap(&data.ldap_pool,&data.env.ldap_admin_dn,&data.env.ldap_admin_password).await;
-    let mut ldap = match ldap {
-        Ok(ldap) => ldap,
+    let new_identity = NewIdentity {
+        user_id: uid,
+        email,
+        password,
+        verified: false,
+        // the account cannot sign in until an admin approves it
+        disabled: data.env.registration_mode == RegistrationMode::Approval,
+    };
+
+    let invite = if data.env.registration_mode == RegistrationMode::Invite {
+        let code = match &body.invite {
+            Some(code) => code,
+            None => {
+                return HttpResponse::Forbidden().json(
+                    serde_json::json!({"status": "fail","message": "Registration needs an invite code"}),
+                );
+            }
+        };
+        match registration_service::claim_invite(&data.redis_client, code, email).await {
+            Ok(invite) => Some(invite),
+            Err(RegistrationError::InvalidInvite) => {
+                data.audit.record(AuditEvent::new(AuditEventType::Register, false, &req).email(email).detail("invalid invite"));
+                return HttpResponse::Forbidden().json(
+                    serde_json::json!({"status": "fail","message": "Invalid or used invite code"}),
+                );
+            }
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
+            }
+        }
+    } else {
+        None
+    };
+
+    let created = data.identity.create(new_identity).await;
+    if let (Err(_), Some(invite)) = (&created, &invite) {
+        // the code stays usable when the account could not be created
+        let _ = registration_service::restore_invite(&data.redis_client, invite).await;
+    }
+
+    match created {
+        Ok(_) => {
+            // a registration missing from the queue could never be approved, so
+            // the account is taken back when it cannot be queued
+            let registration = if data.env.registration_mode == RegistrationMode::Approval {
+                match registration_service::mark_pending(&data.redis_client, uid, email).await {
+                    Ok(registration) => Some(registration),
+                    Err(err) => {
+                        if let Err(delete_err) = data.identity.delete(uid).await {
+                            println!("❌Could not remove the unqueued registration {}: {:?}", uid, delete_err);
+                        }
+                        return HttpResponse::InternalServerError()
+                            .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
+                    }
+                }
+            } else {
+                None
+            };
+            // drop a cached "not found" left by lookups before the account existed
+            user_cache::invalidate_users(&data.redis_client, &[uid]).await;
+            let mut event = AuditEvent::new(AuditEventType::Register, true, &req).subject(uid).email(email);
+            if let Some(invite) = &invite {
+                event = event.actor(invite.created_by).detail(format!("invite {}", invite.code));
+            }
+            data.audit.record(event);
+            // a failed mail is not fatal, the user can ask for another one
+            if let Err(err) = verification_service::send_verification_mail(data.mailer.as_ref(), &data.env, uid, email).await {
+                println!("❌Could not send the verification mail to {}: {}", email, err);
+            }
+            let user = serde_json::json!({
+                "user": {
+                    "id": user_id,
+                    "email": email,
+                    "user_id": user_id
+                }
+            });
+
+            if let Some(registration) = registration {
+                let mailer = data.mailer.clone();
+                let config = data.env.clone();
+                actix_web::rt::spawn(async move {
+                    registration_service::notify_admins(mailer.as_ref(), &config, &registration).await;
+                });
+                return HttpResponse::Accepted().json(serde_json::json!({
+                    "status": "success",
+                    "message": "The registration is waiting for approval",
+                    "data": user
+                }));
+            }
+
+            return HttpResponse::Ok().json(serde_json::json!({"status": "success","data": user}));
+        }
+        Err(IdentityError::Conflict(message)) => {
+            data.audit.record(AuditEvent::new(AuditEventType::Register, false, &req).email(email).detail(message.as_str()));
+            return HttpResponse::Conflict().json(
+                serde_json::json!({"status": "fail","message": message}),
+            );
+        }
+        Err(IdentityError::Invalid(message)) => {
+            return HttpResponse::BadRequest().json(
+                serde_json::json!({"status": "fail","message": message}),
+            );
+        }
         Err(err) => {
             return HttpResponse::InternalServerError()
                 .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
+    }
+}
+
+// Drops what Redis keeps about a deleted account.
+async fn forget_account(data: &AppState, id: u64) {
+    user_cache::invalidate_users(&data.redis_client, &[id]).await;
+    let _ = mfa_service::forget_user(data.db_pool.as_ref(), id).await;
+    let _ = webauthn_service::forget_user(data.db_pool.as_ref(), id).await;
+    let _ = pat_service::forget_user(&data.redis_client, id).await;
+    let _ = registration_service::take_pending(&data.redis_client, id).await;
+    let _ = account_service::forget_user(&data.redis_client, id).await;
+}
+
+// Removes the account from the identity store for good. Admins who only want
+// to lock someone out disable the account instead.
+#[delete("/{id}")]
+async fn delete_user_handler(
+    req: HttpRequest,
+    path: web::Path<u64>,
+    data: web::Data<AppState>,
+    access: OwnerOrAdmin,
+    _scope: RequireScope<ProfileWrite>,
+) -> impl Responder {
+    // deleting cannot be undone, so a personal access token is not enough
+    if let Some(response) = jwt_auth::require_session(&access.auth) {
+        return response;
+    }
+    let id =path.into_inner();
+    match data.identity.delete(id).await {
+        Ok(_) => {
+            forget_account(&data, id).await;
+            data.audit.record(AuditEvent::new(AuditEventType::Delete, true, &req).actor(access.auth.user_id).subject(id));
+            let response = serde_json::json!({"status": "success","message": "User deleted successfully"});
+            HttpResponse::Ok().json(response)
+        }
+        Err(IdentityError::NotFound) => {
+            HttpResponse::Conflict().json(
+                serde_json::json!({"status": "fail","message": "User does not exist"}),
+            )
+        }
+        Err(err) => {
+            HttpResponse::InternalServerError()
+                .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}))
+        }
+    }
+}
+
+async fn verify_email(data: &AppState, token: &str) -> HttpResponse {
+    let claims = match token_service::verify_email_token(data.env.access_token_public_key.to_owned(), token, VERIFY_EMAIL_PURPOSE) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::BadRequest().json(
+                serde_json::json!({"status": "fail","message": "Invalid or expired verification token"}),
+            );
+        }
     };
-    
-    let search_result = ldap
-        .search(
-            &base_dn,
-            Scope::Subtree,
-            &filter, 
-            vec!["1.1"],
-        )
-        .await;
-    let search_result = match search_result {
-        Ok(search_result) => search_result,
+    let user_id = claims.sub.parse::<u64>().unwrap();
+    // a token mailed to a previous address must not verify the current one
+    match data.identity.find_by_id(user_id).await {
+        Ok(Some(identity)) if identity.email.eq_ignore_ascii_case(&claims.email) => {}
+        Ok(_) => {
+            return HttpResponse::BadRequest().json(
+                serde_json::json!({"status": "fail","message": "Invalid or expired verification token"}),
+            );
+        }
         Err(err) => {
             return HttpResponse::InternalServerError()
                 .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
-    };
+    }
+
+    match data.identity.set_verified(user_id, true).await {
+        Ok(_) => {
+            user_cache::invalidate_users(&data.redis_client, &[user_id]).await;
+            HttpResponse::Ok().json(serde_json::json!({"status": "success","message": "Email verified"}))
+        }
+        Err(err) => HttpResponse::InternalServerError()
+            .json(serde_json::json!({"status": "error","message": format!("{:?}", err)})),
+    }
+}
+
+#[get("/verify")]
+async fn verify_email_link_handler(
+    query: web::Query<VerifyEmailSchema>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    verify_email(&data, &query.token).await
+}
+
+#[post("/verify")]
+async fn verify_email_handler(
+    body: web::Json<VerifyEmailSchema>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    verify_email(&data, &body.token).await
+}
+
+// Answers the same way whether or not the address belongs to an unverified
+// account, so it cannot be used to probe for registered emails.
+#[post("/verify/resend")]
+async fn resend_verification_handler(
+    body: web::Json<ResendVerificationSchema>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let accepted = HttpResponse::Accepted().json(
+        serde_json::json!({"status": "success","message": "If the account needs verification, a new mail is on its way"}),
+    );
+    match verification_service::claim_resend_slot(&data.redis_client, &data.env, &body.email).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return HttpResponse::TooManyRequests().json(
+                serde_json::json!({"status": "fail","message": "Please wait before asking for another verification mail"}),
+            );
+        }
+        Err(err) => {
+            return HttpResponse::InternalServerError()
+                .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
+        }
+    }
 
-    let (rs,_res) = match search_result.success() {
-        Ok(search_result) => search_result,
+    let identity = match data.identity.find_by_email(&body.email).await {
+        Ok(Some(identity)) => identity,
+        Ok(None) => return accepted,
         Err(err) => {
             return HttpResponse::InternalServerError()
                 .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
     };
-    if rs.len() > 0 {
-        return HttpResponse::Conflict().json(
-            serde_json::json!({"status": "fail","message": "User with that email or uid already exists"}),
-        );    
+    if !identity.verified {
+        if let Err(err) = verification_service::send_verification_mail(data.mailer.as_ref(), &data.env, identity.user_id, &identity.email).await {
+            println!("❌Could not send the verification mail to {}: {}", identity.email, err);
+        }
     }
+    accepted
+}
 
-    let attrs: Vec<(&str, HashSet<&str>)> = vec![
-    ("objectClass", vec!["inetOrgPerson"].into_iter().collect()),
-    ("uid", vec![user_id.as_str()].into_iter().collect()),
-    ("mail", vec![email].into_iter().collect()),
-    ("sn", vec![username].into_iter().collect()),
-    ("cn", vec![email].into_iter().collect()),
-    ("userPassword", vec![password].into_iter().collect()),
-    
-];
-    
+#[get("")]
+async fn list_users_handler(
+    query: web::Query<ListUsersQuery>,
+    data: web::Data<AppState>,
+    _admin: RequireRole<Admin>,
+) -> impl Responder {
+    // a cursor carries the filter, page size and position of the listing it continues
+    let cursor = match &query.cursor {
+        Some(cursor) => match UserCursor::decode(cursor) {
+            Some(cursor) => cursor,
+            None => {
+                return HttpResponse::BadRequest().json(
+                    serde_json::json!({"status": "fail","message": "Invalid cursor"}),
+                );
+            }
+        },
+        None => {
+            let filter = UserFilter { group: query.group.clone(), email: query.email.clone(), uid: query.uid };
+            UserCursor::first(filter, query.limit)
+        }
+    };
+    if let Some(group) = &cursor.filter.group {
+        if !is_valid_group_name(group) {
+            return HttpResponse::BadRequest().json(
+                serde_json::json!({"status": "fail","message": "Invalid group name"}),
+            );
+        }
+    }
 
-    let result = ldap.add(&dn, attrs).await;
-    let result = match result {
-        Ok(result) => result,
+    let page = match data.identity.list_page(&cursor.filter, cursor.after, cursor.limit).await {
+        Ok(page) => page,
+        Err(IdentityError::NotFound) => {
+            return HttpResponse::NotFound().json(
+                serde_json::json!({"status": "fail","message": "Group does not exist"}),
+            );
+        }
         Err(err) => {
             return HttpResponse::InternalServerError()
                 .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
     };
-    match result.success() {
-        Ok(_) => {
-            let user_response = serde_json::json!({"status": "success","data": serde_json::json!({
-                "user": {
-                    "id": user_id,
-                    "email": email,
-                    "user_id": user_id
-                }
-            })});
+    let next_cursor = match page.identities.last() {
+        Some(last) if page.identities.len() == cursor.limit => {
+            Some(UserCursor { after: last.user_id, ..cursor.clone() }.encode())
+        }
+        _ => None,
+    };
+    let users: Vec<serde_json::Value> = page
+        .identities
+        .iter()
+        .map(|identity| serde_json::json!({
+            "id": identity.user_id,
+            "email": identity.email,
+            "user_id": identity.user_id
+        }))
+        .collect();
+
+    HttpResponse::Ok().json(serde_json::json!({"status": "success","data": serde_json::json!({
+        "users": users,
+        "page": {
+            "limit": cursor.limit,
+            "count": users.len(),
+            "next_cursor": next_cursor,
+            "estimated_total": page.total
+        }
+    })}))
+}
+
+// Largest import file accepted, roughly a few tens of thousands of rows.
+const MAX_IMPORT_BYTES: usize = 8 * 1024 * 1024;
 
-            return HttpResponse::Ok().json(user_response);
+fn bulk_format(format: &Option<String>) -> std::result::Result<BulkFormat, HttpResponse> {
+    match format.as_deref().map(BulkFormat::parse).unwrap_or(Some(BulkFormat::Csv)) {
+        Some(format) => Ok(format),
+        None => Err(HttpResponse::BadRequest().json(
+            serde_json::json!({"status": "fail","message": "format must be csv or ldif"}),
+        )),
+    }
+}
+
+#[post("/import")]
+async fn import_users_handler(
+    req: HttpRequest,
+    query: web::Query<ImportUsersQuery>,
+    body: String,
+    data: web::Data<AppState>,
+    admin: RequireRole<Admin>,
+) -> impl Responder {
+    let format = match bulk_format(&query.format) {
+        Ok(format) => format,
+        Err(response) => return response,
+    };
+    let records = match parse_import(format, &body) {
+        Ok(records) => records,
+        Err(message) => {
+            return HttpResponse::BadRequest().json(
+                serde_json::json!({"status": "fail","message": message}),
+            );
         }
+    };
+
+    let report = import_users(data.identity.as_ref(), records, query.dry_run.unwrap_or(false)).await;
+    let created = report.created();
+    for (uid, email) in created.iter() {
+        data.audit.record(
+            AuditEvent::new(AuditEventType::Register, true, &req)
+                .actor(admin.auth.user_id)
+                .subject(*uid)
+                .email(email.as_str())
+                .detail("bulk import"),
+        );
+    }
+    let uids: Vec<u64> = created.iter().map(|(uid, _)| *uid).collect();
+    user_cache::invalidate_users(&data.redis_client, &uids).await;
+    verification_service::send_verification_mails(data.mailer.as_ref(), &data.env, &created).await;
+
+    HttpResponse::Ok().json(serde_json::json!({"status": "success","data": report}))
+}
+
+#[get("/export")]
+async fn export_users_handler(
+    query: web::Query<ExportUsersQuery>,
+    data: web::Data<AppState>,
+    _admin: RequireRole<Admin>,
+) -> impl Responder {
+    let format = match bulk_format(&query.format) {
+        Ok(format) => format,
+        Err(response) => return response,
+    };
+    let identities = match data.identity.list_all().await {
+        Ok(identities) => identities,
         Err(err) => {
             return HttpResponse::InternalServerError()
                 .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
+    };
+
+    match export_users(&data.env, format, &identities) {
+        Ok(export) => HttpResponse::Ok()
+            .content_type(format.content_type())
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"users.{}\"", format.extension()),
+            ))
+            .body(export),
+        Err(message) => HttpResponse::InternalServerError()
+            .json(serde_json::json!({"status": "error","message": message})),
     }
+}
 
+fn registration_error_response(err: RegistrationError) -> HttpResponse {
+    match err {
+        RegistrationError::InvalidInvite => HttpResponse::NotFound()
+            .json(serde_json::json!({"status": "fail","message": "Invite does not exist"})),
+        RegistrationError::NotPending => HttpResponse::NotFound()
+            .json(serde_json::json!({"status": "fail","message": "No pending registration for this user"})),
+        err => HttpResponse::InternalServerError()
+            .json(serde_json::json!({"status": "error","message": format!("{:?}", err)})),
+    }
+}
 
+#[post("/invites")]
+async fn create_invite_handler(
+    req: HttpRequest,
+    admin: RequireRole<Admin>,
+    body: web::Json<CreateInviteSchema>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match registration_service::create_invite(&data.redis_client, &data.env, admin.auth.user_id, body.email.clone()).await {
+        Ok(invite) => {
+            let mut event = AuditEvent::new(AuditEventType::InviteCreated, true, &req).actor(admin.auth.user_id);
+            if let Some(email) = &invite.email {
+                event = event.email(email);
+            }
+            data.audit.record(event);
+            HttpResponse::Created().json(serde_json::json!({"status": "success","data": serde_json::json!({
+                "invite": invite
+            })}))
+        }
+        Err(err) => registration_error_response(err),
+    }
+}
 
+#[get("/invites")]
+async fn list_invites_handler(
+    data: web::Data<AppState>,
+    _admin: RequireRole<Admin>,
+) -> impl Responder {
+    match registration_service::list_invites(&data.redis_client).await {
+        Ok(invites) => HttpResponse::Ok().json(serde_json::json!({"status": "success","data": serde_json::json!({
+            "invites": invites
+        })})),
+        Err(err) => registration_error_response(err),
+    }
 }
 
-#[delete("/{id}")]
-async fn delete_user_handler(
+#[delete("/invites/{code}")]
+async fn revoke_invite_handler(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+    _admin: RequireRole<Admin>,
+) -> impl Responder {
+    match registration_service::revoke_invite(&data.redis_client, &path.into_inner()).await {
+        Ok(_) => HttpResponse::Ok().json(serde_json::json!({"status": "success","message": "Invite revoked"})),
+        Err(err) => registration_error_response(err),
+    }
+}
+
+#[get("/pending")]
+async fn list_pending_handler(
+    data: web::Data<AppState>,
+    _admin: RequireRole<Admin>,
+) -> impl Responder {
+    match registration_service::list_pending(&data.redis_client).await {
+        Ok(pending) => HttpResponse::Ok().json(serde_json::json!({"status": "success","data": serde_json::json!({
+            "pending": pending
+        })})),
+        Err(err) => registration_error_response(err),
+    }
+}
+
+#[post("/pending/{id}/approve")]
+async fn approve_registration_handler(
+    req: HttpRequest,
     path: web::Path<u64>,
     data: web::Data<AppState>,
+    admin: RequireRole<Admin>,
 ) -> impl Responder {
-    let id =path.into_inner();
-    let base_dn = "ou=dia,dc=diditalready,dc=com";
-    let filter = format!("(&(objectClass=inetOrgPerson)(uid={}))",id);
-    let ldap = get_admin_ldap(&data.ldap_pool,&data.env.ldap_admin_dn,&data.env.ldap_admin_password).await;
-    let mut ldap = match ldap {
-        Ok(ldap) => ldap,
+    let id = path.into_inner();
+    let registration = match registration_service::take_pending(&data.redis_client, id).await {
+        Ok(registration) => registration,
+        Err(err) => return registration_error_response(err),
+    };
+    // registrations are created disabled, approving is what lets them sign in
+    match data.identity.set_disabled(id, false).await {
+        Ok(_) => {}
+        Err(IdentityError::NotFound) => {
+            return HttpResponse::NotFound()
+                .json(serde_json::json!({"status": "fail","message": "User does not exist"}));
+        }
         Err(err) => {
+            // keep it queued so the approval can be retried
+            let _ = registration_service::mark_pending(&data.redis_client, id, &registration.email).await;
             return HttpResponse::InternalServerError()
                 .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
+    }
+    user_cache::invalidate_users(&data.redis_client, &[id]).await;
+    data.audit.record(
+        AuditEvent::new(AuditEventType::RegistrationApproved, true, &req)
+            .actor(admin.auth.user_id)
+            .subject(id)
+            .email(&registration.email),
+    );
+    registration_service::notify_user(data.mailer.as_ref(), &registration, true).await;
+    HttpResponse::Ok().json(serde_json::json!({"status": "success","message": "Registration approved"}))
+}
+
+// Rejecting removes the account that was created at registration.
+#[post("/pending/{id}/reject")]
+async fn reject_registration_handler(
+    req: HttpRequest,
+    path: web::Path<u64>,
+    data: web::Data<AppState>,
+    admin: RequireRole<Admin>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let registration = match registration_service::take_pending(&data.redis_client, id).await {
+        Ok(registration) => registration,
+        Err(err) => return registration_error_response(err),
     };
-    let search_result = ldap
-        .search(
-            &base_dn,
-            Scope::Subtree,
-            &filter, 
-            vec!["1.1"],
-        )
-        .await;
-    let search_result = match search_result {
-        Ok(search_result) => search_result,
+    match data.identity.delete(id).await {
+        Ok(_) | Err(IdentityError::NotFound) => {}
         Err(err) => {
+            // keep it queued so the rejection can be retried
+            let _ = registration_service::mark_pending(&data.redis_client, id, &registration.email).await;
             return HttpResponse::InternalServerError()
                 .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
-    };
+    }
+    forget_account(&data, id).await;
+    data.audit.record(
+        AuditEvent::new(AuditEventType::RegistrationRejected, true, &req)
+            .actor(admin.auth.user_id)
+            .subject(id)
+            .email(&registration.email),
+    );
+    registration_service::notify_user(data.mailer.as_ref(), &registration, false).await;
+    HttpResponse::Ok().json(serde_json::json!({"status": "success","message": "Registration rejected"}))
+}
+
+// Disabled accounts with the reason and time the identity store keeps for
+// them. Who disabled an account is in the audit log (type=account_disabled).
+#[get("/disabled")]
+async fn list_disabled_handler(
+    data: web::Data<AppState>,
+    _admin: RequireRole<Admin>,
+) -> impl Responder {
+    match data.identity.list_disabled().await {
+        Ok(identities) => {
+            let disabled: Vec<serde_json::Value> = identities
+                .into_iter()
+                .map(|identity| serde_json::json!({
+                    "id": identity.user_id,
+                    "email": identity.email,
+                    "user_id": identity.user_id,
+                    "reason": identity.disabled_reason,
+                    "disabled_at": identity.disabled_at
+                }))
+                .collect();
+            HttpResponse::Ok().json(serde_json::json!({"status": "success","data": serde_json::json!({
+                "disabled": disabled
+            })}))
+        }
+        Err(err) => HttpResponse::InternalServerError()
+            .json(serde_json::json!({"status": "error","message": format!("{:?}", err)})),
+    }
+}
 
-    let (rs,_res) = match search_result.success() {
-        Ok(search_result) => search_result,
+// Disabling keeps the account, unlike DELETE /{id}, but refuses its logins and
+// refreshes and revokes the tokens it holds until an admin enables it again.
+#[post("/{id}/disable")]
+async fn disable_user_handler(
+    req: HttpRequest,
+    path: web::Path<u64>,
+    admin: RequireRole<Admin>,
+    body: web::Json<DisableUserSchema>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let reason = body.reason.trim();
+    if reason.is_empty() {
+        return HttpResponse::BadRequest()
+            .json(serde_json::json!({"status": "fail","message": "A reason is required"}));
+    }
+    if id == admin.auth.user_id {
+        return HttpResponse::BadRequest()
+            .json(serde_json::json!({"status": "fail","message": "You cannot disable your own account"}));
+    }
+    match data.identity.find_by_id(id).await {
+        Ok(Some(identity)) if identity.disabled => {
+            return HttpResponse::Conflict()
+                .json(serde_json::json!({"status": "fail","message": "The account is already disabled"}));
+        }
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return HttpResponse::NotFound()
+                .json(serde_json::json!({"status": "fail","message": "User does not exist"}));
+        }
+        Err(err) => {
+            return HttpResponse::InternalServerError()
+                .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
+        }
+    }
+    let disabled = match data.identity.disable(id, reason).await {
+        Ok(identity) => identity,
+        Err(IdentityError::NotFound) => {
+            return HttpResponse::NotFound()
+                .json(serde_json::json!({"status": "fail","message": "User does not exist"}));
+        }
         Err(err) => {
             return HttpResponse::InternalServerError()
                 .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
         }
     };
-    if !(rs.len() > 0) {
-        return HttpResponse::Conflict().json(
-            serde_json::json!({"status": "fail","message": "User does not exist"}),
-        );    
+    user_cache::invalidate_users(&data.redis_client, &[id]).await;
+    data.audit.record(
+        AuditEvent::new(AuditEventType::AccountDisabled, true, &req)
+            .actor(admin.auth.user_id)
+            .subject(id)
+            .detail(reason),
+    );
+    // the account is disabled either way, these only cut short the tokens it
+    // already holds; the response says when they could not be revoked
+    let tokens_revoked = match account_service::revoke_tokens(&data.redis_client, &data.env, id).await {
+        Ok(_) => true,
+        Err(err) => {
+            println!("❌Could not revoke the tokens of {}: {:?}", id, err);
+            false
+        }
+    };
+    let _ = pat_service::forget_user(&data.redis_client, id).await;
+    HttpResponse::Ok().json(serde_json::json!({"status": "success","data": serde_json::json!({
+        "account": {
+            "user_id": id,
+            "reason": disabled.disabled_reason,
+            "disabled_by": admin.auth.user_id,
+            "disabled_at": disabled.disabled_at,
+            "tokens_revoked": tokens_revoked
+        }
+    })}))
+}
+
+#[post("/{id}/enable")]
+async fn enable_user_handler(
+    req: HttpRequest,
+    path: web::Path<u64>,
+    data: web::Data<AppState>,
+    admin: RequireRole<Admin>,
+) -> impl Responder {
+    let id = path.into_inner();
+    match data.identity.find_by_id(id).await {
+        Ok(Some(identity)) if !identity.disabled => {
+            return HttpResponse::Conflict()
+                .json(serde_json::json!({"status": "fail","message": "The account is not disabled"}));
+        }
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return HttpResponse::NotFound()
+                .json(serde_json::json!({"status": "fail","message": "User does not exist"}));
+        }
+        Err(err) => {
+            return HttpResponse::InternalServerError()
+                .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
+        }
     }
-    let dn = format!("uid={},{}", id, base_dn);
-    let result = ldap.delete(&dn).await;
-    let result = match result {
-        Ok(result) => {
-            let response = serde_json::json!({"status": "success","message": "User deleted successfully"});
-            return HttpResponse::Ok().json(response);
+    // enabling must not let a registration skip the approval queue
+    match registration_service::is_pending(&data.redis_client, id).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return HttpResponse::Conflict().json(
+                serde_json::json!({"status": "fail","message": "The registration is waiting for approval, approve it instead"}),
+            );
+        }
+        Err(err) => {
+            return HttpResponse::InternalServerError()
+                .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
+        }
+    }
+    match data.identity.set_disabled(id, false).await {
+        Ok(_) => {}
+        Err(IdentityError::NotFound) => {
+            return HttpResponse::NotFound()
+                .json(serde_json::json!({"status": "fail","message": "User does not exist"}));
+        }
+        Err(err) => {
+            return HttpResponse::InternalServerError()
+                .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
+        }
+    }
+    user_cache::invalidate_users(&data.redis_client, &[id]).await;
+    data.audit.record(
+        AuditEvent::new(AuditEventType::AccountEnabled, true, &req)
+            .actor(admin.auth.user_id)
+            .subject(id),
+    );
+    HttpResponse::Ok().json(serde_json::json!({"status": "success","message": "Account enabled"}))
+}
+
+#[get("/{id}")]
+async fn get_user_handler(
+    path: web::Path<u64>,
+    data: web::Data<AppState>,
+    _access: OwnerOrAdmin,
+    _scope: RequireScope<ProfileRead>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let identity = match data.identity.find_by_id(id).await {
+        Ok(Some(identity)) => identity,
+        Ok(None) => {
+            return HttpResponse::NotFound().json(
+                serde_json::json!({"status": "fail","message": "User does not exist"}),
+            );
         }
         Err(err) => {
             return HttpResponse::InternalServerError()
@@ -167,13 +753,434 @@ async fn delete_user_handler(
         }
     };
 
+    HttpResponse::Ok().json(serde_json::json!({"status": "success","data": serde_json::json!({
+        "user": user_json(&identity)
+    })}))
+}
+
+// An account as GET and PATCH return it, with the attributes PATCH can change.
+fn user_json(identity: &Identity) -> serde_json::Value {
+    serde_json::json!({
+        "id": identity.user_id,
+        "email": identity.email,
+        "user_id": identity.user_id,
+        "disabled": identity.disabled,
+        "verified": identity.verified,
+        "cn": identity.profile.cn,
+        "sn": identity.profile.sn,
+        "displayName": identity.profile.display_name,
+        "givenName": identity.profile.given_name,
+        "telephoneNumber": identity.profile.telephone_number
+    })
 }
 
+#[patch("/{id}")]
+async fn update_user_handler(
+    path: web::Path<u64>,
+    access: OwnerOrAdmin,
+    _scope: RequireScope<ProfileWrite>,
+    body: web::Json<UpdateUserSchema>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    // whoever controls the address can reset the password, so moving it
+    // takes a login session rather than a personal access token
+    if body.mail.is_some() {
+        if let Some(response) = jwt_auth::require_session(&access.auth) {
+            return response;
+        }
+    }
+    let changes = body.changes();
+    if changes.is_empty() {
+        return HttpResponse::BadRequest().json(
+            serde_json::json!({"status": "fail","message": "No attributes to update"}),
+        );
+    }
+    if let Some((attr, _)) = changes.iter().find(|(_, value)| value.trim().is_empty()) {
+        return HttpResponse::BadRequest().json(
+            serde_json::json!({"status": "fail","message": format!("{} must not be empty", attr)}),
+        );
+    }
+
+    let identity = match data.identity.update(id, &body).await {
+        Ok(identity) => identity,
+        Err(IdentityError::NotFound) => {
+            return HttpResponse::NotFound().json(
+                serde_json::json!({"status": "fail","message": "User does not exist"}),
+            );
+        }
+        Err(IdentityError::Conflict(message)) => {
+            return HttpResponse::Conflict().json(
+                serde_json::json!({"status": "fail","message": message}),
+            );
+        }
+        Err(IdentityError::Invalid(message)) => {
+            return HttpResponse::BadRequest().json(
+                serde_json::json!({"status": "fail","message": message}),
+            );
+        }
+        Err(err) => {
+            return HttpResponse::InternalServerError()
+                .json(serde_json::json!({"status": "error","message": format!("{:?}", err)}));
+        }
+    };
+    user_cache::invalidate_users(&data.redis_client, &[id]).await;
+    // a new address stays unverified until the link mailed to it is opened
+    if body.mail.is_some() && !identity.verified {
+        if let Err(err) = verification_service::send_verification_mail(data.mailer.as_ref(), &data.env, identity.user_id, &identity.email).await {
+            println!("❌Could not send the verification mail to {}: {}", identity.email, err);
+        }
+    }
 
+    HttpResponse::Ok().json(serde_json::json!({"status": "success","data": serde_json::json!({
+        "user": user_json(&identity)
+    })}))
+}
 
 pub fn config(conf: &mut web::ServiceConfig) {
     let scope = web::scope("/api/user")
+        .app_data(web::PayloadConfig::new(MAX_IMPORT_BYTES))
+        // public
         .service(register_user_handler)
+        .service(verify_email_link_handler)
+        .service(verify_email_handler)
+        .service(resend_verification_handler)
+        // admin only
+        .service(list_users_handler)
+        .service(import_users_handler)
+        .service(export_users_handler)
+        .service(create_invite_handler)
+        .service(list_invites_handler)
+        .service(revoke_invite_handler)
+        .service(list_pending_handler)
+        .service(approve_registration_handler)
+        .service(reject_registration_handler)
+        .service(list_disabled_handler)
+        .service(disable_user_handler)
+        .service(enable_user_handler)
+        // the user themselves or an admin
+        .service(get_user_handler)
+        .service(update_user_handler)
         .service(delete_user_handler);
     conf.service(scope);
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity_store::IdentityStore;
+    use crate::scope_service::{SCOPE_ADMIN, SCOPE_PROFILE_READ, SCOPE_PROFILE_WRITE};
+    use crate::test_support::{access_token, memory_store, redis_available, test_state};
+    use actix_web::{http::StatusCode, test, App};
+
+    #[actix_web::test]
+    async fn admin_routes_need_a_token() {
+        let data = test_state(memory_store().await);
+        let app = test::init_service(App::new().app_data(data.clone()).configure(config)).await;
+        let req = test::TestRequest::get().uri("/api/user/disabled").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn listings_continue_from_their_cursor() {
+        let data = test_state(memory_store().await);
+        if !redis_available(&data).await {
+            return;
+        }
+        let app = test::init_service(App::new().app_data(data.clone()).configure(config)).await;
+        let token = access_token(&data.env, 1, &["admins"], &[SCOPE_ADMIN]);
+        let authorization = ("Authorization", format!("Bearer {}", token));
+
+        let req = test::TestRequest::get()
+            .uri("/api/user?limit=1")
+            .insert_header(authorization.clone())
+            .to_request();
+        let body: serde_json::Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(body["data"]["users"][0]["user_id"], 1);
+        assert_eq!(body["data"]["page"]["estimated_total"], 2);
+        let cursor = body["data"]["page"]["next_cursor"].as_str().unwrap().to_string();
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/user?cursor={}", cursor))
+            .insert_header(authorization.clone())
+            .to_request();
+        let body: serde_json::Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(body["data"]["users"][0]["user_id"], 2);
+
+        let req = test::TestRequest::get()
+            .uri("/api/user?group=admins")
+            .insert_header(authorization.clone())
+            .to_request();
+        let body: serde_json::Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(body["data"]["users"].as_array().unwrap().len(), 1);
+        assert_eq!(body["data"]["page"]["next_cursor"], serde_json::Value::Null);
+
+        let req = test::TestRequest::get()
+            .uri("/api/user?cursor=garbage")
+            .insert_header(authorization)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn users_cannot_read_other_users() {
+        let data = test_state(memory_store().await);
+        if !redis_available(&data).await {
+            return;
+        }
+        let app = test::init_service(App::new().app_data(data.clone()).configure(config)).await;
+        let token = access_token(&data.env, 2, &[], &[SCOPE_PROFILE_READ]);
+
+        let req = test::TestRequest::get()
+            .uri("/api/user/2")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["data"]["user"]["email"], "user@example.com");
+
+        let req = test::TestRequest::get()
+            .uri("/api/user/1")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn only_admins_disable_accounts() {
+        let data = test_state(memory_store().await);
+        if !redis_available(&data).await {
+            return;
+        }
+        let app = test::init_service(App::new().app_data(data.clone()).configure(config)).await;
+        let token = access_token(&data.env, 2, &[], &[SCOPE_ADMIN]);
+        let req = test::TestRequest::post()
+            .uri("/api/user/1/disable")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_json(serde_json::json!({"reason": "testing"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn disabling_is_kept_by_the_identity_store() {
+        let store = memory_store().await;
+        // a user of its own, the cutoff it leaves in Redis would revoke other tests' tokens
+        store
+            .create(NewIdentity { user_id: 3, email: "leaver@example.com", password: "correct horse", verified: true, disabled: false })
+            .await
+            .unwrap();
+        let data = test_state(store.clone());
+        if !redis_available(&data).await {
+            return;
+        }
+        let app = test::init_service(App::new().app_data(data.clone()).configure(config)).await;
+        let token = access_token(&data.env, 1, &["admins"], &[SCOPE_ADMIN, SCOPE_PROFILE_READ]);
+        let authorization = ("Authorization", format!("Bearer {}", token));
+
+        let req = test::TestRequest::post()
+            .uri("/api/user/3/disable")
+            .insert_header(authorization.clone())
+            .set_json(serde_json::json!({"reason": ""}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::post()
+            .uri("/api/user/3/disable")
+            .insert_header(authorization.clone())
+            .set_json(serde_json::json!({"reason": "left the company"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        let stored = store.find_by_id(3).await.unwrap().unwrap();
+        assert!(stored.disabled);
+        assert_eq!(stored.disabled_reason.as_deref(), Some("left the company"));
+        let disabled_at = serde_json::json!(stored.disabled_at.unwrap());
+        assert_eq!(body["data"]["account"]["disabled_at"], disabled_at);
+        assert_eq!(body["data"]["account"]["tokens_revoked"], true);
+
+        let req = test::TestRequest::get()
+            .uri("/api/user/disabled")
+            .insert_header(authorization.clone())
+            .to_request();
+        let body: serde_json::Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(body["data"]["disabled"].as_array().unwrap().len(), 1);
+        assert_eq!(body["data"]["disabled"][0]["user_id"], 3);
+        assert_eq!(body["data"]["disabled"][0]["reason"], "left the company");
+        assert_eq!(body["data"]["disabled"][0]["disabled_at"], disabled_at);
+
+        // admins still see the account, flagged
+        let req = test::TestRequest::get()
+            .uri("/api/user/3")
+            .insert_header(authorization.clone())
+            .to_request();
+        let body: serde_json::Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(body["data"]["user"]["disabled"], true);
+
+        let req = test::TestRequest::post()
+            .uri("/api/user/3/enable")
+            .insert_header(authorization.clone())
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert!(!store.find_by_id(3).await.unwrap().unwrap().disabled);
+
+        let req = test::TestRequest::post()
+            .uri("/api/user/3/enable")
+            .insert_header(authorization)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CONFLICT);
+    }
+
+    #[actix_web::test]
+    async fn disabling_says_when_tokens_could_not_be_revoked() {
+        let store = memory_store().await;
+        store
+            .create(NewIdentity { user_id: 8, email: "keeper@example.com", password: "correct horse", verified: true, disabled: false })
+            .await
+            .unwrap();
+        let mut state = std::sync::Arc::try_unwrap(test_state(store.clone()).into_inner()).ok().unwrap();
+        // SETEX refuses a zero expiry, so the cutoff cannot be written while Redis is up
+        state.env.refresh_token_max_age = 0;
+        let data = web::Data::new(state);
+        if !redis_available(&data).await {
+            return;
+        }
+        let app = test::init_service(App::new().app_data(data.clone()).configure(config)).await;
+        let token = access_token(&data.env, 1, &["admins"], &[SCOPE_ADMIN]);
+        let req = test::TestRequest::post()
+            .uri("/api/user/8/disable")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_json(serde_json::json!({"reason": "left the company"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["data"]["account"]["tokens_revoked"], false);
+        assert!(store.find_by_id(8).await.unwrap().unwrap().disabled);
+    }
+
+    #[actix_web::test]
+    async fn a_new_address_has_to_be_verified() {
+        let store = memory_store().await;
+        store
+            .create(NewIdentity { user_id: 4, email: "mover@example.com", password: "correct horse", verified: true, disabled: false })
+            .await
+            .unwrap();
+        let data = test_state(store.clone());
+        if !redis_available(&data).await {
+            return;
+        }
+        let app = test::init_service(App::new().app_data(data.clone()).configure(config)).await;
+        let token = access_token(&data.env, 4, &[], &[SCOPE_PROFILE_WRITE]);
+        let req = test::TestRequest::patch()
+            .uri("/api/user/4")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_json(serde_json::json!({"mail": "mover@example.org"}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let identity = store.find_by_id(4).await.unwrap().unwrap();
+        assert_eq!(identity.email, "mover@example.org");
+        assert!(!identity.verified);
+    }
+
+    #[actix_web::test]
+    async fn profile_changes_are_read_back() {
+        let store = memory_store().await;
+        store
+            .create(NewIdentity { user_id: 6, email: "renamed@example.com", password: "correct horse", verified: true, disabled: false })
+            .await
+            .unwrap();
+        let data = test_state(store.clone());
+        if !redis_available(&data).await {
+            return;
+        }
+        let app = test::init_service(App::new().app_data(data.clone()).configure(config)).await;
+        let token = access_token(&data.env, 6, &[], &[SCOPE_PROFILE_READ, SCOPE_PROFILE_WRITE]);
+        let authorization = ("Authorization", format!("Bearer {}", token));
+
+        let req = test::TestRequest::patch()
+            .uri("/api/user/6")
+            .insert_header(authorization.clone())
+            .set_json(serde_json::json!({"displayName": "Jane Doe", "telephoneNumber": "+1 555 0100"}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri("/api/user/6")
+            .insert_header(authorization.clone())
+            .to_request();
+        let body: serde_json::Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(body["data"]["user"]["displayName"], "Jane Doe");
+        assert_eq!(body["data"]["user"]["telephoneNumber"], "+1 555 0100");
+        assert_eq!(body["data"]["user"]["sn"], serde_json::Value::Null);
+
+        // someone else's account
+        let req = test::TestRequest::patch()
+            .uri("/api/user/2")
+            .insert_header(authorization.clone())
+            .set_json(serde_json::json!({"sn": "Doe"}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+        // an address another account uses
+        let req = test::TestRequest::patch()
+            .uri("/api/user/6")
+            .insert_header(authorization)
+            .set_json(serde_json::json!({"mail": "USER@example.com"}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CONFLICT);
+        assert_eq!(store.find_by_id(6).await.unwrap().unwrap().email, "renamed@example.com");
+    }
+
+    #[actix_web::test]
+    async fn approving_a_registration_enables_the_account() {
+        let store = memory_store().await;
+        store
+            .create(NewIdentity { user_id: 5, email: "newcomer@example.com", password: "correct horse", verified: true, disabled: true })
+            .await
+            .unwrap();
+        let data = test_state(store.clone());
+        if !redis_available(&data).await {
+            return;
+        }
+        registration_service::mark_pending(&data.redis_client, 5, "newcomer@example.com").await.unwrap();
+        let app = test::init_service(App::new().app_data(data.clone()).configure(config)).await;
+        let token = access_token(&data.env, 1, &["admins"], &[SCOPE_ADMIN]);
+        let authorization = ("Authorization", format!("Bearer {}", token));
+
+        let req = test::TestRequest::post()
+            .uri("/api/user/5/enable")
+            .insert_header(authorization.clone())
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CONFLICT);
+        assert!(store.find_by_id(5).await.unwrap().unwrap().disabled);
+
+        let req = test::TestRequest::post()
+            .uri("/api/user/pending/5/approve")
+            .insert_header(authorization)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert!(!store.find_by_id(5).await.unwrap().unwrap().disabled);
+    }
+
+    #[actix_web::test]
+    async fn registrations_that_cannot_be_queued_are_taken_back() {
+        let store = memory_store().await;
+        let mut state = std::sync::Arc::try_unwrap(test_state(store.clone()).into_inner()).ok().unwrap();
+        state.env.registration_mode = RegistrationMode::Approval;
+        // nothing listens there, so the registration cannot be queued
+        state.redis_client = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+        let data = web::Data::new(state);
+        let app = test::init_service(App::new().app_data(data.clone()).configure(config)).await;
+        let req = test::TestRequest::post()
+            .uri("/api/user/")
+            .set_json(serde_json::json!({"email": "queued@example.com", "password": "correct horse", "user_id": 7}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(store.find_by_id(7).await.unwrap().is_none());
+    }
+}
@@ -10,6 +10,19 @@ pub struct User {
     pub user_id: i32, 
     #[serde(rename = "createdAt")]
     pub created_at: Option<DateTime<Utc>>,
+    #[serde(rename = "disabledAt")]
+    pub disabled_at: Option<DateTime<Utc>>,
+    #[serde(rename = "disabledReason")]
+    pub disabled_reason: Option<String>,
+    pub verified: bool,
+    pub cn: Option<String>,
+    pub sn: Option<String>,
+    #[serde(rename = "displayName")]
+    pub display_name: Option<String>,
+    #[serde(rename = "givenName")]
+    pub given_name: Option<String>,
+    #[serde(rename = "telephoneNumber")]
+    pub telephone_number: Option<String>,
 }
 
 
@@ -18,15 +31,154 @@ pub struct RegisterUserSchema {
     pub email: String,
     pub password: String,
     pub user_id: i32,
+    // required when REGISTRATION_MODE=invite
+    pub invite: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct UpdateUserSchema {
+    #[serde(alias = "email")]
+    pub mail: Option<String>,
+    pub cn: Option<String>,
+    pub sn: Option<String>,
+    #[serde(rename = "displayName")]
+    pub display_name: Option<String>,
+    #[serde(rename = "givenName")]
+    pub given_name: Option<String>,
+    #[serde(rename = "telephoneNumber")]
+    pub telephone_number: Option<String>,
+}
+
+impl UpdateUserSchema {
+    // The LDAP attributes a PATCH is allowed to replace, paired with their new values.
+    pub fn changes(&self) -> Vec<(&'static str, &str)> {
+        let fields = [
+            ("mail", &self.mail),
+            ("cn", &self.cn),
+            ("sn", &self.sn),
+            ("displayName", &self.display_name),
+            ("givenName", &self.given_name),
+            ("telephoneNumber", &self.telephone_number),
+        ];
+        fields
+            .into_iter()
+            .filter_map(|(attr, value)| value.as_deref().map(|value| (attr, value)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListUsersQuery {
+    pub email: Option<String>,
+    pub uid: Option<u64>,
+    pub group: Option<String>,
+    pub limit: Option<i32>,
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportUsersQuery {
+    pub format: Option<String>,
+    pub dry_run: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportUsersQuery {
+    pub format: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct LoginUserSchema {
     pub email: String,
     pub password: String,
+    // space-separated scopes to ask for, all the user may have when absent
+    pub scope: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct RefreshSchema {
+    // empty when the refresh token comes in its cookie
+    #[serde(default)]
     pub refresh: String,
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaCodeSchema {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaVerifySchema {
+    pub challenge: String,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+    pub scope: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaDisableSchema {
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasskeyLoginStartSchema {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasskeyLoginFinishSchema {
+    pub ceremony: String,
+    pub credential: webauthn_rs::prelude::PublicKeyCredential,
+    pub scope: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmailSchema {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResendVerificationSchema {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MagicLinkRequestSchema {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MagicLinkRedeemSchema {
+    pub token: String,
+    pub nonce: String,
+    pub scope: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateTokenSchema {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    pub user: Option<u64>,
+    #[serde(rename = "type")]
+    pub event_type: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub before: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateInviteSchema {
+    pub email: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DisableUserSchema {
+    pub reason: String,
+}
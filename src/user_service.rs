@@ -1,8 +1,12 @@
 use crate::{
     user_model::User,
-    response::FilteredUser
+    response::FilteredUser,
+    identity_store::IdentityError
+};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
 };
-use uuid::Uuid;
 
 pub fn filter_user_record(user: &User) -> FilteredUser {
     FilteredUser {
@@ -12,7 +16,24 @@ pub fn filter_user_record(user: &User) -> FilteredUser {
     }
 }
 
-pub fn fetch_user_by_id_query(param: &u64) -> (&'static str, &u64) {
-    let query = "SELECT * FROM users WHERE id = $1";
+pub fn fetch_user_by_id_query(param: &i32) -> (&'static str, &i32) {
+    let query = "SELECT * FROM users WHERE user_id = $1";
     (query, param)
-}
\ No newline at end of file
+}
+
+pub fn hash_password(password: &str) -> Result<String, IdentityError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| IdentityError::Backend(err.to_string()))
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed_hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
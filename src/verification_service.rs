@@ -0,0 +1,116 @@
+use redis::AsyncCommands;
+
+use crate::config::Config;
+use crate::identity_store::{IdentityError, IdentityStore};
+use crate::mail_service::MailTransport;
+use crate::token_service;
+
+pub const VERIFY_EMAIL_PURPOSE: &str = "verify_email";
+
+// What an unverified account may do at login.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmailVerificationMode {
+    // logins are not affected
+    Off,
+    // tokens are issued without group claims
+    Limited,
+    // logins are refused
+    Required,
+}
+
+impl EmailVerificationMode {
+    pub fn parse(value: &str) -> Option<EmailVerificationMode> {
+        match value.to_ascii_lowercase().as_str() {
+            "off" => Some(EmailVerificationMode::Off),
+            "limited" => Some(EmailVerificationMode::Limited),
+            "required" => Some(EmailVerificationMode::Required),
+            _ => None,
+        }
+    }
+}
+
+// Whether an address is confirmed is kept by the identity store
+// (`Identity::verified`). Earlier versions marked unverified accounts in Redis.
+const LEGACY_MARKER_PATTERN: &str = "unverified:*";
+
+fn resend_key(email: &str) -> String {
+    format!("verify_resend:{}", email.to_lowercase())
+}
+
+// Moves the Redis markers of earlier versions into the identity store, so
+// upgrading does not verify every pending account. Returns how many moved.
+pub async fn import_legacy_markers(identity: &dyn IdentityStore, redis_client: &redis::Client) -> Result<usize, String> {
+    let mut conn = redis_client.get_async_connection().await.map_err(|err| err.to_string())?;
+    let keys: Vec<String> = {
+        let mut iter = conn.scan_match::<_, String>(LEGACY_MARKER_PATTERN).await.map_err(|err| err.to_string())?;
+        let mut keys = Vec::new();
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        keys
+    };
+    let mut imported = 0;
+    for key in keys {
+        if let Some(uid) = key.strip_prefix("unverified:").and_then(|uid| uid.parse::<u64>().ok()) {
+            match identity.set_verified(uid, false).await {
+                Ok(_) => imported += 1,
+                // the account is gone, so is the need for the marker
+                Err(IdentityError::NotFound) => {}
+                Err(err) => return Err(format!("{:?}", err)),
+            }
+        }
+        let _: () = conn.del(&key).await.map_err(|err| err.to_string())?;
+    }
+    Ok(imported)
+}
+
+// Claims the resend slot for `email`, false while an earlier mail is still
+// within EMAIL_VERIFICATION_RESEND_SECS.
+pub async fn claim_resend_slot(redis_client: &redis::Client, config: &Config, email: &str) -> redis::RedisResult<bool> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let claimed: Option<String> = redis::cmd("SET")
+        .arg(resend_key(email))
+        .arg("1")
+        .arg("NX")
+        .arg("EX")
+        .arg(config.email_verification_resend_secs)
+        .query_async(&mut conn)
+        .await?;
+    Ok(claimed.is_some())
+}
+
+pub async fn send_verification_mail(
+    mailer: &dyn MailTransport,
+    config: &Config,
+    uid: u64,
+    email: &str,
+) -> Result<(), String> {
+    let token = token_service::generate_email_token(
+        uid,
+        email,
+        VERIFY_EMAIL_PURPOSE,
+        None,
+        config.email_token_max_age,
+        config.access_token_private_key.to_owned(),
+    )
+    .map_err(|err| err.to_string())?;
+    let link = format!("{}?token={}", config.email_verification_url, token);
+    let body = format!(
+        "Confirm your email address by opening this link:\n\n{}\n\nThe link expires in {} minutes.",
+        link, config.email_token_max_age
+    );
+    mailer
+        .send(email, "Confirm your email address", &body)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+// Mails every account an import created, which start out unverified. Failed
+// mails are logged, the users can ask for another one.
+pub async fn send_verification_mails(mailer: &dyn MailTransport, config: &Config, accounts: &[(u64, String)]) {
+    for (uid, email) in accounts {
+        if let Err(err) = send_verification_mail(mailer, config, *uid, email).await {
+            println!("❌Could not send the verification mail to {}: {}", email, err);
+        }
+    }
+}
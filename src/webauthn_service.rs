@@ -0,0 +1,356 @@
+use base64::{engine::general_purpose, Engine as _};
+use redis::AsyncCommands;
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+use webauthn_rs::prelude::*;
+
+use crate::config::Config;
+
+// Passkeys are kept in the `passkeys` table, one row per credential with the
+// signature counter next to it. Redis only holds the ceremony states below.
+//
+// Registration accepts any authenticator: attestation is requested as "none",
+// so nothing ties a passkey to a make or model. Checking packed attestation
+// against a CA list is out of scope until a deployment needs to restrict the
+// authenticators it accepts.
+
+// Ceremonies have to finish within this window, matching the timeout the
+// browser is given.
+const CEREMONY_TTL_SECS: usize = 300;
+
+#[derive(Debug)]
+pub enum PasskeyError {
+    NotConfigured,
+    NoPasskeys,
+    InvalidCeremony,
+    // the signature counter did not move past the stored one, which points at
+    // a cloned authenticator or a replayed assertion
+    CounterRegressed,
+    WebauthnError(WebauthnError),
+    RedisError(redis::RedisError),
+    DatabaseError(sqlx::Error),
+    SerdeError(serde_json::Error),
+}
+
+impl From<WebauthnError> for PasskeyError {
+    fn from(err: WebauthnError) -> Self {
+        PasskeyError::WebauthnError(err)
+    }
+}
+
+impl From<redis::RedisError> for PasskeyError {
+    fn from(err: redis::RedisError) -> Self {
+        PasskeyError::RedisError(err)
+    }
+}
+
+impl From<sqlx::Error> for PasskeyError {
+    fn from(err: sqlx::Error) -> Self {
+        PasskeyError::DatabaseError(err)
+    }
+}
+
+impl From<serde_json::Error> for PasskeyError {
+    fn from(err: serde_json::Error) -> Self {
+        PasskeyError::SerdeError(err)
+    }
+}
+
+// Builds the relying party from WEBAUTHN_RP_ID and WEBAUTHN_ORIGIN, or None
+// when passkeys are not configured.
+pub fn build_webauthn(config: &Config) -> Result<Option<Webauthn>, String> {
+    let (rp_id, origin) = match (&config.webauthn_rp_id, &config.webauthn_origin) {
+        (Some(rp_id), Some(origin)) => (rp_id, origin),
+        _ => return Ok(None),
+    };
+    relying_party(rp_id, origin, &config.webauthn_rp_name).map(Some)
+}
+
+fn relying_party(rp_id: &str, origin: &str, rp_name: &str) -> Result<Webauthn, String> {
+    let origin = Url::parse(origin).map_err(|err| format!("WEBAUTHN_ORIGIN: {}", err))?;
+    WebauthnBuilder::new(rp_id, &origin)
+        .map_err(|err| format!("{:?}", err))?
+        .rp_name(rp_name)
+        .build()
+        .map_err(|err| format!("{:?}", err))
+}
+
+// Earlier versions kept each user's passkeys as one JSON list in Redis.
+const LEGACY_PASSKEYS_PATTERN: &str = "passkeys:*";
+
+fn credential_key(credential_id: &CredentialID) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(&credential_id.0)
+}
+
+fn pool(db: Option<&Pool<Postgres>>) -> Result<&Pool<Postgres>, PasskeyError> {
+    db.ok_or(PasskeyError::NotConfigured)
+}
+
+fn registration_key(uid: u64) -> String {
+    format!("passkey_registration:{}", uid)
+}
+
+fn authentication_key(ceremony: &str) -> String {
+    format!("passkey_authentication:{}", ceremony)
+}
+
+// The WebAuthn user handle, derived from the uid so it never changes.
+fn user_handle(uid: u64) -> Uuid {
+    Uuid::from_u64_pair(0, uid)
+}
+
+async fn load_passkeys(db: &Pool<Postgres>, uid: u64) -> Result<Vec<Passkey>, PasskeyError> {
+    let credentials: Vec<String> = sqlx::query_scalar("SELECT credential FROM passkeys WHERE user_id = $1 ORDER BY created_at")
+        .bind(uid as i64)
+        .fetch_all(db)
+        .await?;
+    credentials
+        .iter()
+        .map(|credential| serde_json::from_str(credential).map_err(PasskeyError::from))
+        .collect()
+}
+
+async fn insert_passkey(db: &Pool<Postgres>, uid: u64, passkey: &Passkey) -> Result<(), PasskeyError> {
+    sqlx::query("INSERT INTO passkeys (credential_id, user_id, credential) VALUES ($1, $2, $3) ON CONFLICT (credential_id) DO NOTHING")
+        .bind(credential_key(passkey.cred_id()))
+        .bind(uid as i64)
+        .bind(serde_json::to_string(passkey)?)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+// Authenticators without a counter always send 0; any other counter has to
+// move forward on every use.
+fn counter_accepted(stored: u32, presented: u32) -> bool {
+    presented > stored || (presented == 0 && stored == 0)
+}
+
+// Reads and deletes a ceremony state so each challenge can be answered once.
+async fn take_state<T: serde::de::DeserializeOwned>(redis_client: &redis::Client, key: &str) -> Result<T, PasskeyError> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let (value, _): (Option<String>, ()) = redis::pipe()
+        .atomic()
+        .get(key)
+        .del(key)
+        .query_async(&mut conn)
+        .await?;
+    match value {
+        Some(value) => Ok(serde_json::from_str(&value)?),
+        None => Err(PasskeyError::InvalidCeremony),
+    }
+}
+
+pub async fn start_registration(
+    webauthn: &Webauthn,
+    db: Option<&Pool<Postgres>>,
+    redis_client: &redis::Client,
+    uid: u64,
+    email: &str,
+) -> Result<CreationChallengeResponse, PasskeyError> {
+    let passkeys = load_passkeys(pool(db)?, uid).await?;
+    // stop the same authenticator from being registered twice
+    let exclude: Vec<CredentialID> = passkeys.iter().map(|passkey| passkey.cred_id().clone()).collect();
+    let (challenge, state) = webauthn.start_passkey_registration(
+        user_handle(uid),
+        email,
+        email,
+        if exclude.is_empty() { None } else { Some(exclude) },
+    )?;
+
+    let mut conn = redis_client.get_async_connection().await?;
+    let _: () = conn
+        .set_ex(registration_key(uid), serde_json::to_string(&state)?, CEREMONY_TTL_SECS)
+        .await?;
+    Ok(challenge)
+}
+
+// Verifies the attestation and stores the new credential, returning its id.
+pub async fn finish_registration(
+    webauthn: &Webauthn,
+    db: Option<&Pool<Postgres>>,
+    redis_client: &redis::Client,
+    uid: u64,
+    credential: &RegisterPublicKeyCredential,
+) -> Result<CredentialID, PasskeyError> {
+    let db = pool(db)?;
+    let state: PasskeyRegistration = take_state(redis_client, &registration_key(uid)).await?;
+    let passkey = webauthn.finish_passkey_registration(credential, &state)?;
+    insert_passkey(db, uid, &passkey).await?;
+    Ok(passkey.cred_id().clone())
+}
+
+// Returns the ceremony id the client echoes back with its assertion, and the
+// request options for navigator.credentials.get().
+pub async fn start_authentication(
+    webauthn: &Webauthn,
+    db: Option<&Pool<Postgres>>,
+    redis_client: &redis::Client,
+    uid: u64,
+) -> Result<(String, RequestChallengeResponse), PasskeyError> {
+    let passkeys = load_passkeys(pool(db)?, uid).await?;
+    if passkeys.is_empty() {
+        return Err(PasskeyError::NoPasskeys);
+    }
+    let (challenge, state) = webauthn.start_passkey_authentication(&passkeys)?;
+
+    let ceremony = Uuid::new_v4().to_string();
+    let value = serde_json::to_string(&(uid, state))?;
+    let mut conn = redis_client.get_async_connection().await?;
+    let _: () = conn.set_ex(authentication_key(&ceremony), value, CEREMONY_TTL_SECS).await?;
+    Ok((ceremony, challenge))
+}
+
+// Verifies an assertion and returns the uid it proves. webauthn-rs only
+// compares the counter with the copy taken when the ceremony started, so the
+// stored counter is checked again here and moved with a compare-and-swap.
+pub async fn finish_authentication(
+    webauthn: &Webauthn,
+    db: Option<&Pool<Postgres>>,
+    redis_client: &redis::Client,
+    ceremony: &str,
+    credential: &PublicKeyCredential,
+) -> Result<u64, PasskeyError> {
+    let db = pool(db)?;
+    let (uid, state): (u64, PasskeyAuthentication) = take_state(redis_client, &authentication_key(ceremony)).await?;
+    let result = webauthn.finish_passkey_authentication(credential, &state)?;
+
+    let credential_id = credential_key(result.cred_id());
+    let row: Option<(String, i64)> =
+        sqlx::query_as("SELECT credential, sign_count FROM passkeys WHERE credential_id = $1 AND user_id = $2")
+            .bind(&credential_id)
+            .bind(uid as i64)
+            .fetch_optional(db)
+            .await?;
+    let (credential, stored) = row.ok_or(PasskeyError::NoPasskeys)?;
+    if !counter_accepted(stored as u32, result.counter()) {
+        return Err(PasskeyError::CounterRegressed);
+    }
+    let mut passkey: Passkey = serde_json::from_str(&credential)?;
+    passkey.update_credential(&result);
+    let updated = sqlx::query("UPDATE passkeys SET credential = $2, sign_count = $3 WHERE credential_id = $1 AND sign_count = $4")
+        .bind(&credential_id)
+        .bind(serde_json::to_string(&passkey)?)
+        .bind(result.counter() as i64)
+        .bind(stored)
+        .execute(db)
+        .await?
+        .rows_affected();
+    if updated == 0 {
+        // another assertion got there first
+        return Err(PasskeyError::CounterRegressed);
+    }
+    Ok(uid)
+}
+
+// Drops the passkeys of a deleted account.
+pub async fn forget_user(db: Option<&Pool<Postgres>>, uid: u64) -> Result<(), PasskeyError> {
+    let db = match db {
+        Some(db) => db,
+        None => return Ok(()),
+    };
+    sqlx::query("DELETE FROM passkeys WHERE user_id = $1")
+        .bind(uid as i64)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+// Moves passkeys left in Redis by earlier versions into the database, so
+// upgrading does not lock anyone out. Returns how many moved.
+pub async fn import_legacy_passkeys(db: &Pool<Postgres>, redis_client: &redis::Client) -> Result<usize, PasskeyError> {
+    let mut conn = redis_client.get_async_connection().await?;
+    let keys: Vec<String> = {
+        let mut iter = conn.scan_match::<_, String>(LEGACY_PASSKEYS_PATTERN).await?;
+        let mut keys = Vec::new();
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        keys
+    };
+    let mut imported = 0;
+    for key in keys {
+        let uid = match key.strip_prefix("passkeys:").and_then(|uid| uid.parse::<u64>().ok()) {
+            Some(uid) => uid,
+            None => continue,
+        };
+        let value: Option<String> = conn.get(&key).await?;
+        if let Some(value) = value {
+            let passkeys: Vec<Passkey> = serde_json::from_str(&value)?;
+            for passkey in &passkeys {
+                insert_passkey(db, uid, passkey).await?;
+            }
+            imported += passkeys.len();
+        }
+        let _: () = conn.del(&key).await?;
+    }
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webauthn_authenticator_rs::softpasskey::SoftPasskey;
+    use webauthn_authenticator_rs::WebauthnAuthenticator;
+
+    const ORIGIN: &str = "https://auth.example.com";
+
+    fn relying_party_for_tests() -> Webauthn {
+        relying_party("auth.example.com", ORIGIN, "AuthAPI").unwrap()
+    }
+
+    fn register(webauthn: &Webauthn, authenticator: &mut WebauthnAuthenticator<SoftPasskey>, uid: u64) -> Passkey {
+        let (options, state) = webauthn
+            .start_passkey_registration(user_handle(uid), "user@example.com", "user@example.com", None)
+            .unwrap();
+        let credential = authenticator.do_registration(Url::parse(ORIGIN).unwrap(), options).unwrap();
+        webauthn.finish_passkey_registration(&credential, &state).unwrap()
+    }
+
+    #[test]
+    fn soft_passkey_round_trip() {
+        let webauthn = relying_party_for_tests();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new());
+        let mut passkey = register(&webauthn, &mut authenticator, 2);
+
+        // stored and loaded the way the passkeys table keeps it
+        let stored = serde_json::to_string(&passkey).unwrap();
+        let loaded: Passkey = serde_json::from_str(&stored).unwrap();
+        assert_eq!(credential_key(loaded.cred_id()), credential_key(passkey.cred_id()));
+
+        let mut counter = 0;
+        for _ in 0..2 {
+            let (options, state) = webauthn.start_passkey_authentication(&[passkey.clone()]).unwrap();
+            let assertion = authenticator.do_authentication(Url::parse(ORIGIN).unwrap(), options).unwrap();
+            let result = webauthn.finish_passkey_authentication(&assertion, &state).unwrap();
+            assert_eq!(result.cred_id(), passkey.cred_id());
+            assert!(counter_accepted(counter, result.counter()));
+            counter = result.counter();
+            passkey.update_credential(&result);
+        }
+    }
+
+    #[test]
+    fn assertions_only_answer_their_own_challenge() {
+        let webauthn = relying_party_for_tests();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new());
+        let passkey = register(&webauthn, &mut authenticator, 2);
+
+        let (options, _) = webauthn.start_passkey_authentication(&[passkey.clone()]).unwrap();
+        let (_, other_state) = webauthn.start_passkey_authentication(&[passkey]).unwrap();
+        let assertion = authenticator.do_authentication(Url::parse(ORIGIN).unwrap(), options).unwrap();
+        assert!(webauthn.finish_passkey_authentication(&assertion, &other_state).is_err());
+    }
+
+    #[test]
+    fn counters_must_move_forward() {
+        assert!(counter_accepted(0, 0));
+        assert!(counter_accepted(0, 1));
+        assert!(counter_accepted(41, 42));
+        // a clone that is behind, or an assertion spent twice
+        assert!(!counter_accepted(42, 42));
+        assert!(!counter_accepted(42, 7));
+        // a counter that stops after having counted
+        assert!(!counter_accepted(42, 0));
+    }
+}